@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use xxhash_rust::xxh3::xxh3_128;
+
+// Start of every bloom file, followed by a version byte, the number of hashes and the number of bits
+const MAGIC: &[u8; 8] = b"ZJFBLOOM";
+const VERSION: u8 = 1;
+const HEADER_SIZE: u64 = 21;
+// with_rate never uses more hashes than this
+const MAX_HASH_COUNT: u32 = 32;
+
+// A bloom filter of allowed values for --bloom-file, built once with --build-bloom
+// Values that were added are always found, values that were not are found with the false positive rate it was built for
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    bit_count: u64,
+    hash_count: u32,
+}
+
+impl BloomFilter {
+    // Sized for the expected number of values and false positive rate with the usual formulas
+    // m = -n * ln(p) / ln(2)^2 bits and k = m / n * ln(2) hashes
+    pub fn with_rate(values: u64, false_positive_rate: f64) -> Self {
+        let values = values.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bit_count = (-values * false_positive_rate.ln() / (ln2 * ln2))
+            .ceil()
+            .max(64.0) as u64;
+        let hash_count = ((bit_count as f64 / values) * ln2)
+            .round()
+            .clamp(1.0, MAX_HASH_COUNT as f64) as u32;
+        BloomFilter {
+            bits: vec![0; bit_count.div_ceil(64) as usize],
+            bit_count,
+            hash_count,
+        }
+    }
+
+    pub fn insert(&mut self, value: &str) {
+        for bit in bit_positions(value, self.hash_count, self.bit_count) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    pub fn contains(&self, value: &str) -> bool {
+        bit_positions(value, self.hash_count, self.bit_count)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    pub fn size_in_bytes(&self) -> u64 {
+        self.bits.len() as u64 * 8
+    }
+
+    pub fn hash_count(&self) -> u32 {
+        self.hash_count
+    }
+
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        writer.write_all(&self.hash_count.to_le_bytes())?;
+        writer.write_all(&self.bit_count.to_le_bytes())?;
+        for word in &self.bits {
+            writer.write_all(&word.to_le_bytes())?;
+        }
+        writer.flush()
+    }
+
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("Failed to open {:?}: {}", path, e))?;
+        let file_size = file
+            .metadata()
+            .map_err(|e| format!("Failed to read {:?}: {}", path, e))?
+            .len();
+        let mut reader = BufReader::new(file);
+        let invalid = || format!("{:?} is not a bloom file created with --build-bloom", path);
+        let mut header = [0u8; HEADER_SIZE as usize];
+        reader.read_exact(&mut header).map_err(|_| invalid())?;
+        if &header[..8] != MAGIC || header[8] != VERSION {
+            return Err(invalid());
+        }
+        let hash_count = u32::from_le_bytes(header[9..13].try_into().unwrap());
+        let bit_count = u64::from_le_bytes(header[13..21].try_into().unwrap());
+        if hash_count == 0 || hash_count > MAX_HASH_COUNT || bit_count == 0 {
+            return Err(invalid());
+        }
+        // A damaged header must not make it allocate more than the file holds
+        let words = bit_count.div_ceil(64);
+        if words
+            .checked_mul(8)
+            .and_then(|size| size.checked_add(HEADER_SIZE))
+            != Some(file_size)
+        {
+            return Err(format!(
+                "{:?} is truncated or damaged, its header does not match its size",
+                path
+            ));
+        }
+        let mut bits = vec![0u64; words as usize];
+        let mut word = [0u8; 8];
+        for bits_word in bits.iter_mut() {
+            reader.read_exact(&mut word).map_err(|_| invalid())?;
+            *bits_word = u64::from_le_bytes(word);
+        }
+        Ok(BloomFilter {
+            bits,
+            bit_count,
+            hash_count,
+        })
+    }
+}
+
+// Double hashing, both halves of a single 128 bit hash give every position the filter needs
+fn bit_positions(value: &str, hash_count: u32, bit_count: u64) -> impl Iterator<Item = u64> {
+    let hash = xxh3_128(value.as_bytes());
+    let first = hash as u64;
+    let second = (hash >> 64) as u64 | 1;
+    (0..hash_count as u64).map(move |i| first.wrapping_add(i.wrapping_mul(second)) % bit_count)
+}
+
+// Builds a bloom file from a list with one value per line, empty lines are ignored
+// The list is read twice so the filter can be sized without holding the values in memory
+// Returns the number of values together with the filter
+pub fn build(
+    list: &Path,
+    output: &Path,
+    false_positive_rate: f64,
+) -> Result<(u64, BloomFilter), String> {
+    let open = || {
+        File::open(list)
+            .map(BufReader::new)
+            .map_err(|e| format!("Failed to open {:?}: {}", list, e))
+    };
+    let read_error = |e: std::io::Error| format!("Failed to read {:?}: {}", list, e);
+    let mut count = 0;
+    for line in open()?.lines() {
+        if !line.map_err(read_error)?.is_empty() {
+            count += 1;
+        }
+    }
+    let mut filter = BloomFilter::with_rate(count, false_positive_rate);
+    for line in open()?.lines() {
+        let line = line.map_err(read_error)?;
+        if !line.is_empty() {
+            filter.insert(&line);
+        }
+    }
+    filter
+        .write(output)
+        .map_err(|e| format!("Failed to write {:?}: {}", output, e))?;
+    Ok((count, filter))
+}
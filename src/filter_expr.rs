@@ -0,0 +1,59 @@
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use serde_json::Value;
+
+// Upper bound of operations per line so a runaway expression cannot stall a thread
+const MAX_OPERATIONS: u64 = 100_000;
+
+// A --filter-expr expression, compiled once and evaluated for every parsed JSON line
+// The line is available as `record`, e.g. `record.level == "error" && record.user.age > 30`
+pub struct FilterExpr {
+    engine: Engine,
+    ast: AST,
+}
+
+impl FilterExpr {
+    pub fn new(expr: &str) -> Result<Self, String> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        // get(record, "user.tags.0") looks up a dotted path, () if it does not exist
+        engine.register_fn("get", |record: Map, path: &str| {
+            get_path(Dynamic::from_map(record), path).unwrap_or(Dynamic::UNIT)
+        });
+        // has(record, "user.name") checks whether a dotted path exists, even if its value is null
+        engine.register_fn("has", |record: Map, path: &str| {
+            get_path(Dynamic::from_map(record), path).is_some()
+        });
+        let ast = engine
+            .compile_expression(expr)
+            .map_err(|e| format!("Invalid --filter-expr: {}", e))?;
+        Ok(FilterExpr { engine, ast })
+    }
+
+    // Lines are only kept if the expression returns true, errors and other results drop the line
+    pub fn is_match(&self, value: &Value) -> bool {
+        let Ok(record) = rhai::serde::to_dynamic(value) else {
+            return false;
+        };
+        let mut scope = Scope::new();
+        scope.push_constant_dynamic("record", record);
+        matches!(
+            self.engine
+                .eval_ast_with_scope::<bool>(&mut scope, &self.ast),
+            Ok(true)
+        )
+    }
+}
+
+// Same rules as json::get_path, numbers index into arrays
+fn get_path(value: Dynamic, path: &str) -> Option<Dynamic> {
+    path.split('.').try_fold(value, |current, segment| {
+        if current.is_map() {
+            current.cast::<Map>().remove(segment)
+        } else if current.is_array() {
+            let index = segment.parse::<usize>().ok()?;
+            current.cast::<rhai::Array>().into_iter().nth(index)
+        } else {
+            None
+        }
+    })
+}
@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+// Little endian magic numbers of a zstd frame and of the first of the 16 skippable frame variants
+const FRAME_MAGIC: u32 = 0xFD2F_B528;
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+const SKIPPABLE_MAGIC_MASK: u32 = 0xFFFF_FFF0;
+
+// Whether the bytes start with the magic number of a skippable frame, 0x184D2A50 to 0x184D2A5F
+// Decoders ignore these frames, producers use them to store their own data next to the compressed content
+pub fn is_skippable(bytes: &[u8]) -> bool {
+    bytes.len() >= 4
+        && u32::from_le_bytes(bytes[..4].try_into().unwrap()) & SKIPPABLE_MAGIC_MASK
+            == SKIPPABLE_MAGIC
+}
+
+// The size of a skippable frame at the start of the bytes including its 8 byte header
+pub fn skippable_size(bytes: &[u8]) -> Option<u64> {
+    if !is_skippable(bytes) || bytes.len() < 8 {
+        return None;
+    }
+    Some(8 + u64::from(u32::from_le_bytes(bytes[4..8].try_into().unwrap())))
+}
+
+// A skippable frame found by --extract-metadata
+pub struct SkippableFrame {
+    // Position of the frame in the compressed file
+    pub offset: u64,
+    // The last 4 bits of the magic number, which producers can use to tell kinds of data apart
+    pub variant: u8,
+    pub data: Vec<u8>,
+}
+
+// Walks the frames of a zstd file and returns the content of its skippable frames
+// Only frame and block headers are read, the compressed data is skipped over without decoding it
+pub fn skippable_frames(path: &Path) -> std::io::Result<Vec<SkippableFrame>> {
+    walk_frames(path).map(|(frames, _)| frames)
+}
+
+// The decompressed size of a zstd file as recorded in its frame headers, summed over all frames
+// None if any frame does not record its size, e.g. because its encoder was streaming
+pub fn content_size(path: &Path) -> std::io::Result<Option<u64>> {
+    walk_frames(path).map(|(_, content_size)| content_size)
+}
+
+// Walks all frames of a zstd file to make sure the last one is not cut off, without decoding anything
+pub fn check_complete(path: &Path) -> std::io::Result<()> {
+    walk_frames(path).map(|_| ())
+}
+
+// The skippable frames of a zstd file and the sum of the content sizes of its regular frames
+fn walk_frames(path: &Path) -> std::io::Result<(Vec<SkippableFrame>, Option<u64>)> {
+    let file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut frames = Vec::new();
+    let mut content_size = Some(0);
+    let mut offset = 0;
+    while offset < file_size {
+        let magic = read_u32(&mut reader)?;
+        if magic & SKIPPABLE_MAGIC_MASK == SKIPPABLE_MAGIC {
+            let size = read_u32(&mut reader)?;
+            let mut data = vec![0; size as usize];
+            reader.read_exact(&mut data)?;
+            frames.push(SkippableFrame {
+                offset,
+                variant: (magic & !SKIPPABLE_MAGIC_MASK) as u8,
+                data,
+            });
+            offset += 8 + u64::from(size);
+        } else if magic == FRAME_MAGIC {
+            let (size, frame_content_size) = skip_frame(&mut reader)?;
+            offset += 4 + size;
+            content_size = content_size
+                .zip(frame_content_size)
+                .map(|(sum, size)| sum + size);
+        } else {
+            return Err(invalid(format!("no zstd frame at offset {}", offset)));
+        }
+    }
+    if offset > file_size {
+        return Err(invalid(format!(
+            "the last frame ends after the end of the file at offset {}",
+            file_size
+        )));
+    }
+    Ok((frames, content_size))
+}
+
+// Skips the rest of a zstd frame after its magic number and returns how many bytes that was
+// and the content size from its header, if it has one
+// See https://github.com/facebook/zstd/blob/dev/doc/zstd_compression_format.md#frames
+fn skip_frame(reader: &mut BufReader<File>) -> std::io::Result<(u64, Option<u64>)> {
+    let mut descriptor = [0u8];
+    reader.read_exact(&mut descriptor)?;
+    let descriptor = descriptor[0];
+    let single_segment = descriptor & 0x20 != 0;
+    let has_checksum = descriptor & 0x04 != 0;
+    let dictionary_id_size = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+    let content_size_size = match descriptor >> 6 {
+        0 => u64::from(single_segment),
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    let header_rest = u64::from(!single_segment) + dictionary_id_size + content_size_size;
+    let mut header = vec![0u8; header_rest as usize];
+    reader.read_exact(&mut header)?;
+    let content_size = (content_size_size > 0).then(|| {
+        let mut field = [0u8; 8];
+        field[..content_size_size as usize]
+            .copy_from_slice(&header[(header_rest - content_size_size) as usize..]);
+        // The 2 byte field is stored with an offset of 256
+        u64::from_le_bytes(field) + if content_size_size == 2 { 256 } else { 0 }
+    });
+    let mut size = 1 + header_rest;
+    loop {
+        let mut block_header = [0u8; 3];
+        reader.read_exact(&mut block_header)?;
+        let block_header =
+            u32::from_le_bytes([block_header[0], block_header[1], block_header[2], 0]);
+        let block_size = match (block_header >> 1) & 0x03 {
+            // Raw and compressed blocks store their size, RLE blocks a single byte
+            0 | 2 => u64::from(block_header >> 3),
+            1 => 1,
+            _ => return Err(invalid(String::from("reserved block type"))),
+        };
+        reader.seek_relative(block_size as i64)?;
+        size += 3 + block_size;
+        if block_header & 0x01 != 0 {
+            break;
+        }
+    }
+    if has_checksum {
+        reader.seek_relative(4)?;
+        size += 4;
+    }
+    Ok((size, content_size))
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,174 @@
+use chrono::{DateTime, NaiveDate};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+// Recursively search a parsed JSON value for an object key with the given name
+// Objects inside arrays are searched as well, the array itself does not count as a level
+// A max_depth of 0 means unlimited, 1 only checks the keys of the top level object
+pub fn has_key(value: &Value, key: &str, max_depth: usize) -> bool {
+    has_key_at_depth(value, key, max_depth, 1)
+}
+
+fn has_key_at_depth(value: &Value, key: &str, max_depth: usize, depth: usize) -> bool {
+    if max_depth != 0 && depth > max_depth {
+        return false;
+    }
+    match value {
+        Value::Object(map) => {
+            map.contains_key(key)
+                || map
+                    .values()
+                    .any(|v| has_key_at_depth(v, key, max_depth, depth + 1))
+        }
+        Value::Array(array) => array
+            .iter()
+            .any(|v| has_key_at_depth(v, key, max_depth, depth)),
+        _ => false,
+    }
+}
+
+// Look up a field by a dotted path like "user.name", numbers index into arrays like "tags.0"
+pub fn get_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(value, |current, segment| match current {
+            Value::Object(map) => map.get(segment),
+            Value::Array(array) => array.get(segment.parse::<usize>().ok()?),
+            _ => None,
+        })
+}
+
+// Sort the keys of every object by name for --canonicalize-json, including objects nested in objects and arrays
+// serde_json keeps the original key order with preserve_order, so every object is rebuilt
+pub fn sort_keys(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(String, Value)> = std::mem::take(map).into_iter().collect();
+            entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+            for (key, mut value) in entries {
+                sort_keys(&mut value);
+                map.insert(key, value);
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(sort_keys),
+        _ => {}
+    }
+}
+
+// The JSON types that can be required with --type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JsonType {
+    Number,
+    String,
+    Bool,
+    Array,
+    Object,
+    Null,
+}
+
+impl JsonType {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "number" => Some(JsonType::Number),
+            "string" => Some(JsonType::String),
+            "bool" => Some(JsonType::Bool),
+            "array" => Some(JsonType::Array),
+            "object" => Some(JsonType::Object),
+            "null" => Some(JsonType::Null),
+            _ => None,
+        }
+    }
+
+    pub fn matches(&self, value: &Value) -> bool {
+        matches!(
+            (self, value),
+            (JsonType::Number, Value::Number(_))
+                | (JsonType::String, Value::String(_))
+                | (JsonType::Bool, Value::Bool(_))
+                | (JsonType::Array, Value::Array(_))
+                | (JsonType::Object, Value::Object(_))
+                | (JsonType::Null, Value::Null)
+        )
+    }
+}
+
+// Sort key of a field for --sort-by, numbers come first by value, then strings and then anything else by its JSON text
+#[derive(Debug)]
+pub enum SortKey {
+    Number(f64),
+    String(String),
+    Other(String),
+}
+
+impl SortKey {
+    pub fn new(value: &Value) -> Self {
+        match value {
+            Value::Number(number) => SortKey::Number(number.as_f64().unwrap_or_default()),
+            Value::String(text) => SortKey::String(text.clone()),
+            other => SortKey::Other(other.to_string()),
+        }
+    }
+
+    pub fn compare(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (SortKey::Number(a), SortKey::Number(b)) => a.total_cmp(b),
+            (SortKey::String(a), SortKey::String(b)) | (SortKey::Other(a), SortKey::Other(b)) => {
+                a.cmp(b)
+            }
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            SortKey::Number(_) => 0,
+            SortKey::String(_) => 1,
+            SortKey::Other(_) => 2,
+        }
+    }
+}
+
+// Epoch values above this are taken as milliseconds, as seconds it would be the year 5138
+const EPOCH_MILLIS_THRESHOLD: f64 = 1e11;
+
+// The text a field is compared by, strings by their content and anything else by its JSON text
+pub fn value_text(value: &Value) -> Cow<'_, str> {
+    match value {
+        Value::String(text) => Cow::Borrowed(text),
+        other => Cow::Owned(other.to_string()),
+    }
+}
+
+// Read a timestamp field as milliseconds since the epoch
+// Numbers and numeric strings are epoch seconds or milliseconds, other strings RFC 3339
+pub fn timestamp_millis(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(number) => epoch_millis(number.as_f64()?),
+        Value::String(text) => parse_timestamp(text),
+        _ => None,
+    }
+}
+
+// Parse a timestamp given as epoch seconds or milliseconds, RFC 3339 or a plain date (midnight UTC)
+pub fn parse_timestamp(text: &str) -> Option<i64> {
+    let text = text.trim();
+    if let Ok(number) = text.parse::<f64>() {
+        return epoch_millis(number);
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Some(time.timestamp_millis());
+    }
+    let date = NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp_millis())
+}
+
+fn epoch_millis(number: f64) -> Option<i64> {
+    if !number.is_finite() {
+        return None;
+    }
+    if number.abs() >= EPOCH_MILLIS_THRESHOLD {
+        Some(number as i64)
+    } else {
+        Some((number * 1000.0) as i64)
+    }
+}
@@ -1,24 +1,65 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::error::Error;
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Error as IoError, Lines, Read, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, IsTerminal, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::time::{Duration, Instant};
-use std::{fs, process, u64, usize};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fs, process};
 
-use clap::Parser;
+use aho_corasick::AhoCorasick;
+use chrono::FixedOffset;
+use clap::{CommandFactory, Parser};
 use colored::*;
-use indicatif::{HumanBytes, HumanCount, HumanDuration, ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, HumanCount, HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
 use rayon::prelude::*;
-use regex::Regex;
-use serde::{Deserialize, Serialize};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sysinfo::System;
 use terminal_size::{terminal_size, Width};
+use xxhash_rust::xxh3::Xxh3;
 use zstd::stream::read::Decoder;
 use zstd::stream::write::Encoder;
 
+mod bloom;
+#[cfg(feature = "filter-expr")]
+mod filter_expr;
+mod frames;
+mod json;
+mod manifest;
+mod selftest;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
 const PB_UPDATE_INTERVAL: u64 = 1000; // Update interval in ms
+const SNAPSHOT_POLL_INTERVAL: u64 = 50; // How often in ms the progress updater checks for a SIGUSR1 between updates
+const MIN_BAR_WIDTH: usize = 10; // Narrowest progress bar in columns, used when the terminal is too narrow
+const FILE_PB_UPDATE_BYTES: usize = 1 << 20; // Update the --file-progress bars every MiB of decompressed data
+const PROGRESS_LOG_MAX_SIZE: u64 = 10_000_000; // Rotate the progress log after 10 MB
+const ZSTD_FRAME_HEADER_MAX: usize = 18; // The largest possible zstd frame header in bytes
+const DECODE_ERROR_CONTEXT: usize = 32; // Bytes shown before a decoding error
+const DEFAULT_BLOOM_FPR: f64 = 0.01; // False positive rate of --build-bloom without --bloom-fpr
+const EXPLAIN_LINE_PREFIX: usize = 120; // Bytes of a line shown with --explain
+const RESUME_SAMPLE_SIZE: u64 = 65536; // Bytes read from the start and end of a file for --resume-by-hash
+const JSON_ARRAY_START: &[u8] = b"[\n"; // Written before the first record with --json-array
+const JSON_ARRAY_END: &[u8] = b"\n]"; // Written after the last record with --json-array
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd]; // Every zstd frame starts with this magic number
+const RECOVERY_READ_SIZE: usize = 131072; // Compressed bytes read at once with --skip-corrupt-frames
+const DISK_FULL_RETRY_INTERVAL: u64 = 10; // Seconds between write attempts with --on-disk-full pause
+const WINDOW_LOG_MIN: u32 = 10; // Smallest --window-log-max zstd accepts
+const WINDOW_LOG_MAX: u32 = if cfg!(target_pointer_width = "64") {
+    31
+} else {
+    30
+}; // Largest --window-log-max zstd accepts
+const CONCAT_FIELD: &str = "_concat"; // Field --concat-fields adds to the parsed JSON of a line
+const MAX_OPEN_PARTITIONS: usize = 64; // Files kept open by --partition-by-time without --max-open-files
+
+// Set by SIGUSR1 with --progress-on-signal, the progress updater then prints a snapshot to stderr
+static SNAPSHOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+// Numbers the temporary files of --include-content-size outputs within the process
+static SPOOL_FILES: AtomicUsize = AtomicUsize::new(0);
 
 fn main() -> Result<(), Box<dyn Error>> {
     // Shared counter for the total decompressed size
@@ -26,63 +67,300 @@ fn main() -> Result<(), Box<dyn Error>> {
     let global_decompressed_lines = Arc::new(AtomicUsize::new(0));
     let global_filtered_lines = Arc::new(AtomicUsize::new(0));
     let global_processed_size = Arc::new(AtomicU64::new(0));
+    let global_in_progress_size = Arc::new(InProgressSize::default());
     let global_to_be_processed_size = Arc::new(AtomicU64::new(0));
+    let global_files_processed = AtomicUsize::new(0);
+    let global_files_skipped = AtomicUsize::new(0);
+    let global_files_failed = AtomicUsize::new(0);
+    let failed_files = Mutex::new(Vec::new());
+    // Inputs written to a merged output or time partitions, they all fail if one of those does not verify
+    let shared_output_inputs = Mutex::new(Vec::new());
 
     // Set up config parameters from cli, the config file and fallback values
-    let config = set_config();
+    let config = set_config(Cli::parse());
 
-    // Create thread pool for file processing, we also need to reserve one for the progress updater
-    let threads = if config.threads == 0 {
-        0
-    } else {
-        config.threads + 1
-    };
+    // Stored as the last run once everything succeeded, files modified during the run are picked up again next time
+    let run_started = SystemTime::now();
+
+    // A zstd stream on stdin is filtered to stdout instead of searching for files
+    if config.input == ["-"] {
+        return filter_stdin(&config);
+    }
 
+    // Create thread pool for file processing, the progress updater runs on its own thread outside of it
     rayon::ThreadPoolBuilder::new()
-        .num_threads(threads)
+        .num_threads(config.threads)
         .build_global()
         .unwrap();
 
-    // Find all .zst files in input_path
+    // Find all files with an accepted extension in input_path
     let mut total_dir_size = 0;
     let mut zstd_files = Vec::new();
 
-    // Verify that the input path is valid and create it if necessary
-    let input_path = PathBuf::from(&config.input);
-    if input_path.exists() {
-        if !input_path.is_dir() {
-            if input_path.extension().and_then(|ext| ext.to_str()) == Some("zst") {
+    // Verify that the input paths are valid, invalid ones can be skipped with --skip-invalid-inputs
+    let mut invalid_inputs = Vec::new();
+    // Symlinks, FIFOs, sockets, devices and directories that only look like input files
+    let mut special_files = Vec::new();
+    // A recursive pattern like /data/**/*.zst would read the outputs of earlier runs again if they are written below /data
+    // Directories that do not exist yet can not contain anything
+    let scan_excluded_dirs: Vec<PathBuf> = if config.include_output_in_scan {
+        Vec::new()
+    } else {
+        config
+            .output_for
+            .iter()
+            .filter_map(|mapping| Some(mapping.split_once('=')?.1))
+            .chain([config.output.as_str()])
+            .filter_map(|dir| fs::canonicalize(dir).ok())
+            .collect()
+    };
+    let mut files_in_output = 0;
+    for input in &config.input {
+        let input_path = PathBuf::from(input);
+        // Inputs like /data/**/*.jsonl.zst are expanded as a glob, every matching regular file is used
+        if is_glob(input) && !input_path.exists() {
+            match glob_paths(input, config.max_depth) {
+                Ok(paths) => {
+                    let files_before = zstd_files.len();
+                    let recursive = input.contains("**");
+                    for path in paths {
+                        if recursive
+                            && fs::canonicalize(&path).is_ok_and(|path| {
+                                scan_excluded_dirs.iter().any(|dir| path.starts_with(dir))
+                            })
+                        {
+                            files_in_output += 1;
+                            continue;
+                        }
+                        match fs::metadata(&path) {
+                            Ok(metadata) if metadata.is_file() => {
+                                total_dir_size += metadata.len();
+                                zstd_files.push(path);
+                            }
+                            Ok(_) => special_files.push((path, false)),
+                            Err(_) => {}
+                        }
+                    }
+                    if zstd_files.len() == files_before {
+                        eprintln!(
+                            "{}",
+                            format!("No files found for the input pattern {:?}", input).yellow()
+                        );
+                    }
+                }
+                Err(e) if config.skip_invalid_inputs => {
+                    eprintln!(
+                        "{}",
+                        format!("Skipping invalid input pattern {:?}: {}", input, e).yellow()
+                    );
+                    invalid_inputs.push(input.clone());
+                }
+                Err(e) => {
+                    eprintln!("Error: The input pattern '{:?}' is not valid: {}", input, e);
+                    std::process::exit(1);
+                }
+            }
+            continue;
+        }
+        if input_path.is_file() {
+            if has_input_extension(&input_path, &config.input_extensions) {
                 let metadata_res = input_path.metadata();
                 if let Ok(metadata) = metadata_res {
                     total_dir_size += metadata.len();
                     zstd_files.push(input_path);
                 }
             }
-        } else {
-            zstd_files = fs::read_dir(&config.input)?
-                .filter_map(|entry| {
+            continue;
+        }
+        match fs::read_dir(&input_path) {
+            Ok(entries) => {
+                zstd_files.extend(entries.filter_map(|entry| {
                     let entry = entry.ok()?;
                     let path = entry.path();
-                    if path.extension().and_then(|ext| ext.to_str()) == Some("zst") {
-                        let metadata = entry.metadata().ok()?;
-                        total_dir_size += metadata.len();
-                        Some(path)
+                    if !has_input_extension(&path, &config.input_extensions) {
+                        return None;
+                    }
+                    // Only regular files are read, opening a FIFO or a device could block forever
+                    // The metadata of a symlink describes the link itself unless it is followed
+                    let is_symlink = entry.file_type().ok()?.is_symlink();
+                    let metadata = if is_symlink && config.follow_symlinks {
+                        fs::metadata(&path).ok()
                     } else {
-                        None
+                        entry.metadata().ok()
+                    };
+                    match metadata {
+                        Some(metadata) if metadata.is_file() => {
+                            total_dir_size += metadata.len();
+                            Some(path)
+                        }
+                        _ => {
+                            special_files.push((path, is_symlink));
+                            None
+                        }
                     }
-                })
-                .collect();
+                }));
+            }
+            Err(e) if config.skip_invalid_inputs => {
+                eprintln!(
+                    "{}",
+                    format!("Skipping invalid input path {:?}: {}", input, e).yellow()
+                );
+                invalid_inputs.push(input.clone());
+            }
+            Err(_) => {
+                eprintln!(
+                    "Error: The input path '{:?}' is not a valid directory.",
+                    input
+                );
+                std::process::exit(1);
+            }
         }
-    } else {
+    }
+
+    if files_in_output > 0 {
+        print_if_not_quiet(
+            config.quiet,
+            &format!(
+                "Skipping {} file(s) in the output directory found by a recursive input pattern, use --include-output-in-scan to read them",
+                files_in_output
+            ),
+        );
+    }
+
+    for (path, is_symlink) in &special_files {
+        let reason = if *is_symlink && !config.follow_symlinks {
+            "a symlink, use --follow-symlinks to include it"
+        } else if *is_symlink {
+            "a symlink that does not point to a regular file"
+        } else {
+            "not a regular file"
+        };
         eprintln!(
-            "Error: The input path '{:?}' is not a valid directory.",
-            &config.input
+            "{}",
+            format!("Skipping {:?}, it is {}", path, reason).yellow()
+        );
+    }
+
+    // Stale outputs are judged against every input found, not just the ones left to process
+    let found_inputs = zstd_files.clone();
+
+    // Leave out files that were not modified since --newer-than, which only needs their metadata
+    let last_run_path = format!("{}last_run", config.output);
+    if !config.newer_than.is_empty() {
+        let threshold = if config.newer_than == "last-run" {
+            let last_run = fs::read_to_string(&last_run_path)
+                .ok()
+                .and_then(|content| json::parse_timestamp(&content));
+            if last_run.is_none() {
+                print_if_not_quiet(
+                    config.quiet,
+                    &format!(
+                        "No previous run recorded in {}, processing all files",
+                        last_run_path
+                    ),
+                );
+            }
+            last_run
+        } else {
+            json::parse_timestamp(&config.newer_than)
+        };
+        if let Some(threshold) = threshold {
+            let files_before = zstd_files.len();
+            let mut old_size = 0;
+            zstd_files.retain(|path| {
+                let Ok(metadata) = fs::metadata(path) else {
+                    // Unreadable files are kept so the error is reported when processing them
+                    return true;
+                };
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|since_epoch| since_epoch.as_millis() as i64);
+                if modified.is_some_and(|modified| modified < threshold) {
+                    old_size += metadata.len();
+                    false
+                } else {
+                    true
+                }
+            });
+            total_dir_size -= old_size;
+            print_if_not_quiet(
+                config.quiet,
+                &format!(
+                    "Skipping {} file(s) not modified since --newer-than",
+                    files_before - zstd_files.len()
+                ),
+            );
+        }
+    }
+
+    // Leave out files whose content was already processed in an earlier run, even if they were renamed
+    let resume_manifest_path = format!("{}resume.manifest", config.output);
+    let mut resume_keys = HashMap::new();
+    if config.resume_by_hash {
+        let processed_keys: HashSet<String> = fs::read_to_string(&resume_manifest_path)
+            .map(|content| {
+                content
+                    .lines()
+                    .filter_map(|line| Some(line.split('\t').next()?.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let files_before = zstd_files.len();
+        let mut resumed_size = 0;
+        zstd_files.retain(|path| match content_key(path) {
+            Ok(key) => {
+                let key = format!("{:016x}", key);
+                if processed_keys.contains(&key) {
+                    resumed_size += fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+                    false
+                } else {
+                    resume_keys.insert(path.clone(), key);
+                    true
+                }
+            }
+            // Unreadable files are kept so the error is reported when processing them
+            Err(_) => true,
+        });
+        total_dir_size -= resumed_size;
+        print_if_not_quiet(
+            config.quiet,
+            &format!(
+                "Skipping {} file(s) already listed in {}",
+                files_before - zstd_files.len(),
+                resume_manifest_path
+            ),
         );
-        std::process::exit(1);
     }
-    global_to_be_processed_size.fetch_add(total_dir_size, Ordering::Relaxed);
 
-    if !Path::new(&config.input).is_dir() {}
+    // Keep only the first files in the order they would be processed, name order with --ordered
+    let files_limited = config.limit_files > 0 && zstd_files.len() > config.limit_files;
+    if files_limited {
+        let files_before = zstd_files.len();
+        sort_by_schedule(
+            &mut zstd_files,
+            if config.ordered {
+                "name"
+            } else {
+                &config.schedule
+            },
+        );
+        let limited_size: u64 = zstd_files
+            .drain(config.limit_files..)
+            .map(|path| fs::metadata(path).map(|m| m.len()).unwrap_or(0))
+            .sum();
+        total_dir_size -= limited_size;
+        print_if_not_quiet(
+            config.quiet,
+            &format!(
+                "Skipping {} file(s) over --limit-files {}",
+                files_before - zstd_files.len(),
+                config.limit_files
+            ),
+        );
+    }
+    global_to_be_processed_size.fetch_add(total_dir_size, Ordering::Relaxed);
 
     // Verify that the output path is valid and create it if necessary
     let output_path = Path::new(&config.output);
@@ -99,13 +377,134 @@ fn main() -> Result<(), Box<dyn Error>> {
         fs::create_dir_all(output_path)?;
     }
 
+    // Compile the line filter once for all files
+    let filter = match LineFilter::new(&config) {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    // Every --output-for directory and --route category subdirectory is created up front
+    let output_dirs = config
+        .output_for
+        .iter()
+        .filter_map(|mapping| Some(mapping.split_once('=')?.1))
+        .chain([config.output.as_str()]);
+    for dir in output_dirs {
+        fs::create_dir_all(dir)?;
+        // Match count buckets are files next to the regular output
+        if config.bucket_by_match_count {
+            continue;
+        }
+        for (name, _) in &filter.routes {
+            fs::create_dir_all(Path::new(dir).join(name))?;
+        }
+    }
+
+    // Outputs of an earlier run with a different naming would never be skipped or overwritten, they just pile up
+    let writes_per_input = !config.no_write
+        && !config.count_only
+        && config.merge_output.is_empty()
+        && config.sqlite.is_empty()
+        && config.partition_by_time.is_empty();
+    if writes_per_input {
+        let stale = stale_outputs(&found_inputs, &config);
+        if config.clean_stale_outputs {
+            let mut deleted = 0;
+            for path in &stale {
+                match fs::remove_file(path) {
+                    Ok(()) => deleted += 1,
+                    Err(e) => eprintln!("Failed to delete {:?}: {}", path, e),
+                }
+            }
+            if deleted > 0 {
+                print_if_not_quiet(
+                    config.quiet,
+                    &format!("Deleted {} stale output file(s)", deleted),
+                );
+            }
+        } else if !stale.is_empty() {
+            eprintln!(
+                "{}",
+                format!(
+                    "Found {} output file(s) that do not match the current --file-extension or --zstd, e.g. {:?}. Use --clean-stale-outputs to delete them.",
+                    stale.len(),
+                    stale[0]
+                )
+                .yellow()
+            );
+        }
+    }
+
+    // Shared by all writers to stop once --max-output-bytes have been written
+    let output_limit = Arc::new(OutputLimit::new(&config));
+
+    // Shared by all readers for --max-read-rate
+    let read_rate = RateLimit::new(config.max_read_rate);
+
+    // Create the shared output file for --merge-output, existing files are never overwritten
+    // With --shard-by-thread every worker thread gets its own output file instead
+    // --sqlite works the same way, just with a database instead of a file
+    let merged_output_paths: Vec<String> = if config.no_write || config.count_only {
+        Vec::new()
+    } else if !config.sqlite.is_empty() {
+        vec![config.sqlite.clone()]
+    } else if config.merge_output.is_empty() {
+        Vec::new()
+    } else if config.shard_by_thread {
+        (0..rayon::current_num_threads())
+            .map(|index| shard_file_name(&config.merge_output, index))
+            .collect()
+    } else {
+        vec![config.merge_output.clone()]
+    };
+    let mut merged_outputs = Vec::new();
+    for merged_output_path in &merged_output_paths {
+        if config.append && config.sqlite.is_empty() {
+            merged_outputs.push(Mutex::new(MergedOutput::append(
+                merged_output_path,
+                &config,
+                &output_limit,
+            )?));
+            continue;
+        }
+        if Path::new(merged_output_path).exists() {
+            eprintln!(
+                "Error: The merge output file '{:?}' already exists.",
+                merged_output_path
+            );
+            std::process::exit(1);
+        }
+        #[cfg(feature = "sqlite")]
+        if !config.sqlite.is_empty() {
+            merged_outputs.push(Mutex::new(MergedOutput::sqlite(merged_output_path)?));
+            continue;
+        }
+        let file = File::create(merged_output_path)?;
+        merged_outputs.push(Mutex::new(MergedOutput::new(file, &config, &output_limit)?));
+    }
+
+    // Every successfully processed file is added to the manifest right away, so an aborted run can be resumed
+    let resume_manifest = if config.resume_by_hash && !config.no_write && !config.count_only {
+        Some(Mutex::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&resume_manifest_path)?,
+        ))
+    } else {
+        None
+    };
+
     // Display files
     let total_files = zstd_files.len();
     //let display_limit = 5;
     print_if_not_quiet(
         config.quiet,
         &format!(
-            "Found {} .zst file(s) ({})",
+            "Found {} input file(s) ({})",
             total_files,
             HumanBytes(total_dir_size)
         ),
@@ -121,17 +520,26 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     // Create progress bar
     let bar_width = match terminal_size() {
-        Some((Width(w), _)) => w as usize - 52,
+        // The rest of the line takes 52 columns, narrow terminals still get a short bar instead of an underflow
+        Some((Width(w), _)) => (w as usize).saturating_sub(52).max(MIN_BAR_WIDTH),
         None => 40,
     };
-    let pb = ProgressBar::new(zstd_files.len() as u64);
-    pb.set_style(
-        ProgressStyle::with_template(&format!(
+    // The aggregate bar stays on top, --file-progress adds a bar per file being filtered below it
+    let multi_progress = MultiProgress::new();
+    let pb = multi_progress.add(ProgressBar::new(zstd_files.len() as u64));
+    // The compact style fits on a single line without a bar, for narrow terminals and captured logs
+    let template = if config.progress_style == "compact" {
+        String::from("[{elapsed_precise}] {pos}/{len} files {msg}")
+    } else {
+        format!(
             "[{{elapsed_precise}}] [{{spinner:.cyan}}{{bar:{}.cyan/blue}}] {{pos}}/{{len}} files {{msg}}",
             bar_width
-        ))
-        .unwrap()
-        .progress_chars("#>-"),
+        )
+    };
+    pb.set_style(
+        ProgressStyle::with_template(&template)
+            .unwrap()
+            .progress_chars("#>-"),
     );
     pb.enable_steady_tick(Duration::from_millis(50));
 
@@ -145,22 +553,225 @@ fn main() -> Result<(), Box<dyn Error>> {
     let e = Arc::clone(&global_decompressed_lines);
     let f = Arc::clone(&global_filtered_lines);
     let g = Arc::clone(&global_processed_size);
-    rayon::spawn(move || start_progress_updater(start_time, &a, b, &c, &d, &e, &f, &g));
+    let h = Arc::clone(&global_in_progress_size);
+    if config.progress_on_signal {
+        install_snapshot_signal();
+    }
+    std::thread::spawn(move || start_progress_updater(start_time, &a, b, &c, &d, &e, &f, &g, &h));
+
+    // --partition-by-time keeps up to half of --max-open-files open for its buckets
+    let max_open_partitions = if config.max_open_files == 0 {
+        MAX_OPEN_PARTITIONS
+    } else {
+        (config.max_open_files / 2).max(1)
+    };
+    let partitions = match TimePartitions::new(&config, &output_limit, max_open_partitions) {
+        Ok(partitions) => partitions.map(Mutex::new),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let shared_handles = if partitions.is_some() {
+        max_open_partitions
+    } else {
+        merged_outputs.len()
+    };
+
+    // Each file being filtered holds its input and its own output open, shared outputs are opened up front
+    // The --route categories or --bucket-by-match-count buckets of an input are further outputs of their own
+    let handles_per_file = if merged_outputs.is_empty()
+        && partitions.is_none()
+        && !config.no_write
+        && !config.count_only
+    {
+        2 + filter.routes.len() + usize::from(config.bucket_by_match_count)
+    } else {
+        1
+    };
+    // No handles left for the inputs would mean no limit at all
+    if config.max_open_files > 0 && config.max_open_files < shared_handles + handles_per_file {
+        eprintln!(
+            "Error: --max-open-files {} is too low, {} shared output(s) and {} file(s) per input need {}.",
+            config.max_open_files,
+            shared_handles,
+            handles_per_file,
+            shared_handles + handles_per_file
+        );
+        std::process::exit(1);
+    }
+    let file_handles = FileHandleLimit::new(config.max_open_files.saturating_sub(shared_handles));
 
     // Start a file operation for every available thread
-    zstd_files.par_iter().for_each(|file_path| {
-        let _ = read_lines(
-            &file_path,
+    let process_file = |file_path: &PathBuf| {
+        let permit = file_handles.acquire(handles_per_file);
+        let file_pb = config
+            .file_progress
+            .then(|| add_file_progress_bar(&multi_progress, file_path));
+        let shared_output = match &partitions {
+            Some(partitions) => Some(SharedOutput::Partitioned(partitions)),
+            None => merged_outputs
+                .get(if config.shard_by_thread {
+                    rayon::current_thread_index().unwrap_or(0)
+                } else {
+                    0
+                })
+                .map(SharedOutput::Merged),
+        };
+        let writes_shared_output = shared_output.is_some();
+        let result = read_lines(
+            file_path,
+            shared_output,
             &config,
+            &filter,
+            &output_limit,
             &pb,
+            file_pb.as_ref(),
             &global_decompressed_size,
             &global_decompressed_lines,
             &global_filtered_lines,
             &global_processed_size,
+            &global_in_progress_size,
             &global_to_be_processed_size,
+            &read_rate,
         );
+        drop(permit);
+        if let Some(file_pb) = file_pb {
+            file_pb.finish_and_clear();
+            multi_progress.remove(&file_pb);
+        }
+        if let (Ok(FileStatus::Processed), Some(manifest), Some(key)) =
+            (&result, &resume_manifest, resume_keys.get(file_path))
+        {
+            // The output is hashed once it is complete so --verify-manifest can check it later
+            let output = generate_output_filename(&file_path.to_string_lossy(), &config);
+            let checksum = if writes_per_input {
+                manifest::checksum(Path::new(&output)).ok()
+            } else {
+                None
+            };
+            let entry = manifest::entry(
+                key,
+                file_path,
+                checksum.map(|(size, hash)| (output.as_str(), size, hash)),
+            );
+            if let Err(e) = manifest.lock().unwrap().write_all(entry.as_bytes()) {
+                eprintln!("Failed to update {:?}: {}", resume_manifest_path, e);
+            }
+        }
+        match result {
+            Ok(FileStatus::Processed) => {
+                if writes_shared_output {
+                    shared_output_inputs.lock().unwrap().push(file_path.clone());
+                }
+                global_files_processed.fetch_add(1, Ordering::Relaxed)
+            }
+            Ok(FileStatus::Skipped) => global_files_skipped.fetch_add(1, Ordering::Relaxed),
+            Err(_) => {
+                // A failed file will never be added to the processed size, so remove it from the total instead
+                if let Ok(metadata) = fs::metadata(file_path) {
+                    global_to_be_processed_size.fetch_sub(metadata.len(), Ordering::Relaxed);
+                }
+                failed_files.lock().unwrap().push(file_path.clone());
+                global_files_failed.fetch_add(1, Ordering::Relaxed)
+            }
+        };
         pb.inc(1);
-    });
+    };
+    if config.ordered {
+        // One file after another in name order so the output is the same for every run
+        zstd_files.sort();
+        zstd_files.iter().for_each(process_file);
+    } else if config.batch > 0 {
+        // Only the files of one batch are in flight at a time, which caps the memory used by huge files
+        let batches = size_balanced_batches(&zstd_files, config.batch);
+        let batch_count = batches.len();
+        for (index, (mut batch, batch_size)) in batches.into_iter().enumerate() {
+            sort_by_schedule(&mut batch, &config.schedule);
+            batch.par_iter().for_each(process_file);
+            if !config.quiet {
+                pb.suspend(|| {
+                    println!(
+                        "Batch {}/{} done: {} file(s) ({}), {} processed, {} skipped, {} failed so far",
+                        index + 1,
+                        batch_count,
+                        batch.len(),
+                        HumanBytes(batch_size),
+                        global_files_processed.load(Ordering::Relaxed),
+                        global_files_skipped.load(Ordering::Relaxed),
+                        global_files_failed.load(Ordering::Relaxed)
+                    )
+                });
+            }
+        }
+    } else {
+        sort_by_schedule(&mut zstd_files, &config.schedule);
+        zstd_files.iter().par_bridge().for_each(process_file);
+    }
+
+    // All inputs are done, finish the shared output files
+    let mut broken_shared_outputs = 0;
+    for (merged_output, merged_output_path) in merged_outputs.into_iter().zip(&merged_output_paths)
+    {
+        let mut merged_output = merged_output.into_inner().unwrap();
+        let lines_written = merged_output.lines_written();
+        if config.json_array {
+            // An empty array is closed right after the opening bracket
+            let end = if lines_written == 0 {
+                &b"]"[..]
+            } else {
+                JSON_ARRAY_END
+            };
+            merged_output.writer.write_all(end)?;
+        }
+        merged_output.writer.finish()?;
+        if lines_written == 0 && config.shard_by_thread {
+            // Not every thread necessarily got a file to work on
+            fs::remove_file(merged_output_path)?;
+        } else if config.verify_output && config.sqlite.is_empty() {
+            if let Err(err) = verify_output(Path::new(merged_output_path), lines_written, &config) {
+                eprintln!("{}", err.red());
+                broken_shared_outputs += 1;
+            }
+        }
+    }
+
+    if let Some(partitions) = partitions {
+        let (partition_count, unpartitioned, broken_partitions) =
+            partitions.into_inner().unwrap().finish()?;
+        broken_shared_outputs += broken_partitions;
+        if !config.quiet {
+            println!(
+                "Wrote {} time partition(s) to {}",
+                partition_count, config.output
+            );
+            if unpartitioned > 0 {
+                println!(
+                    "{}",
+                    format!(
+                        "{} kept line(s) had no usable {:?} timestamp and went to unpartitioned",
+                        unpartitioned,
+                        config
+                            .partition_by_time
+                            .rsplit_once(':')
+                            .map_or("", |(path, _)| path)
+                    )
+                    .yellow()
+                );
+            }
+        }
+    }
+
+    // Which input a broken line came from is unknown, so every input that went into the shared outputs
+    // fails like an input whose own output does not verify
+    if broken_shared_outputs > 0 {
+        let inputs = shared_output_inputs.into_inner().unwrap();
+        global_files_processed.fetch_sub(inputs.len(), Ordering::Relaxed);
+        global_files_failed.fetch_add(inputs.len(), Ordering::Relaxed);
+        failed_files.lock().unwrap().extend(inputs);
+    }
+
+    let duration = start_time.elapsed();
 
     // Wait PB_UPDATE_INTERVAL so the progressbar updates one last time
     std::thread::sleep(Duration::from_millis(PB_UPDATE_INTERVAL * 2));
@@ -168,33 +779,279 @@ fn main() -> Result<(), Box<dyn Error>> {
     //pb.finish_with_message("All files processed.");
     pb.finish();
     println!("All files processed.");
+    // Where the lines were dropped, every line only counts for the first filter it fails
+    for &predicate in &filter.predicates {
+        println!(
+            "Lines dropped by {}: {}",
+            predicate.name(),
+            HumanCount(filter.dropped_by(predicate) as u64)
+        );
+    }
+    if config.max_line_bytes_hard > 0 && config.max_line_bytes_policy == "skip" {
+        println!(
+            "Lines skipped by --max-line-bytes-hard: {}",
+            HumanCount(filter.oversized_lines.load(Ordering::Relaxed) as u64)
+        );
+    }
+    if config.no_recompress {
+        println!(
+            "Inputs copied by --no-recompress, their lines are not counted: {}",
+            HumanCount(filter.copied_inputs.load(Ordering::Relaxed) as u64)
+        );
+    }
+    if !config.unique_by.is_empty() {
+        println!(
+            "Lines dropped by --unique-by: {}",
+            HumanCount(filter.duplicates.load(Ordering::Relaxed) as u64)
+        );
+        let untracked = filter.unique_untracked.load(Ordering::Relaxed);
+        if untracked > 0 {
+            println!(
+                "{}",
+                format!(
+                    "{} lines were kept without deduplication because --unique-max-keys was reached",
+                    HumanCount(untracked as u64)
+                )
+                .yellow()
+            );
+        }
+    }
+
+    if !config.distinct.is_empty() {
+        let values = filter.distinct_values.lock().unwrap();
+        let distinct_path = format!("{}distinct.jsonl", config.output);
+        let mut content: Vec<&str> = values.iter().map(String::as_str).collect();
+        sort_json_texts(&mut content);
+        match fs::write(&distinct_path, content.join("\n")) {
+            Ok(()) => println!(
+                "{} distinct values of {:?} written to {:?}",
+                HumanCount(values.len() as u64),
+                config.distinct,
+                distinct_path
+            ),
+            Err(e) => eprintln!("Failed to write {:?}: {}", distinct_path, e),
+        }
+        let untracked = filter.distinct_untracked.load(Ordering::Relaxed);
+        if untracked > 0 {
+            println!(
+                "{}",
+                format!(
+                    "--distinct-max-values was reached, the values of {} lines were not collected",
+                    HumanCount(untracked as u64)
+                )
+                .yellow()
+            );
+        }
+    }
+
+    if !config.group_by.is_empty() {
+        let groups = filter.groups.lock().unwrap();
+        let group_path = format!("{}grouped.jsonl", config.output);
+        let mut keys: Vec<&str> = groups.keys().map(String::as_str).collect();
+        sort_json_texts(&mut keys);
+        let result = File::create(&group_path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            for key in &keys {
+                write!(writer, "{{\"key\":{},\"records\":[", key)?;
+                for (index, record) in groups[*key].iter().enumerate() {
+                    if index > 0 {
+                        writer.write_all(b",")?;
+                    }
+                    writer.write_all(record.as_bytes())?;
+                }
+                writer.write_all(b"]}\n")?;
+            }
+            writer.flush()
+        });
+        match result {
+            Ok(()) => println!(
+                "{} groups of {:?} written to {:?}",
+                HumanCount(keys.len() as u64),
+                config.group_by,
+                group_path
+            ),
+            Err(e) => eprintln!("Failed to write {:?}: {}", group_path, e),
+        }
+        let ungrouped = filter.ungrouped.load(Ordering::Relaxed);
+        if ungrouped > 0 {
+            println!(
+                "{}",
+                format!(
+                    "--group-max-records was reached, {} matching lines were not grouped",
+                    HumanCount(ungrouped as u64)
+                )
+                .yellow()
+            );
+        }
+    }
+
+    // Document the run in the output directory, --distinct and --group-by write their result there as well
+    let writes_output_dir =
+        !config.no_write || !config.distinct.is_empty() || !config.group_by.is_empty();
+    if !config.no_summary_file && writes_output_dir {
+        let summary = RunSummary {
+            config: &config,
+            files_found: total_files,
+            invalid_inputs: &invalid_inputs,
+            files_processed: global_files_processed.load(Ordering::Relaxed),
+            files_skipped: global_files_skipped.load(Ordering::Relaxed),
+            files_failed: global_files_failed.load(Ordering::Relaxed),
+            total_lines: global_decompressed_lines.load(Ordering::Relaxed),
+            matched_lines: global_filtered_lines.load(Ordering::Relaxed),
+            type_mismatched_lines: filter.dropped_by(Predicate::Type),
+            time_excluded_lines: filter.dropped_by(Predicate::Time),
+            dropped_lines: filter
+                .predicates
+                .iter()
+                .map(|&predicate| {
+                    (
+                        predicate.name().to_string(),
+                        filter.dropped_by(predicate).into(),
+                    )
+                })
+                .collect(),
+            duplicate_lines: filter.duplicates.load(Ordering::Relaxed),
+            ratio_outliers: filter.ratio_outliers.lock().unwrap().clone(),
+            compressed_size: global_processed_size.load(Ordering::Relaxed),
+            decompressed_size: global_decompressed_size.load(Ordering::Relaxed),
+            duration_seconds: duration.as_secs_f64(),
+        };
+        let summary_path = format!("{}summary.json", config.output);
+        if let Err(e) = File::create(&summary_path).and_then(|file| {
+            serde_json::to_writer_pretty(file, &summary).map_err(std::io::Error::from)
+        }) {
+            eprintln!("Failed to write {:?}: {}", summary_path, e);
+        }
+    }
+
+    // List the inputs that failed so exactly those can be processed again with --retry-from
+    if !config.no_write && !config.count_only {
+        let failed_list_path = format!("{}failed.list", config.output);
+        let mut failed_files = failed_files.into_inner().unwrap();
+        if failed_files.is_empty() {
+            // The list of an earlier run would retry files that are fine by now
+            let _ = fs::remove_file(&failed_list_path);
+        } else {
+            failed_files.sort();
+            let content: String = failed_files
+                .iter()
+                .map(|path| format!("{}\n", path.display()))
+                .collect();
+            match fs::write(&failed_list_path, content) {
+                Ok(()) => print_if_not_quiet(
+                    config.quiet,
+                    &format!(
+                        "{} failed file(s) listed in {}, process them again with --retry-from {}",
+                        failed_files.len(),
+                        failed_list_path,
+                        failed_list_path
+                    ),
+                ),
+                Err(e) => eprintln!("Failed to write {:?}: {}", failed_list_path, e),
+            }
+        }
+    }
+
+    // Record the start of this run for --newer-than last-run, a failed file has to be picked up again next time
+    if !config.no_write && !config.count_only {
+        if global_files_failed.load(Ordering::Relaxed) > 0 {
+            print_if_not_quiet(
+                config.quiet,
+                &format!("Not updating {} since some files failed", last_run_path),
+            );
+        } else if files_limited {
+            print_if_not_quiet(
+                config.quiet,
+                &format!(
+                    "Not updating {} since --limit-files left out files",
+                    last_run_path
+                ),
+            );
+        } else {
+            let started_millis = run_started
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            if let Err(e) = fs::write(&last_run_path, started_millis.to_string()) {
+                eprintln!("Failed to write {:?}: {}", last_run_path, e);
+            }
+        }
+    }
+    if config.count_only {
+        println!(
+            "Total: {} lines ({})",
+            HumanCount(global_decompressed_lines.load(Ordering::Relaxed) as u64),
+            HumanBytes(global_decompressed_size.load(Ordering::Relaxed) as u64)
+        );
+    }
+    if config.deep_verify {
+        let corrupt = global_files_failed.load(Ordering::Relaxed);
+        let intact = global_files_processed.load(Ordering::Relaxed);
+        if corrupt > 0 {
+            println!(
+                "{}",
+                format!("Deep verify: {} intact, {} corrupt", intact, corrupt).red()
+            );
+            // Lets a script stop before the real run
+            process::exit(1);
+        }
+        println!(
+            "{}",
+            format!("Deep verify: all {} files intact", intact).green()
+        );
+    }
+    if output_limit.aborted.load(Ordering::Relaxed) {
+        eprintln!(
+            "{}",
+            "Run aborted by --on-disk-full abort since the output disk is full".red()
+        );
+        process::exit(1);
+    }
+    if broken_shared_outputs > 0 {
+        eprintln!(
+            "{}",
+            format!(
+                "{} shared output file(s) failed --verify-output",
+                broken_shared_outputs
+            )
+            .red()
+        );
+        process::exit(1);
+    }
     /*
     let final_size = global_decompressed_size.load(Ordering::SeqCst);
     println!(
         "Total time elapsed: {:.2?}s",
-        start_time.elapsed().as_secs()
+        start_time.elapsed().as_secs_f64()
     );
     println!("Total size processed: {}", HumanBytes(final_size as u64));
     println!(
         "Average processing speed: {}/s",
-        HumanBytes(final_size as u64 / start_time.elapsed().as_secs())
+        HumanBytes(per_second(final_size as f64, start_time.elapsed().as_secs_f64()) as u64)
     );*/
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn read_lines(
     input_file_path: &Path,
+    shared_output: Option<SharedOutput>,
     config: &Config,
+    filter: &LineFilter,
+    output_limit: &Arc<OutputLimit>,
     pb: &ProgressBar,
+    file_pb: Option<&ProgressBar>,
     global_decompressed_size: &Arc<AtomicUsize>,
     global_decompressed_lines: &Arc<AtomicUsize>,
     global_filtered_lines: &Arc<AtomicUsize>,
     global_processed_size: &Arc<AtomicU64>,
+    global_in_progress_size: &Arc<InProgressSize>,
     global_to_be_processed_size: &Arc<AtomicU64>,
-) -> std::io::Result<()> {
+    read_rate: &RateLimit,
+) -> std::io::Result<FileStatus> {
     // Operates on a single zstd file decompressing it line by line
     let filesize;
-    
+
     // Skip if input file is empty
     if let Ok(metadata) = fs::metadata(input_file_path) {
         if metadata.len() == 0 {
@@ -207,7 +1064,7 @@ fn read_lines(
                     ),
                 );
             });
-            return Ok(());
+            return Ok(FileStatus::Skipped);
         } else {
             filesize = metadata.len();
         }
@@ -218,15 +1075,153 @@ fn read_lines(
                 &format!("Failed to get metadata for: {:?}", input_file_path),
             );
         });
-        return Ok(());
+        return Ok(FileStatus::Skipped);
+    }
+
+    // Inputs that are not started count as failed, so they end up in failed.list
+    if output_limit.aborted.load(Ordering::Relaxed) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::StorageFull,
+            "not started since the output disk is full",
+        ));
     }
 
-    let output_file_path =
-        generate_output_filename(&input_file_path.to_string_lossy().to_string(), &config);
+    // Do not start new files once the output limit has been reached
+    if output_limit.is_reached() {
+        global_to_be_processed_size.fetch_sub(filesize, Ordering::Relaxed);
+        return Ok(FileStatus::Skipped);
+    }
 
-    // Skip already existing existing files
-    if Path::new(&output_file_path).exists() {
-        global_to_be_processed_size.fetch_sub(filesize, Ordering::Relaxed); // remove the file size from the total to be read count
+    // Skip files whose frame header records a decompressed size outside of the requested range
+    if config.min_decompressed_size > 0 || config.max_decompressed_size > 0 {
+        if let Some(content_size) = frame_content_size(input_file_path) {
+            if content_size < config.min_decompressed_size
+                || (config.max_decompressed_size > 0 && content_size > config.max_decompressed_size)
+            {
+                global_to_be_processed_size.fetch_sub(filesize, Ordering::Relaxed);
+                pb.suspend(|| {
+                    print_if_not_quiet(
+                        config.quiet,
+                        &format!(
+                            "Skipping {:?} with a decompressed size of {}",
+                            input_file_path.file_name().unwrap_or_default(),
+                            HumanBytes(content_size)
+                        ),
+                    );
+                });
+                return Ok(FileStatus::Skipped);
+            }
+        }
+    }
+
+    // Only decompress and count the content, no output file is involved
+    if config.count_only {
+        if let Err(err) = verify_zstd(input_file_path, !config.no_magic_check) {
+            global_to_be_processed_size.fetch_sub(filesize, Ordering::Relaxed);
+            pb.suspend(|| print_if_not_quiet(config.quiet, &err));
+            return Ok(FileStatus::Skipped);
+        }
+        let (file_decompressed_size, file_lines) = match count_lines(
+            input_file_path,
+            config,
+            global_in_progress_size,
+            read_rate,
+            global_decompressed_size,
+            global_decompressed_lines,
+        ) {
+            Ok(counts) => counts,
+            Err(err) => {
+                let report = format!(
+                    "Error when decompressing {:?}: {}",
+                    input_file_path.file_name().unwrap_or_default(),
+                    err
+                );
+                pb.suspend(|| eprintln!("{}", report.red()));
+                return Err(err);
+            }
+        };
+        pb.suspend(|| {
+            print_if_not_quiet(
+                config.quiet,
+                &format!(
+                    "{:?}: {} lines ({})",
+                    input_file_path.file_name().unwrap_or_default(),
+                    HumanCount(file_lines as u64),
+                    HumanBytes(file_decompressed_size as u64)
+                ),
+            );
+        });
+        global_processed_size.fetch_add(filesize, Ordering::Relaxed);
+        return Ok(FileStatus::Processed);
+    }
+
+    // Compress the outputs with the window of the input
+    let input_window_log = if config.match_input_params {
+        frame_window_log(input_file_path).unwrap_or_else(|| {
+            pb.suspend(|| {
+                eprintln!(
+                    "{}",
+                    format!(
+                        "The window of {:?} could not be read from its frame header, using the default",
+                        input_file_path.file_name().unwrap_or_default()
+                    )
+                    .yellow()
+                )
+            });
+            0
+        })
+    } else {
+        0
+    };
+
+    // Tar archives are filtered member by member, each with their own output file
+    if is_tar_zstd(input_file_path, config) {
+        filter_tar(
+            input_file_path,
+            shared_output,
+            input_window_log,
+            config,
+            filter,
+            output_limit,
+            pb,
+            file_pb,
+            global_in_progress_size,
+            read_rate,
+            global_decompressed_size,
+            global_decompressed_lines,
+            global_filtered_lines,
+        )?;
+        global_processed_size.fetch_add(filesize, Ordering::Relaxed);
+        return Ok(FileStatus::Processed);
+    }
+
+    let output_file_path = generate_output_filename(&input_file_path.to_string_lossy(), config);
+
+    // Skip already existing existing files
+    if shared_output.is_none() && Path::new(&output_file_path).exists() {
+        // The lines still count for the numbering of the following inputs, so they are decoded but not filtered
+        if config.global_line_numbers {
+            let (_, file_lines) = count_lines(
+                input_file_path,
+                config,
+                global_in_progress_size,
+                read_rate,
+                &Arc::new(AtomicUsize::new(0)),
+                &Arc::new(AtomicUsize::new(0)),
+            )
+            .inspect_err(|err| {
+                let report = format!(
+                    "Error when counting the lines of {:?}: {}",
+                    input_file_path.file_name().unwrap_or_default(),
+                    err
+                );
+                pb.suspend(|| eprintln!("{}", report.red()));
+            })?;
+            filter
+                .skipped_lines
+                .fetch_add(file_lines, Ordering::Relaxed);
+        }
+        global_to_be_processed_size.fetch_sub(filesize, Ordering::Relaxed); // remove the file size from the total to be read count
         pb.suspend(|| {
             print_if_not_quiet(
                 config.quiet,
@@ -236,32 +1231,258 @@ fn read_lines(
                 ),
             );
         });
-        return Ok(());
+        return Ok(FileStatus::Skipped);
     }
 
     // Verify if the file is a valid zstd
-    if let Err(err) = verify_zstd(input_file_path) {
-        pb.suspend(|| print_if_not_quiet(config.quiet, &format!("{}", err)));
-        return Ok(());
+    if let Err(err) = verify_zstd(input_file_path, !config.no_magic_check) {
+        global_to_be_processed_size.fetch_sub(filesize, Ordering::Relaxed);
+        pb.suspend(|| print_if_not_quiet(config.quiet, &err));
+        return Ok(FileStatus::Skipped);
     }
 
-    // In in-memory buffer for storing matching lines
-    let mut buffer: Vec<u8> = Vec::with_capacity(config.buffer);
+    // The decoder passes over skippable frames, their content is only kept with --extract-metadata
+    if config.extract_metadata {
+        extract_metadata(input_file_path, config, pb);
+    }
+
+    // Every line would be kept unchanged, so the compressed frames are copied instead of decoded and encoded again
+    if config.no_recompress {
+        copy_compressed(
+            input_file_path,
+            &output_file_path,
+            filesize,
+            output_limit,
+            global_in_progress_size,
+            read_rate,
+        )
+        .inspect_err(|err| {
+            let _ = fs::remove_file(&output_file_path);
+            if err.kind() != std::io::ErrorKind::StorageFull {
+                let report = format!("Failed to copy {:?}: {}", input_file_path, err);
+                pb.suspend(|| eprintln!("{}", report.red()));
+            }
+            discard_on_disk_full(
+                err,
+                &output_file_path,
+                true,
+                filter,
+                config,
+                output_limit,
+                pb,
+            )
+        })?;
+        filter.copied_inputs.fetch_add(1, Ordering::Relaxed);
+        if !config.on_complete.is_empty() {
+            run_on_complete(input_file_path, &output_file_path, config, pb)?;
+        }
+        global_processed_size.fetch_add(filesize, Ordering::Relaxed);
+        return Ok(FileStatus::Processed);
+    }
+
+    // Using https://stackoverflow.com/questions/77304382/how-to-decode-and-read-a-zstd-file-in-rust
+    let file = ProgressReader::open(input_file_path, global_in_progress_size, read_rate)?;
+    let skipped_frames = SkippedFrames::default();
+    let decoder: Box<dyn Read> = if config.skip_corrupt_frames {
+        Box::new(FrameRecoveryReader::new(
+            file,
+            &skipped_frames,
+            config.window_log_max,
+        )?)
+    } else {
+        Box::new(open_decoder(file, config)?)
+    };
+    let reader = line_reader(decoder, config);
+
+    let output_written = filter_lines(
+        reader,
+        &input_file_path.to_string_lossy(),
+        &output_file_path,
+        shared_output,
+        input_window_log,
+        config,
+        filter,
+        output_limit,
+        pb,
+        file_pb,
+        global_decompressed_size,
+        global_decompressed_lines,
+        global_filtered_lines,
+    )
+    .inspect_err(|err| {
+        discard_on_disk_full(
+            err,
+            &output_file_path,
+            shared_output.is_none(),
+            filter,
+            config,
+            output_limit,
+            pb,
+        )
+    })?;
+    let frames = skipped_frames.frames.load(Ordering::Relaxed);
+    if frames > 0 {
+        pb.suspend(|| {
+            eprintln!(
+                "{}",
+                format!(
+                    "Skipped {} corrupt frame(s) and {} of compressed data in {:?}",
+                    frames,
+                    HumanBytes(skipped_frames.bytes.load(Ordering::Relaxed)),
+                    input_file_path.file_name().unwrap_or_default()
+                )
+                .yellow()
+            )
+        });
+    }
+    if output_written && !config.on_complete.is_empty() {
+        run_on_complete(input_file_path, &output_file_path, config, pb)?;
+    }
+
+    // Only count the file as processed once it has been completed
+    global_processed_size.fetch_add(filesize, Ordering::Relaxed);
+
+    Ok(FileStatus::Processed)
+}
+
+// Copies an input to its output byte for byte for --no-recompress
+// The write goes through CountingFile like any other output, so --max-write-rate and --on-disk-full still apply
+fn copy_compressed(
+    input_file_path: &Path,
+    output_file_path: &str,
+    filesize: u64,
+    output_limit: &Arc<OutputLimit>,
+    global_in_progress_size: &Arc<InProgressSize>,
+    read_rate: &RateLimit,
+) -> std::io::Result<()> {
+    let mut reader = ProgressReader::open(input_file_path, global_in_progress_size, read_rate)?;
+    let mut writer = BufWriter::new(CountingFile {
+        file: Box::new(File::create(output_file_path)?),
+        output_limit: Arc::clone(output_limit),
+    });
+    let copied = std::io::copy(&mut reader, &mut writer)?;
+    writer.flush()?;
+    // The input may have changed while it was read
+    if copied != filesize {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "copied {} of {:?} instead of its {}",
+                HumanBytes(copied),
+                input_file_path.file_name().unwrap_or_default(),
+                HumanBytes(filesize)
+            ),
+        ));
+    }
+    // A truncated input fails when it is decoded, so a copy of it must not count as processed
+    frames::check_complete(Path::new(output_file_path)).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("the copy is not a complete zstd file: {}", e),
+        )
+    })
+}
+
+// Filters a zstd stream from stdin to stdout with --input -, e.g. `cat a.jsonl.zst b.jsonl.zst | zstd-jsonl-filter --input -`
+// Concatenated archives are just a stream of several frames, they are read as one continuous input,
+// so there are no per-file outputs, skipping of existing outputs or summary files
+// Everything except the kept lines goes to stderr
+fn filter_stdin(config: &Config) -> Result<(), Box<dyn Error>> {
+    let filter = match LineFilter::new(config) {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let output_limit = Arc::new(OutputLimit::new(config));
+    let stdout = Mutex::new(MergedOutput::new(std::io::stdout(), config, &output_limit)?);
+    let decompressed_size = Arc::new(AtomicUsize::new(0));
+    let decompressed_lines = Arc::new(AtomicUsize::new(0));
+    let filtered_lines = Arc::new(AtomicUsize::new(0));
+    let reader = line_reader(open_decoder(std::io::stdin().lock(), config)?, config);
+    let result = filter_lines(
+        reader,
+        "stdin",
+        "",
+        Some(SharedOutput::Merged(&stdout)),
+        0,
+        config,
+        &filter,
+        &output_limit,
+        &ProgressBar::hidden(),
+        None,
+        &decompressed_size,
+        &decompressed_lines,
+        &filtered_lines,
+    );
+    let mut stdout = stdout.into_inner().unwrap();
+    if config.json_array {
+        let end = if stdout.lines_written() == 0 {
+            &b"]"[..]
+        } else {
+            JSON_ARRAY_END
+        };
+        stdout.writer.write_all(end)?;
+    }
+    stdout.writer.finish()?;
+    if result.is_err() {
+        process::exit(1);
+    }
+    if !config.quiet {
+        eprintln!(
+            "{} of {} lines kept ({} decompressed)",
+            HumanCount(filtered_lines.load(Ordering::Relaxed) as u64),
+            HumanCount(decompressed_lines.load(Ordering::Relaxed) as u64),
+            HumanBytes(decompressed_size.load(Ordering::Relaxed) as u64)
+        );
+        for &predicate in &filter.predicates {
+            eprintln!(
+                "Lines dropped by {}: {}",
+                predicate.name(),
+                HumanCount(filter.dropped_by(predicate) as u64)
+            );
+        }
+    }
+    Ok(())
+}
 
-    // Track the last matching line to avoid trailing newline
-    let mut last_matching_line: Option<String> = None;
+// Outcome of a single input file, failed files are returned as errors
+enum FileStatus {
+    Processed,
+    Skipped,
+}
 
-    let pattern = Regex::new(&config.pattern.as_str()).unwrap(); //unwrap because already verified //TODO: move
+// Filters a stream of decompressed lines into a single output file
+// Returns whether a separate output file was written and kept
+#[allow(clippy::too_many_arguments)]
+fn filter_lines(
+    reader: impl BufRead,
+    source_name: &str,
+    output_file_path: &str,
+    shared_output: Option<SharedOutput>,
+    input_window_log: u32,
+    config: &Config,
+    filter: &LineFilter,
+    output_limit: &Arc<OutputLimit>,
+    pb: &ProgressBar,
+    file_pb: Option<&ProgressBar>,
+    global_decompressed_size: &Arc<AtomicUsize>,
+    global_decompressed_lines: &Arc<AtomicUsize>,
+    global_filtered_lines: &Arc<AtomicUsize>,
+) -> std::io::Result<bool> {
+    // In in-memory buffer for storing matching lines
+    let mut buffer = LineBuffer::new(config.buffer);
 
-    let output_file = if !config.no_write {
-        let out = File::create(&output_file_path);
+    let output_file = if !config.no_write && shared_output.is_none() {
+        let out = File::create(output_file_path);
         if out.is_err() {
             pb.suspend(|| {
                 print_if_not_quiet(
                     config.quiet,
                     &format!(
                         "Unable to create output file {:?}",
-                        Path::new(&output_file_path).file_name().unwrap_or_default()
+                        Path::new(output_file_path).file_name().unwrap_or_default()
                     ),
                 );
             });
@@ -271,558 +1492,6703 @@ fn read_lines(
         None
     };
 
-    let mut writer= match output_file {
-        Some(file) => {
-            let buf_writer = BufWriter::new(file);
-            Some(buf_writer)
-        },
-        None  => None,
+    // The bucket of a line is taken from the line as it was read, before --extract or the prefixes change it
+    let time_buckets = match shared_output {
+        Some(SharedOutput::Partitioned(partitions)) => {
+            Some(partitions.lock().unwrap().buckets.clone())
+        }
+        _ => None,
     };
 
-    // Function to handle output either (compressed or uncompressed)
-    let mut write_to_output = |data: &[u8]| -> std::io::Result<()> {
-        if config.zstd {
-            // Use a ZSTD encoder to write compressed data
-            match writer {
-                Some(ref mut writer) => {
-                    let mut encoder = Encoder::new(writer.by_ref(), config.compression_level)?;
-                    encoder.write_all(data)?;
-                    encoder.finish()?;
-                }
-                None => {}
-            }
-        } else {
-            // Write uncompressed data directly
-            match writer {
-                Some(ref mut writer) => {
-                    writer.write_all(data)?;
-                }
-                None => {}
-            }
+    // A single writer per output file, wrapped in one zstd encoder if compressed output is requested
+    let mut writer = match (shared_output, output_file) {
+        (Some(SharedOutput::Merged(merged_output)), _) => Sink::Merged(merged_output),
+        (Some(SharedOutput::Partitioned(partitions)), _) => Sink::Partitioned(partitions),
+        (None, Some(file)) => {
+            let mut writer = OutputWriter::new(file, config, output_limit)?;
+            writer.set_window_log(input_window_log)?;
+            Sink::File(Some(writer))
         }
-        Ok(())
+        (None, None) => Sink::File(None),
     };
 
-    // Using https://stackoverflow.com/questions/77304382/how-to-decode-and-read-a-zstd-file-in-rust
-    fn start_reading(
-        reader: BufReader<Decoder<'static, BufReader<File>>>,
-    ) -> Result<Lines<BufReader<Decoder<'static, BufReader<File>>>>, IoError> {
-        Ok(reader.lines())
+    // --json-array opens the array up front, an output without any lines is deleted anyway
+    if config.json_array && shared_output.is_none() {
+        buffer.bytes.extend_from_slice(JSON_ARRAY_START);
     }
-    let file = File::open(input_file_path)?;
 
-    let decoder = Decoder::new(file)?;
-    let reader = BufReader::new(decoder);
+    // Matching lines of a --route category go to its own file instead
+    let mut routes = RouteOutputs::new(
+        output_file_path,
+        input_window_log,
+        filter,
+        config,
+        output_limit,
+    );
+
+    // Number of matching lines since the writer was last flushed
+    let mut lines_since_flush = 0;
+    // When the oldest line that has not been flushed yet entered the buffer, for --flush-ms
+    let mut unflushed_since: Option<Instant> = None;
+    let flush_after = Duration::from_millis(config.flush_ms);
 
     // Measure the size of decompressed data
     let mut decompressed_size = 0;
     let mut line_counter = 0;
     let mut line_filtered_counter = 0;
     let mut flag_data_written = false;
+    let mut lines_written = 0;
+    // Kept lines of the whole input, unlike line_filtered_counter this is never reset
+    let mut kept_lines: u64 = 0;
 
-    if let Ok(lines) = start_reading(reader) {
-        for line in lines {
-            if let Ok(line) = line {
+    // Position and last bytes of the last line that was decoded, to pinpoint decoding errors
+    // With --global-line-numbers the numbers continue where the previous input ended, the inputs are read in order
+    let first_line_number = if config.global_line_numbers {
+        (global_decompressed_lines.load(Ordering::Relaxed)
+            + filter.skipped_lines.load(Ordering::Relaxed)) as u64
+    } else {
+        0
+    };
+    let mut line_number: u64 = first_line_number;
+    let mut last_bytes: Vec<u8> = Vec::with_capacity(DECODE_ERROR_CONTEXT);
+
+    // Decompressed bytes not yet shown on the --file-progress bar
+    let mut file_pb_pending = 0;
+
+    // Keys seen in this input for --unique-by
+    let mut unique_lines = UniqueLines::new(filter, config);
+
+    // Lines of this input per --group-by value, only added to the groups of the run once it is done
+    let mut groups = HashMap::new();
+
+    // Matching lines with their line numbers that are only written once the whole input has been read,
+    // for --tail-lines and --sort-by
+    let mut pending_lines = VecDeque::new();
+    // Bytes held in pending_lines for --record-buffer-bytes, and whether the tail was already cut short
+    let mut pending_bytes = 0;
+    let mut tail_shortened = false;
+
+    // Like lines() this also removes the \r of Windows line endings, so patterns and JSON parsing never see it
+    for line in bounded_lines(reader, config.max_line_bytes_hard) {
+        // Give up on the input once enough has been written, the incomplete output is deleted
+        // so it is created again on the next run instead of being skipped as existing
+        if output_limit.is_reached() && !config.no_write {
+            let report = format!(
+                "Output limit of {} reached, {} was only partially filtered",
+                HumanBytes(config.max_output_bytes),
+                source_name
+            );
+            pb.suspend(|| eprintln!("{}", report.yellow()));
+            return Err(abort_input(
+                std::io::Error::other(report),
+                writer,
+                routes,
+                output_file_path,
+                shared_output.is_none(),
+                [decompressed_size, line_counter, line_filtered_counter],
+                [
+                    global_decompressed_size,
+                    global_decompressed_lines,
+                    global_filtered_lines,
+                ],
+            ));
+        }
+        let line = match line {
+            Ok(Some(line)) => Ok(line),
+            // The line was longer than --max-line-bytes-hard and has already been skipped over
+            Ok(None) => {
                 line_counter += 1;
-                // Test regex pattern
-                // This is the place to add new line-by-line logic
-                if pattern.is_match(&line) {
-                    // Pattern matches
+                line_number += 1;
+                if config.max_line_bytes_policy == "skip" {
+                    filter.oversized_lines.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                let report = format!(
+                    "{} line {} is longer than --max-line-bytes-hard {}",
+                    source_name,
+                    line_number,
+                    HumanBytes(config.max_line_bytes_hard as u64)
+                );
+                pb.suspend(|| eprintln!("{}", report.red()));
+                return Err(abort_input(
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, report),
+                    writer,
+                    routes,
+                    output_file_path,
+                    shared_output.is_none() && !config.no_write,
+                    [decompressed_size, line_counter, line_filtered_counter],
+                    [
+                        global_decompressed_size,
+                        global_decompressed_lines,
+                        global_filtered_lines,
+                    ],
+                ));
+            }
+            Err(err) => Err(err),
+        };
+        if let Ok(line) = line {
+            line_counter += 1;
+            line_number += 1;
+            // Every line has to be valid JSON with --strict-jsonl, whether the filters would keep it or not
+            if config.strict_jsonl
+                && config.strict_jsonl_policy == "abort"
+                && serde_json::from_str::<serde::de::IgnoredAny>(&line).is_err()
+            {
+                let report = format!(
+                    "{} line {} is not valid JSON: {}",
+                    source_name,
+                    line_number,
+                    line_prefix(&line, EXPLAIN_LINE_PREFIX)
+                );
+                pb.suspend(|| eprintln!("{}", report.red()));
+                return Err(abort_input(
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, report),
+                    writer,
+                    routes,
+                    output_file_path,
+                    shared_output.is_none() && !config.no_write,
+                    [decompressed_size, line_counter, line_filtered_counter],
+                    [
+                        global_decompressed_size,
+                        global_decompressed_lines,
+                        global_filtered_lines,
+                    ],
+                ));
+            }
+            if config.explain {
+                if let Some(report) = filter.explain(&line, config, source_name, line_number) {
+                    pb.suspend(|| eprintln!("{}", report));
+                }
+            }
+            // Test regex pattern
+            // This is the place to add new line-by-line logic
+            let is_match = config.decompress_only || filter.is_match(&line, config);
+            // Canonical lines go into --unique-by as well, so records that only differ in key order are duplicates
+            let line = if is_match && config.canonicalize_json {
+                canonicalize_line(line)
+            } else {
+                line
+            };
+            if is_match && matches!(unique_lines.check(&line, line_number), Uniqueness::Keep) {
+                // Pattern matches
+                if config.tail_lines > 0 || !config.sort_by.is_empty() {
+                    pending_bytes += line.len();
+                    pending_lines.push_back((line_number, line.clone()));
+                    if config.tail_lines > 0 && pending_lines.len() > config.tail_lines {
+                        pending_bytes -=
+                            pending_lines.pop_front().map_or(0, |(_, line)| line.len());
+                    }
+                    let over_record_buffer = config.record_buffer_bytes != 0
+                        && pending_bytes > config.record_buffer_bytes;
+                    // The tail just gets shorter, dropping lines from a sort would change the result
+                    if over_record_buffer && config.sort_by.is_empty() {
+                        while pending_bytes > config.record_buffer_bytes {
+                            pending_bytes -=
+                                pending_lines.pop_front().map_or(0, |(_, line)| line.len());
+                        }
+                        if !tail_shortened {
+                            tail_shortened = true;
+                            let report = format!(
+                                "{} keeps less than --tail-lines {} lines, the last ones are more than --record-buffer-bytes {}",
+                                source_name,
+                                config.tail_lines,
+                                HumanBytes(config.record_buffer_bytes as u64)
+                            );
+                            pb.suspend(|| eprintln!("{}", report.yellow()));
+                        }
+                    }
+                    let too_many_to_sort = !config.sort_by.is_empty()
+                        && config.sort_max_lines != 0
+                        && pending_lines.len() > config.sort_max_lines;
+                    if too_many_to_sort || (over_record_buffer && !config.sort_by.is_empty()) {
+                        let report = if too_many_to_sort {
+                            format!(
+                                "{} has more than {} matching lines to sort, raise --sort-max-lines to sort it",
+                                source_name, config.sort_max_lines
+                            )
+                        } else {
+                            format!(
+                                "{} has more than {} of matching lines to sort, raise --record-buffer-bytes to sort it",
+                                source_name,
+                                HumanBytes(config.record_buffer_bytes as u64)
+                            )
+                        };
+                        pb.suspend(|| eprintln!("{}", report.red()));
+                        return Err(abort_input(
+                            std::io::Error::other(report),
+                            writer,
+                            routes,
+                            output_file_path,
+                            shared_output.is_none() && !config.no_write,
+                            [decompressed_size, line_counter, line_filtered_counter],
+                            [
+                                global_decompressed_size,
+                                global_decompressed_lines,
+                                global_filtered_lines,
+                            ],
+                        ));
+                    }
+                } else {
                     line_filtered_counter += 1;
+                    kept_lines += 1;
+
+                    if !config.distinct.is_empty() {
+                        filter.collect_distinct(&line, config);
+                    }
+                    if !config.group_by.is_empty() {
+                        filter.collect_group(&mut groups, &line, config);
+                    }
 
                     if !config.no_write {
                         // Skip if no output should be written
-                        flag_data_written = true;
+                        let output_line = if config.extract {
+                            filter.extract(&line, config)
+                        } else {
+                            transform_line(&line, source_name, config)
+                        };
+                        let output_line =
+                            prefix_line(output_line, source_name, line_number, config);
 
-                        // Write matches to buffer to decrease the number individual disk writes
-                        if let Some(last_line) = last_matching_line.take() {
-                            let line_bytes = format!("{}\n", last_line).into_bytes(); // Convert the line to bytes
-                            buffer.extend_from_slice(&line_bytes); // Append to the buffer
-                        }
+                        let route = filter.route(&line, config);
+                        if !route.is_empty() {
+                            routes.write(&route, output_line)?;
+                            if config.flush_ms > 0 && unflushed_since.is_none() {
+                                unflushed_since = Some(Instant::now());
+                            }
+                        } else {
+                            flag_data_written = true;
 
-                        // Store the current matching line as the last line
-                        last_matching_line = Some(line.to_string());
+                            // Write matches to buffer to decrease the number individual disk writes
+                            lines_written += count_records(output_line.as_bytes(), config) + 1;
+                            match &time_buckets {
+                                Some(time_buckets) => buffer.push_to_bucket(
+                                    &output_line,
+                                    time_buckets.bucket(&line),
+                                    config,
+                                ),
+                                None => buffer.push(&output_line, config),
+                            }
+                            if config.flush_ms > 0 && unflushed_since.is_none() {
+                                unflushed_since = Some(Instant::now());
+                            }
+
+                            // If the buffer size exceeds the limit, flush it to the output file
+                            if buffer.bytes.len() >= config.buffer {
+                                buffer.flush(&mut writer, output_limit)?;
+                            }
+                        }
 
-                        // If the buffer size exceeds the limit, flush it to the output file
-                        if buffer.len() >= config.buffer {
-                            flush_buffer(&mut buffer, &mut write_to_output).unwrap();
+                        // Force the data out to disk so partial results can be observed
+                        lines_since_flush += 1;
+                        if config.flush_interval > 0 && lines_since_flush >= config.flush_interval {
+                            buffer.flush(&mut writer, output_limit)?;
+                            writer.flush()?;
+                            routes.flush()?;
+                            lines_since_flush = 0;
+                            unflushed_since = None;
                         }
                     }
                 }
+            }
+
+            // Checked for every line and not just matching ones, so rare matches do not wait for the next one
+            if unflushed_since.is_some_and(|since| since.elapsed() >= flush_after) {
+                buffer.flush(&mut writer, output_limit)?;
+                writer.flush()?;
+                routes.flush()?;
+                unflushed_since = None;
+            }
+
+            last_bytes.clear();
+            last_bytes.extend_from_slice(line_suffix(&line, DECODE_ERROR_CONTEXT).as_bytes());
 
-                decompressed_size += line.len();
-                if decompressed_size > 500000000 {
-                    // Update in 500 MB intervals
-                    // Relaxed Ordering because we only care about eventual consistency
-                    global_decompressed_size.fetch_add(decompressed_size, Ordering::Relaxed);
-                    decompressed_size = 0;
-                    global_decompressed_lines.fetch_add(line_counter, Ordering::Relaxed);
-                    line_counter = 0;
-                    global_filtered_lines.fetch_add(line_filtered_counter, Ordering::Relaxed);
-                    line_filtered_counter = 0;
+            if let Some(file_pb) = file_pb {
+                // Updating the bar for every line would be far too slow
+                file_pb_pending += line.len() + 1;
+                if file_pb_pending >= FILE_PB_UPDATE_BYTES {
+                    file_pb.inc(file_pb_pending as u64);
+                    file_pb.set_message(format!(
+                        "{} lines",
+                        HumanCount(line_number - first_line_number)
+                    ));
+                    file_pb_pending = 0;
                 }
+            }
+
+            decompressed_size += line.len();
+            if decompressed_size > 500000000 {
+                // Update in 500 MB intervals
+                // Relaxed Ordering because we only care about eventual consistency
+                global_decompressed_size.fetch_add(decompressed_size, Ordering::Relaxed);
+                decompressed_size = 0;
+                global_decompressed_lines.fetch_add(line_counter, Ordering::Relaxed);
+                line_counter = 0;
+                global_filtered_lines.fetch_add(line_filtered_counter, Ordering::Relaxed);
+                line_filtered_counter = 0;
+            }
+        } else if let Err(err) = line {
+            // Files compressed with --long need a larger window than zstd allows by default
+            let hint = if err.to_string().contains("too much memory") {
+                "Make sure your zstd archive includes a single jsonl file or raise --window-log-max."
             } else {
-                panic!(
-                    "Error when decompressing {} with the error: {line:?}\n\
-                Make sure your zstd archive includes a single jsonl file.",
-                    &input_file_path.to_string_lossy().to_string()
-                );
+                "Make sure your zstd archive includes a single jsonl file."
+            };
+            let report = format!(
+                "Error when decompressing {} after line {} with the error: {}\n\
+                Last bytes before the error: {}\n\
+                {}",
+                source_name,
+                line_number,
+                err,
+                hex_snippet(&last_bytes),
+                hint
+            );
+            pb.suspend(|| eprintln!("{}", report.red()));
+
+            return Err(abort_input(
+                std::io::Error::new(err.kind(), report),
+                writer,
+                routes,
+                output_file_path,
+                shared_output.is_none() && !config.no_write,
+                [decompressed_size, line_counter, line_filtered_counter],
+                [
+                    global_decompressed_size,
+                    global_decompressed_lines,
+                    global_filtered_lines,
+                ],
+            ));
+        }
+    }
+
+    if let Some(file_pb) = file_pb {
+        file_pb.inc(file_pb_pending as u64);
+    }
+
+    // With --unique-keep last, --tail-lines and --sort-by the kept lines are only known now
+    let held_lines = unique_lines.into_held_lines();
+    let mut deferred_lines: Vec<String> = if config.tail_lines > 0 || !config.sort_by.is_empty() {
+        // Held lines go back to where their last occurrence was, so the tail and ties in the sort
+        // follow the order of the input
+        let mut numbered_lines: Vec<(u64, String)> = pending_lines.into();
+        numbered_lines.extend(held_lines);
+        numbered_lines.sort_by_key(|(line_number, _)| *line_number);
+        let excess = if config.tail_lines > 0 {
+            numbered_lines.len().saturating_sub(config.tail_lines)
+        } else {
+            0
+        };
+        numbered_lines
+            .drain(excess..)
+            .map(|(_, line)| line)
+            .collect()
+    } else {
+        held_lines.into_iter().map(|(_, line)| line).collect()
+    };
+    if !config.sort_by.is_empty() {
+        sort_lines(&mut deferred_lines, config);
+    }
+    for line in deferred_lines {
+        line_filtered_counter += 1;
+        kept_lines += 1;
+        if !config.distinct.is_empty() {
+            filter.collect_distinct(&line, config);
+        }
+        if !config.group_by.is_empty() {
+            filter.collect_group(&mut groups, &line, config);
+        }
+        if !config.no_write {
+            let output_line = if config.extract {
+                filter.extract(&line, config)
+            } else {
+                transform_line(&line, source_name, config)
+            };
+            // Only --with-filename, --line-numbers can not be combined with deferred lines
+            let output_line = prefix_line(output_line, source_name, 0, config);
+            let route = filter.route(&line, config);
+            if !route.is_empty() {
+                routes.write(&route, output_line)?;
+                continue;
+            }
+            flag_data_written = true;
+            lines_written += count_records(output_line.as_bytes(), config) + 1;
+            match &time_buckets {
+                Some(time_buckets) => {
+                    buffer.push_to_bucket(&output_line, time_buckets.bucket(&line), config)
+                }
+                None => buffer.push(&output_line, config),
+            }
+            if buffer.bytes.len() >= config.buffer {
+                buffer.flush(&mut writer, output_limit)?;
             }
         }
     }
 
+    filter.merge_groups(groups);
+
     // Update the process bar by adding the remaining size
     global_decompressed_size.fetch_add(decompressed_size, Ordering::Relaxed);
     global_decompressed_lines.fetch_add(line_counter, Ordering::Relaxed);
     global_filtered_lines.fetch_add(line_filtered_counter, Ordering::Relaxed);
-    global_processed_size.fetch_add(filesize, Ordering::Relaxed);
 
-    // Flush any remaining data in the buffer to the output file
-    if !buffer.is_empty() {
-        flush_buffer(&mut buffer, &mut write_to_output)?;
+    // Close the array after the last line and flush any remaining data in the buffer to the output file
+    if config.json_array && shared_output.is_none() && flag_data_written {
+        buffer.bytes.extend_from_slice(JSON_ARRAY_END);
+    }
+    if !buffer.bytes.is_empty() {
+        buffer.flush(&mut writer, output_limit)?;
+    }
+
+    // Finish the zstd frame and flush everything to disk
+    writer.finish()?;
+    routes.finish()?;
+
+    // Flag inputs that kept suspiciously few or many lines, e.g. after a format change upstream
+    let total_lines = line_number - first_line_number;
+    let ratio = percentage(kept_lines as f64, total_lines as f64);
+    if ratio < config.warn_if_ratio_below || ratio > config.warn_if_ratio_above {
+        pb.suspend(|| {
+            eprintln!(
+                "{}",
+                format!(
+                    "{} kept {:.4}% of its lines ({}/{}), outside of the expected {}% to {}%",
+                    source_name,
+                    ratio,
+                    kept_lines,
+                    total_lines,
+                    config.warn_if_ratio_below,
+                    config.warn_if_ratio_above
+                )
+                .yellow()
+            )
+        });
+        filter.ratio_outliers.lock().unwrap().push(RatioOutlier {
+            file: source_name.to_string(),
+            total_lines,
+            kept_lines,
+            ratio,
+        });
     }
 
-    // Write the last matching line without an extra newline
-    if let Some(last_line) = last_matching_line {
-        write_to_output(last_line.as_bytes())?;
+    // Re-read the finished output to make sure it can be decoded and is complete
+    if config.verify_output && flag_data_written && shared_output.is_none() {
+        if let Err(err) = verify_output(Path::new(output_file_path), lines_written, config) {
+            // Delete the broken output so it is created again on the next run
+            let _ = fs::remove_file(output_file_path);
+            pb.suspend(|| {
+                eprintln!(
+                    "{}. The output file was deleted.",
+                    err.trim_end_matches('.')
+                )
+            });
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err));
+        }
     }
 
     // Delete the file if nothing was ever written to it
-    if !flag_data_written {
+    if !flag_data_written && !config.no_write && shared_output.is_none() {
         // Check if the file is empty
-        fs::remove_file(&output_file_path)?;
+        fs::remove_file(output_file_path)?;
         pb.suspend(|| {
             print_if_not_quiet(
                 config.quiet,
                 &format!(
                     "Empty output file deleted {:?}",
-                    Path::new(&output_file_path).file_name().unwrap_or_default()
+                    Path::new(output_file_path).file_name().unwrap_or_default()
                 ),
             );
         });
     }
 
-    Ok(())
+    Ok(flag_data_written && !config.no_write && shared_output.is_none())
 }
 
-fn flush_buffer(
-    buffer: &mut Vec<u8>,
-    write_to_output: &mut impl FnMut(&[u8]) -> std::io::Result<()>,
-) -> std::io::Result<()> {
-    write_to_output(&buffer)?; // Write the buffer content to the output
-    buffer.clear(); // Clear the buffer after writing
-    Ok(())
+// Gives up on an input after an error, counts what was read so far and deletes the incomplete outputs
+// so they are created again on the next run
+// The counts are the decompressed bytes, lines and kept lines not yet added to the global totals
+fn abort_input(
+    err: std::io::Error,
+    writer: Sink,
+    routes: RouteOutputs,
+    output_file_path: &str,
+    remove_output: bool,
+    counts: [usize; 3],
+    totals: [&AtomicUsize; 3],
+) -> std::io::Error {
+    for (count, total) in counts.into_iter().zip(totals) {
+        total.fetch_add(count, Ordering::Relaxed);
+    }
+    if remove_output {
+        drop(writer);
+        let _ = fs::remove_file(output_file_path);
+    }
+    routes.remove();
+    err
 }
 
-fn generate_output_filename(input_file_path: &str, config: &Config) -> String {
-    let path = Path::new(input_file_path);
-
-    // Strip the ".jsonl.zst" extension
-    let input_stem = path
-        .file_stem()
-        .unwrap_or_default()
-        .to_string_lossy()
-        .to_string(); // e.g. "13030000000-13040000000.jsonl"
-
-    let file_stem_without_extension = Path::new(&input_stem)
-        .file_stem()
-        .unwrap_or_default()
-        .to_string_lossy(); // Get only base file name if applicable, i.e. "13030000000-13040000000"
-
-    let original_file_extension = Path::new(&input_stem)
-        .extension()
-        .unwrap_or_default()
-        .to_string_lossy();
-
-    let output_file_extention = {
-        if config.file_extension.is_empty() {
-            format!(".{}", original_file_extension)
-        } else {
-            format!(".{}", config.file_extension)
+// Runs the --on-complete command for a finished output file with `{input}` and `{output}` replaced by their quoted paths
+// The paths are also passed as ZJF_INPUT and ZJF_OUTPUT in the environment of the command
+// A failing command is only reported, unless --on-complete-strict counts the input file as failed
+fn run_on_complete(
+    input_file_path: &Path,
+    output_file_path: &str,
+    config: &Config,
+    pb: &ProgressBar,
+) -> std::io::Result<()> {
+    let input = input_file_path.to_string_lossy();
+    let command = on_complete_command(&config.on_complete, &input, output_file_path);
+    let mut shell = if cfg!(windows) {
+        let mut shell = process::Command::new("cmd");
+        shell.args(["/C", &command]);
+        shell
+    } else {
+        let mut shell = process::Command::new("sh");
+        shell.args(["-c", &command]);
+        shell
+    };
+    let result = shell
+        .env("ZJF_INPUT", input.as_ref())
+        .env("ZJF_OUTPUT", output_file_path)
+        .output();
+    let error = match result {
+        Ok(output) if output.status.success() => {
+            pb.suspend(|| {
+                print_if_not_quiet(
+                    config.quiet,
+                    &format!(
+                        "--on-complete for {:?} finished with {}",
+                        Path::new(output_file_path).file_name().unwrap_or_default(),
+                        output.status
+                    ),
+                );
+            });
+            return Ok(());
         }
+        Ok(output) => format!(
+            "--on-complete for {:?} failed with {}: {}",
+            Path::new(output_file_path).file_name().unwrap_or_default(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => format!(
+            "--on-complete for {:?} could not be started: {}",
+            Path::new(output_file_path).file_name().unwrap_or_default(),
+            e
+        ),
     };
-    if config.zstd {
-        format!(
-            "{}{file_stem_without_extension}{}{}.zst",
-            config.output, config.suffix, output_file_extention
-        )
+    if config.on_complete_strict {
+        pb.suspend(|| eprintln!("{}", error.red()));
+        Err(std::io::Error::other(error))
     } else {
-        format!(
-            "{}{file_stem_without_extension}{}{}",
-            config.output, config.suffix, output_file_extention
-        )
+        pb.suspend(|| eprintln!("{}", error.yellow()));
+        Ok(())
     }
 }
 
-// Verify that the file is a valid zstd file
-fn verify_zstd(file_path: &Path) -> Result<(), String> {
-    let mut file = File::open(file_path).map_err(|e| format!("Failed to open file: {}", e))?;
+// Replaces `{input}` and `{output}` in an --on-complete command in a single pass, so a path that
+// contains one of the placeholders itself is not replaced again
+fn on_complete_command(template: &str, input: &str, output: &str) -> String {
+    let mut command = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        command.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("{input}") {
+            command.push_str(&shell_quote(input));
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("{output}") {
+            command.push_str(&shell_quote(output));
+            rest = after;
+        } else {
+            command.push('{');
+            rest = &rest[1..];
+        }
+    }
+    command.push_str(rest);
+    command
+}
 
-    // Read the first few bytes to detect Zstd magic number
-    let mut magic_bytes = [0u8; 4];
-    file.read_exact(&mut magic_bytes).map_err(|_| {
-        format!(
-            "Skipped not valid zstd {:?}",
-            file_path.file_name().unwrap_or_default()
-        )
-    })?;
+// Quotes a value as a single word for the shell that runs --on-complete, so spaces, `;` or `$(...)`
+// in a file name are never interpreted
+// sh takes everything between single quotes literally, an embedded ' ends the quotes and is escaped
+// cmd has no escapes, but double quotes can not be part of a Windows file name
+fn shell_quote(value: &str) -> String {
+    if cfg!(windows) {
+        format!("\"{}\"", value)
+    } else {
+        format!("'{}'", value.replace('\'', r"'\''"))
+    }
+}
 
-    // Check if the magic bytes match Zstd's magic number
-    if magic_bytes == [0x28, 0xB5, 0x2F, 0xFD] {
-        // It's a Zstd archive; attempt to decompress it
-        let _ = Decoder::new(file).map_err(|_| {
-            format!(
-                "Failed to decode zstd for {:?}",
-                file_path.file_name().unwrap_or_default()
-            )
-        })?;
+// Buffers the decompressed data that is split into lines, with --read-buffer-size instead of the 8 KiB default
+fn line_reader<R: Read>(reader: R, config: &Config) -> BufReader<R> {
+    if config.read_buffer_size != 0 {
+        BufReader::with_capacity(config.read_buffer_size, reader)
     } else {
-        return Err(format!(
-            "Skipped not valid zstd {:?}",
-            file_path.file_name().unwrap_or_default()
-        ));
+        BufReader::new(reader)
     }
-    Ok(())
 }
 
-// Function to start a separate thread for updating the progress bar.
-fn start_progress_updater(
-    start_time: Instant,
-    global_to_be_processed_size: &Arc<AtomicU64>,
-    pb: ProgressBar,
-    _config: &Config,
-    global_decompressed_size: &Arc<AtomicUsize>,
+// Splits decompressed data into lines like BufRead::lines, but never holds more than max_bytes of a line
+// A longer line is skipped up to its end and yields None, 0 means no limit
+fn bounded_lines(
+    mut reader: impl BufRead,
+    max_bytes: usize,
+) -> impl Iterator<Item = std::io::Result<Option<String>>> {
+    std::iter::from_fn(move || {
+        let mut buf = Vec::new();
+        // Two bytes more than allowed fit the \r\n of a line that is exactly at the limit
+        let read = if max_bytes == 0 {
+            reader.read_until(b'\n', &mut buf)
+        } else {
+            (&mut reader)
+                .take(max_bytes as u64 + 2)
+                .read_until(b'\n', &mut buf)
+        };
+        match read {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(err) => return Some(Err(err)),
+        }
+        let complete = buf.last() == Some(&b'\n');
+        if complete {
+            buf.pop();
+            if buf.last() == Some(&b'\r') {
+                buf.pop();
+            }
+        }
+        if max_bytes != 0 && buf.len() > max_bytes {
+            if !complete {
+                if let Err(err) = reader.skip_until(b'\n') {
+                    return Some(Err(err));
+                }
+            }
+            return Some(Ok(None));
+        }
+        Some(String::from_utf8(buf).map(Some).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "stream did not contain valid UTF-8",
+            )
+        }))
+    })
+}
+
+// Expands an input pattern into the paths it matches
+// With --max-depth every ** component is replaced by up to that many * components in total, so the walk
+// never descends into deeper directories instead of reading them and throwing the matches away
+fn glob_paths(pattern: &str, max_depth: Option<usize>) -> Result<Vec<PathBuf>, glob::PatternError> {
+    let Some(max_depth) = max_depth.filter(|_| pattern.contains("**")) else {
+        return Ok(glob::glob(pattern)?.flatten().collect());
+    };
+    // Windows paths can use either separator, glob accepts / on every platform when they are joined again
+    let components: Vec<&str> = pattern.split(std::path::is_separator).collect();
+    // Every way to split the allowed depth between the ** components
+    let mut splits: Vec<Vec<usize>> = vec![Vec::new()];
+    for _ in components.iter().filter(|component| **component == "**") {
+        splits = splits
+            .into_iter()
+            .flat_map(|split| {
+                let left = max_depth - split.iter().sum::<usize>();
+                (0..=left).map(move |depth| [split.as_slice(), &[depth]].concat())
+            })
+            .collect();
+    }
+    // Several ** can reach the same file in different ways, the set also keeps the paths sorted
+    let mut paths = BTreeSet::new();
+    for split in splits {
+        let mut depths = split.into_iter();
+        let expanded: Vec<&str> = components
+            .iter()
+            .flat_map(|component| match *component {
+                "**" => vec!["*"; depths.next().unwrap_or_default()],
+                component => vec![component],
+            })
+            .collect();
+        paths.extend(glob::glob(&expanded.join("/"))?.flatten());
+    }
+    Ok(paths.into_iter().collect())
+}
+
+// Whether an input contains glob metacharacters and should be expanded instead of read as a path
+fn is_glob(input: &str) -> bool {
+    input.contains(['*', '?', '['])
+}
+
+// Opens a zstd stream of an input file, allowing windows up to 2^--window-log-max bytes
+// The compressed input is read in chunks of --read-buffer-size, by default the size zstd recommends
+fn open_decoder<R: Read>(
+    reader: R,
+    config: &Config,
+) -> std::io::Result<Decoder<'static, BufReader<R>>> {
+    let mut decoder = if config.read_buffer_size != 0 {
+        Decoder::with_buffer(BufReader::with_capacity(config.read_buffer_size, reader))?
+    } else {
+        Decoder::new(reader)?
+    };
+    if config.window_log_max != 0 {
+        decoder.window_log_max(config.window_log_max)?;
+    }
+    Ok(decoder)
+}
+
+// Outputs are read back with the largest window they can have, not the --window-log-max of the inputs,
+// since --match-input-params gives them the window of inputs written with --long
+fn open_output_decoder<R: Read>(reader: R) -> std::io::Result<Decoder<'static, BufReader<R>>> {
+    let mut decoder = Decoder::new(reader)?;
+    decoder.window_log_max(WINDOW_LOG_MAX)?;
+    Ok(decoder)
+}
+
+// Check if the decompressed content of a zstd file starts with a tar header
+fn is_tar_zstd(file_path: &Path, config: &Config) -> bool {
+    let Ok(file) = File::open(file_path) else {
+        return false;
+    };
+    let Ok(mut decoder) = open_decoder(file, config) else {
+        return false;
+    };
+    // The first tar header is 512 bytes long and contains "ustar" at offset 257
+    let mut header = [0u8; 512];
+    if decoder.read_exact(&mut header).is_err() {
+        return false;
+    }
+    &header[257..262] == b"ustar"
+}
+
+// Filters every regular file inside a tar archive, writing one output file per member
+// Output files are named after the archive and the member, e.g. archive_member_filtered.jsonl
+#[allow(clippy::too_many_arguments)]
+fn filter_tar(
+    input_file_path: &Path,
+    shared_output: Option<SharedOutput>,
+    input_window_log: u32,
+    config: &Config,
+    filter: &LineFilter,
+    output_limit: &Arc<OutputLimit>,
+    pb: &ProgressBar,
+    file_pb: Option<&ProgressBar>,
+    global_in_progress_size: &Arc<InProgressSize>,
+    read_rate: &RateLimit,
+    global_decompressed_size: &Arc<AtomicUsize>,
     global_decompressed_lines: &Arc<AtomicUsize>,
     global_filtered_lines: &Arc<AtomicUsize>,
-    global_processed_size: &Arc<AtomicU64>,
+) -> std::io::Result<()> {
+    // Strip ".tar.zst" from the archive name
+    let archive_name = Path::new(input_file_path.file_stem().unwrap_or_default())
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+
+    let file = ProgressReader::open(input_file_path, global_in_progress_size, read_rate)?;
+    let decoder = open_decoder(file, config)?;
+    let mut archive = tar::Archive::new(decoder);
+
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let member_path = entry.path()?.to_string_lossy().to_string();
+
+        // Reuse the regular naming scheme with a name made of the archive and the member path
+        let member_name = format!(
+            "{}_{}.zst",
+            archive_name,
+            member_path.replace(['/', '\\'], "_")
+        );
+        // Placed next to the archive so --output-for patterns match members like the archive itself
+        let member_path_in_archive_dir = input_file_path.with_file_name(&member_name);
+        let output_file_path =
+            generate_output_filename(&member_path_in_archive_dir.to_string_lossy(), config);
+
+        if shared_output.is_none() && Path::new(&output_file_path).exists() {
+            pb.suspend(|| {
+                print_if_not_quiet(
+                    config.quiet,
+                    &format!(
+                        "Skipping existing output file {:?}",
+                        Path::new(&output_file_path).file_name().unwrap_or_default()
+                    ),
+                );
+            });
+            continue;
+        }
+
+        let output_written = filter_lines(
+            line_reader(entry, config),
+            &format!("{} in {}", member_path, input_file_path.to_string_lossy()),
+            &output_file_path,
+            shared_output,
+            input_window_log,
+            config,
+            filter,
+            output_limit,
+            pb,
+            file_pb,
+            global_decompressed_size,
+            global_decompressed_lines,
+            global_filtered_lines,
+        )
+        .inspect_err(|err| {
+            discard_on_disk_full(
+                err,
+                &output_file_path,
+                shared_output.is_none(),
+                filter,
+                config,
+                output_limit,
+                pb,
+            )
+        })?;
+        if output_written && !config.on_complete.is_empty() {
+            run_on_complete(input_file_path, &output_file_path, config, pb)?;
+        }
+    }
+    Ok(())
+}
+
+// A full disk leaves the output of an input incomplete, it is deleted so the next run creates it again
+// instead of skipping it as an existing output, lines already written to a shared output stay there
+// With --on-disk-full abort no further inputs are started
+fn discard_on_disk_full(
+    err: &std::io::Error,
+    output_file_path: &str,
+    own_output: bool,
+    filter: &LineFilter,
+    config: &Config,
+    output_limit: &OutputLimit,
+    pb: &ProgressBar,
 ) {
-    let mut sys = System::new_all();
-    let mut last_accurate_proc_size = 0;
-    let mut processed_size_estimate = 0;
-    loop {
-        let elapsed = start_time.elapsed().as_secs_f64();
-        let global_decompressed_size = global_decompressed_size.load(Ordering::Relaxed);
-        let global_filtered_lines = global_filtered_lines.load(Ordering::Relaxed);
-        let global_decompressed_lines = global_decompressed_lines.load(Ordering::Relaxed);
-        let global_processed_size = global_processed_size.load(Ordering::Relaxed);
-        let global_to_be_processed_size = global_to_be_processed_size.load(Ordering::Relaxed);
+    if err.kind() != std::io::ErrorKind::StorageFull {
+        return;
+    }
+    let outcome = if own_output {
+        let _ = fs::remove_file(output_file_path);
+        for path in route_output_paths(output_file_path, filter, config) {
+            let _ = fs::remove_file(path);
+        }
+        format!("the incomplete output {:?} was deleted", output_file_path)
+    } else {
+        String::from("the lines already written to the shared output stay there")
+    };
+    let report =
+        if config.on_disk_full == "abort" && !output_limit.aborted.swap(true, Ordering::Relaxed) {
+            format!(
+                "The output disk is full, {} and no further inputs are started",
+                outcome
+            )
+        } else {
+            format!("The output disk is full, {}", outcome)
+        };
+    pb.suspend(|| eprintln!("{}", report.red()));
+}
+
+// Rewrites a JSON line with the keys of every object sorted and without insignificant whitespace
+// Lines that are not valid JSON are kept as they are
+fn canonicalize_line(line: String) -> String {
+    let Ok(mut value) = serde_json::from_str::<serde_json::Value>(&line) else {
+        return line;
+    };
+    json::sort_keys(&mut value);
+    serde_json::to_string(&value).unwrap_or(line)
+}
+
+// Puts the --with-filename and --line-numbers prefixes in front of an output line
+fn prefix_line(line: String, source_name: &str, line_number: u64, config: &Config) -> String {
+    if !config.with_filename && !config.line_numbers {
+        return line;
+    }
+    let mut prefixed = String::new();
+    if config.with_filename {
+        prefixed.push_str(source_name);
+        prefixed.push_str(&config.prefix_separator);
+    }
+    if config.line_numbers {
+        prefixed.push_str(&line_number.to_string());
+        prefixed.push_str(&config.prefix_separator);
+    }
+    prefixed.push_str(&line);
+    prefixed
+}
+
+// Applies the output options to a line that is kept
+fn transform_line(line: &str, source_name: &str, config: &Config) -> String {
+    if !config.pretty && config.add_source_field.is_empty() {
+        return line.to_string();
+    }
+    // Lines that are not valid JSON are written as they are
+    let Ok(mut value) = serde_json::from_str::<serde_json::Value>(line) else {
+        return line.to_string();
+    };
+    if !config.add_source_field.is_empty() {
+        // Only objects can take another field, an existing field with the same name is replaced
+        if let serde_json::Value::Object(map) = &mut value {
+            let source = if config.source_value.is_empty() {
+                source_name
+            } else {
+                &config.source_value
+            };
+            // Append the field to the original text where possible so the rest of the line stays byte-identical
+            if !config.pretty
+                && !config.canonicalize_json
+                && !map.contains_key(&config.add_source_field)
+            {
+                let body = line.trim_end();
+                let separator = if map.is_empty() { "" } else { "," };
+                return format!(
+                    "{}{}{}:{}}}",
+                    &body[..body.len() - 1],
+                    separator,
+                    serde_json::Value::from(config.add_source_field.as_str()),
+                    serde_json::Value::from(source)
+                );
+            }
+            map.insert(
+                config.add_source_field.clone(),
+                serde_json::Value::String(source.to_string()),
+            );
+        }
+        if config.canonicalize_json {
+            json::sort_keys(&mut value);
+        }
+    }
+    let serialized = if config.pretty {
+        serde_json::to_string_pretty(&value)
+    } else {
+        serde_json::to_string(&value)
+    };
+    serialized.unwrap_or_else(|_| line.to_string())
+}
+
+// A single check of LineFilter, named after its option for --explain
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Predicate {
+    // --strict-jsonl runs before every filter, so it sees all lines
+    StrictJson,
+    Pattern,
+    Contains,
+    // Every JSON filter needs the line to parse first
+    ValidJson,
+    HasKey,
+    Type,
+    Fuzzy,
+    Time,
+    Allow,
+    FilterExpr,
+}
+
+impl Predicate {
+    const ALL: [Predicate; 10] = [
+        Predicate::StrictJson,
+        Predicate::Pattern,
+        Predicate::Contains,
+        Predicate::ValidJson,
+        Predicate::HasKey,
+        Predicate::Type,
+        Predicate::Fuzzy,
+        Predicate::Time,
+        Predicate::Allow,
+        Predicate::FilterExpr,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Predicate::StrictJson => "--strict-jsonl",
+            Predicate::Pattern => "--pattern",
+            Predicate::Contains => "--contains",
+            Predicate::ValidJson => "JSON parsing",
+            Predicate::HasKey => "--has-key",
+            Predicate::Type => "--type",
+            Predicate::Fuzzy => "--fuzzy",
+            Predicate::Time => "--since/--until",
+            Predicate::Allow => "--allow-field",
+            Predicate::FilterExpr => "--filter-expr",
+        }
+    }
+}
+
+// Decides whether a line is kept, compiled once and shared by all files
+struct LineFilter {
+    // The checks that are enabled, in the order they run
+    predicates: Vec<Predicate>,
+    // Lines printed with --explain so far
+    explained: AtomicUsize,
+    // None if the pattern matches everything anyway
+    pattern: Option<Regex>,
+    // Literal substrings from --contains and --contains-file
+    contains: Option<Substrings>,
+    // Required JSON types per field from --type
+    types: Vec<(String, json::JsonType)>,
+    // Lines dropped by each check, indexed by Predicate, a line only counts for the first check it fails
+    dropped: Vec<AtomicUsize>,
+    // Fields that must be within --max-distance edits of a value from --fuzzy
+    fuzzy: Vec<(String, String)>,
+    // Paths and separator of --concat-fields
+    concat: Option<(Vec<String>, String)>,
+    // Time window from --since and --until in milliseconds since the epoch
+    since: Option<i64>,
+    until: Option<i64>,
+    // Keys seen in all inputs with --unique-global
+    unique_keys: Mutex<HashSet<String>>,
+    // Lines dropped by --unique-by
+    duplicates: AtomicUsize,
+    // Lines skipped for being longer than --max-line-bytes-hard
+    oversized_lines: AtomicUsize,
+    // Inputs copied without decoding them by --no-recompress
+    copied_inputs: AtomicUsize,
+    // Lines of inputs skipped for an existing output, --global-line-numbers counts past them
+    skipped_lines: AtomicUsize,
+    // Lines kept without deduplication because --unique-max-keys was reached
+    unique_untracked: AtomicUsize,
+    // Values of the --distinct field as JSON text
+    distinct_values: Mutex<HashSet<String>>,
+    // Lines whose value was not collected because --distinct-max-values was reached
+    distinct_untracked: AtomicUsize,
+    // Matching lines per --group-by value, keyed by the value as JSON text
+    groups: Mutex<HashMap<String, Vec<String>>>,
+    // Number of lines held in groups, the keys do not count towards --group-max-records
+    grouped: AtomicUsize,
+    // Lines that were not grouped because --group-max-records was reached
+    ungrouped: AtomicUsize,
+    // Allowed values of --allow-field
+    allow: Option<AllowList>,
+    // Categories from --route with the name of their subdirectory
+    routes: Vec<(String, Regex)>,
+    // Inputs whose share of kept lines was outside --warn-if-ratio-below and --warn-if-ratio-above
+    ratio_outliers: Mutex<Vec<RatioOutlier>>,
+    // Per-record expression from --filter-expr
+    #[cfg(feature = "filter-expr")]
+    expr: Option<filter_expr::FilterExpr>,
+}
+
+// An input flagged by --warn-if-ratio-below or --warn-if-ratio-above, listed in summary.json
+#[derive(Serialize, Clone)]
+struct RatioOutlier {
+    file: String,
+    total_lines: u64,
+    kept_lines: u64,
+    // Share of kept lines in percent
+    ratio: f64,
+}
+
+// Allowed values for --allow-field, exact from --allow-file or approximate from a --bloom-file
+enum AllowList {
+    Exact(HashSet<String>),
+    Bloom(bloom::BloomFilter),
+}
+
+impl AllowList {
+    fn new(config: &Config) -> Result<Option<Self>, String> {
+        if !config.bloom_file.is_empty() {
+            return bloom::BloomFilter::read(Path::new(&config.bloom_file))
+                .map(|filter| Some(AllowList::Bloom(filter)));
+        }
+        if config.allow_file.is_empty() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&config.allow_file)
+            .map_err(|e| format!("Failed to read {:?}: {}", config.allow_file, e))?;
+        Ok(Some(AllowList::Exact(
+            content
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(String::from)
+                .collect(),
+        )))
+    }
+
+    fn contains(&self, value: &str) -> bool {
+        match self {
+            AllowList::Exact(values) => values.contains(value),
+            AllowList::Bloom(filter) => filter.contains(value),
+        }
+    }
+}
+
+// Matcher of the --contains substrings, a regex when --ignore-case has to fold letters beyond ASCII
+enum Substrings {
+    Literal(AhoCorasick),
+    Folded(Regex),
+}
+
+impl Substrings {
+    fn is_match(&self, text: &str) -> bool {
+        match self {
+            Substrings::Literal(matcher) => matcher.is_match(text),
+            Substrings::Folded(regex) => regex.is_match(text),
+        }
+    }
+}
+
+impl LineFilter {
+    fn new(config: &Config) -> Result<Self, String> {
+        let pattern = if config.count_only
+            || config.decompress_only
+            || (config.pattern == "^" && !config.ignore_case)
+        {
+            None
+        } else {
+            Some(
+                RegexBuilder::new(&config.pattern)
+                    .case_insensitive(config.ignore_case)
+                    .build()
+                    .map_err(|e| format!("Invalid regex: {}", e))?,
+            )
+        };
+
+        let mut substrings = config.contains.clone();
+        if !config.contains_file.is_empty() {
+            let content = fs::read_to_string(&config.contains_file)
+                .map_err(|e| format!("Failed to read {:?}: {}", config.contains_file, e))?;
+            substrings.extend(
+                content
+                    .lines()
+                    .filter(|line| !line.is_empty())
+                    .map(String::from),
+            );
+        }
+        let contains = if substrings.is_empty() {
+            None
+        } else if config.ignore_case && !substrings.iter().all(|substring| substring.is_ascii()) {
+            // Aho-Corasick only folds ASCII letters, the regex folds every Unicode letter
+            let alternation: Vec<String> = substrings.iter().map(|s| regex::escape(s)).collect();
+            Some(Substrings::Folded(
+                RegexBuilder::new(&alternation.join("|"))
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| format!("Failed to build substring matcher: {}", e))?,
+            ))
+        } else {
+            Some(Substrings::Literal(
+                AhoCorasick::builder()
+                    .ascii_case_insensitive(config.ignore_case)
+                    .build(&substrings)
+                    .map_err(|e| format!("Failed to build substring matcher: {}", e))?,
+            ))
+        };
+
+        let mut types = Vec::new();
+        for predicate in &config.types {
+            let (path, type_name) = predicate
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid --type {:?}, expected <path>=<type>", predicate))?;
+            let json_type = json::JsonType::parse(type_name).ok_or_else(|| {
+                format!(
+                    "Invalid type {:?} in --type, expected one of number, string, bool, array, object, null",
+                    type_name
+                )
+            })?;
+            types.push((path.to_string(), json_type));
+        }
+
+        let mut fuzzy = Vec::new();
+        for predicate in &config.fuzzy {
+            let (path, value) = predicate.split_once('=').ok_or_else(|| {
+                format!("Invalid --fuzzy {:?}, expected <path>=<value>", predicate)
+            })?;
+            fuzzy.push((path.to_string(), value.to_string()));
+        }
+
+        let concat = if config.concat_fields.is_empty() {
+            None
+        } else {
+            let (paths, separator) = config
+                .concat_fields
+                .split_once('=')
+                .filter(|(paths, _)| !paths.is_empty())
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid --concat-fields {:?}, expected <path>,<path>,...=<separator>",
+                        config.concat_fields
+                    )
+                })?;
+            Some((
+                paths.split(',').map(str::to_string).collect(),
+                separator.to_string(),
+            ))
+        };
+
+        let parse_bound = |bound: &str, flag: &str| -> Result<Option<i64>, String> {
+            if bound.is_empty() {
+                return Ok(None);
+            }
+            json::parse_timestamp(bound).map(Some).ok_or_else(|| {
+                format!(
+                    "Invalid {} {:?}, expected epoch seconds or milliseconds, RFC 3339 or YYYY-MM-DD",
+                    flag, bound
+                )
+            })
+        };
+        let since = parse_bound(&config.since, "--since")?;
+        let until = parse_bound(&config.until, "--until")?;
+
+        #[cfg(feature = "filter-expr")]
+        let expr = if config.filter_expr.is_empty() {
+            None
+        } else {
+            Some(filter_expr::FilterExpr::new(&config.filter_expr)?)
+        };
+        #[cfg(not(feature = "filter-expr"))]
+        if !config.filter_expr.is_empty() {
+            return Err(String::from(
+                "--filter-expr is not available, build with `--features filter-expr` to enable it",
+            ));
+        }
+
+        let allow = AllowList::new(config)?;
+
+        let mut routes: Vec<(String, Regex)> = Vec::new();
+        for route in &config.routes {
+            let (name, pattern) = route
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid --route {:?}, expected <name>=<regex>", route))?;
+            // The name becomes a directory next to the regular outputs
+            if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+                return Err(format!(
+                    "Invalid --route name {:?}, it is used as a directory name",
+                    name
+                ));
+            }
+            if routes.iter().any(|(existing, _)| existing == name) {
+                return Err(format!("Duplicate --route name {:?}", name));
+            }
+            let regex = RegexBuilder::new(pattern)
+                .case_insensitive(config.ignore_case)
+                .build()
+                .map_err(|e| format!("Invalid regex in --route {:?}: {}", name, e))?;
+            routes.push((name.to_string(), regex));
+        }
+
+        let mut predicates = Vec::new();
+        // With --strict-jsonl-policy abort an invalid line fails the input before it is filtered
+        if config.strict_jsonl && config.strict_jsonl_policy == "skip" {
+            predicates.push(Predicate::StrictJson);
+        }
+        if pattern.is_some() {
+            predicates.push(Predicate::Pattern);
+        }
+        if contains.is_some() {
+            predicates.push(Predicate::Contains);
+        }
+        let json_predicates = [
+            (!config.has_key.is_empty(), Predicate::HasKey),
+            (!types.is_empty(), Predicate::Type),
+            (!fuzzy.is_empty(), Predicate::Fuzzy),
+            (!config.time_field.is_empty(), Predicate::Time),
+            (!config.allow_field.is_empty(), Predicate::Allow),
+            (!config.filter_expr.is_empty(), Predicate::FilterExpr),
+        ];
+        // The value parsed by --strict-jsonl is reused by the JSON filters
+        if json_predicates.iter().any(|(enabled, _)| *enabled)
+            && !predicates.contains(&Predicate::StrictJson)
+        {
+            predicates.push(Predicate::ValidJson);
+        }
+        predicates.extend(
+            json_predicates
+                .iter()
+                .filter(|(enabled, _)| *enabled)
+                .map(|(_, predicate)| *predicate),
+        );
+
+        Ok(LineFilter {
+            predicates,
+            explained: AtomicUsize::new(0),
+            pattern,
+            contains,
+            types,
+            dropped: Predicate::ALL.iter().map(|_| AtomicUsize::new(0)).collect(),
+            fuzzy,
+            concat,
+            since,
+            until,
+            unique_keys: Mutex::new(HashSet::new()),
+            duplicates: AtomicUsize::new(0),
+            oversized_lines: AtomicUsize::new(0),
+            copied_inputs: AtomicUsize::new(0),
+            skipped_lines: AtomicUsize::new(0),
+            unique_untracked: AtomicUsize::new(0),
+            distinct_values: Mutex::new(HashSet::new()),
+            distinct_untracked: AtomicUsize::new(0),
+            groups: Mutex::new(HashMap::new()),
+            grouped: AtomicUsize::new(0),
+            ungrouped: AtomicUsize::new(0),
+            allow,
+            routes,
+            ratio_outliers: Mutex::new(Vec::new()),
+            #[cfg(feature = "filter-expr")]
+            expr,
+        })
+    }
+
+    fn is_match(&self, line: &str, config: &Config) -> bool {
+        match self.evaluate(line, config, None) {
+            None => true,
+            Some(predicate) => {
+                self.dropped[predicate as usize].fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    fn dropped_by(&self, predicate: Predicate) -> usize {
+        self.dropped[predicate as usize].load(Ordering::Relaxed)
+    }
+
+    // The --route categories a kept line is written to, none means the regular output
+    // With --route-mode first a line only goes to the first route that matches
+    // With --bucket-by-match-count every line goes to the bucket of the number of routes it matches
+    fn route(&self, line: &str, config: &Config) -> Vec<usize> {
+        if config.bucket_by_match_count {
+            return vec![self
+                .routes
+                .iter()
+                .filter(|(_, regex)| regex.is_match(line))
+                .count()];
+        }
+        let mut matching = self
+            .routes
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, regex))| regex.is_match(line))
+            .map(|(index, _)| index);
+        if config.route_mode == "first" {
+            matching.next().into_iter().collect()
+        } else {
+            matching.collect()
+        }
+    }
+
+    // The matches of the pattern in a line for --extract, one per record
+    // With a capture group only the first group is written instead of the whole match
+    fn extract(&self, line: &str, config: &Config) -> String {
+        let Some(pattern) = &self.pattern else {
+            return line.to_string();
+        };
+        let limit = if config.max_line_matches == 0 {
+            usize::MAX
+        } else {
+            config.max_line_matches
+        };
+        let matches: Vec<&str> = pattern
+            .captures_iter(line_prefix(line, config.scan_prefix))
+            .filter_map(|captures| captures.get(1).or_else(|| captures.get(0)))
+            .take(limit)
+            .map(|m| m.as_str())
+            .collect();
+        matches.join(&config.output_delimiter)
+    }
+
+    // Runs the enabled checks in order and returns the first one the line fails, the JSON is parsed at most once
+    // With a trace every check runs and records its result, JSON filters are left out if the line is not valid JSON
+    fn evaluate(
+        &self,
+        line: &str,
+        config: &Config,
+        mut trace: Option<&mut Vec<(Predicate, bool)>>,
+    ) -> Option<Predicate> {
+        let mut value = None;
+        let mut failed = None;
+        for &predicate in &self.predicates {
+            let passed = match predicate {
+                Predicate::Pattern => self.match_text(line, &mut value).is_some_and(|text| {
+                    self.pattern.as_ref().is_none_or(|pattern| {
+                        pattern.is_match(line_prefix(text, config.scan_prefix))
+                    })
+                }),
+                Predicate::Contains => self.match_text(line, &mut value).is_some_and(|text| {
+                    self.contains
+                        .as_ref()
+                        .is_none_or(|contains| contains.is_match(text))
+                }),
+                Predicate::StrictJson | Predicate::ValidJson => {
+                    if value.is_none() {
+                        value = self.parse(line);
+                    }
+                    value.is_some()
+                }
+                _ => match &value {
+                    Some(value) => self.matches_json_predicate(predicate, value, config),
+                    None => continue,
+                },
+            };
+            if let Some(trace) = trace.as_deref_mut() {
+                trace.push((predicate, passed));
+            }
+            if !passed && failed.is_none() {
+                failed = Some(predicate);
+                if trace.is_none() {
+                    break;
+                }
+            }
+        }
+        failed
+    }
+
+    fn parse(&self, line: &str) -> Option<serde_json::Value> {
+        serde_json::from_str::<serde_json::Value>(line)
+            .ok()
+            .map(|value| self.with_concat(value))
+    }
+
+    // The text --pattern and --contains match, with --concat-fields that is the joined value instead of the line
+    // The line is parsed for it only once, the JSON filters reuse the value
+    // Lines without the value, because they are not JSON objects, match nothing
+    fn match_text<'a>(
+        &self,
+        line: &'a str,
+        value: &'a mut Option<serde_json::Value>,
+    ) -> Option<&'a str> {
+        if self.concat.is_none() {
+            return Some(line);
+        }
+        if value.is_none() {
+            *value = self.parse(line);
+        }
+        value.as_ref()?.get(CONCAT_FIELD)?.as_str()
+    }
+
+    // Adds the --concat-fields value to a parsed JSON object, missing fields count as empty strings
+    // Only the filters see it, the line that is written stays as it is
+    fn with_concat(&self, mut value: serde_json::Value) -> serde_json::Value {
+        let Some((paths, separator)) = &self.concat else {
+            return value;
+        };
+        let joined = paths
+            .iter()
+            .map(|path| {
+                json::get_path(&value, path)
+                    .map(|field| json::value_text(field).into_owned())
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<_>>()
+            .join(separator);
+        if let serde_json::Value::Object(map) = &mut value {
+            map.insert(CONCAT_FIELD.to_string(), serde_json::Value::String(joined));
+        }
+        value
+    }
+
+    fn matches_json_predicate(
+        &self,
+        predicate: Predicate,
+        value: &serde_json::Value,
+        config: &Config,
+    ) -> bool {
+        match predicate {
+            Predicate::HasKey => json::has_key(value, &config.has_key, config.has_key_depth),
+            Predicate::Type => self.types.iter().all(|(path, json_type)| {
+                json::get_path(value, path).is_some_and(|field| json_type.matches(field))
+            }),
+            Predicate::Fuzzy => self.fuzzy.iter().all(|(path, target)| {
+                json::get_path(value, path).is_some_and(|field| {
+                    strsim::levenshtein(&json::value_text(field), target) <= config.max_distance
+                })
+            }),
+            Predicate::Time => json::get_path(value, &config.time_field)
+                .and_then(json::timestamp_millis)
+                .is_some_and(|time| {
+                    self.since.is_none_or(|since| time >= since)
+                        && self.until.is_none_or(|until| time < until)
+                }),
+            Predicate::Allow => json::get_path(value, &config.allow_field).is_some_and(|field| {
+                self.allow
+                    .as_ref()
+                    .is_none_or(|allow| allow.contains(&json::value_text(field)))
+            }),
+            #[cfg(feature = "filter-expr")]
+            Predicate::FilterExpr => self.expr.as_ref().is_none_or(|expr| expr.is_match(value)),
+            _ => true,
+        }
+    }
+
+    // Describes why a line is kept or dropped for --explain, None once --explain-lines have been printed
+    fn explain(
+        &self,
+        line: &str,
+        config: &Config,
+        source_name: &str,
+        line_number: u64,
+    ) -> Option<String> {
+        if self.explained.fetch_add(1, Ordering::Relaxed) >= config.explain_lines {
+            return None;
+        }
+        let mut trace = Vec::new();
+        let failed = self.evaluate(line, config, Some(&mut trace));
+        let checks = if trace.is_empty() {
+            String::from("no filters")
+        } else {
+            trace
+                .iter()
+                .map(|(predicate, passed)| {
+                    format!(
+                        "{} {}",
+                        predicate.name(),
+                        if *passed { "passed" } else { "failed" }
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        Some(format!(
+            "{}:{} {} ({})\n  {}",
+            source_name,
+            line_number,
+            if failed.is_none() { "kept" } else { "dropped" },
+            checks,
+            line_prefix(line, EXPLAIN_LINE_PREFIX)
+        ))
+    }
+
+    // Remembers the --distinct value of a matching line, lines without the field are ignored
+    fn collect_distinct(&self, line: &str, config: &Config) {
+        let Some(value) = serde_json::from_str::<serde_json::Value>(line)
+            .ok()
+            .and_then(|value| Some(json::get_path(&value, &config.distinct)?.to_string()))
+        else {
+            return;
+        };
+        let mut values = self.distinct_values.lock().unwrap();
+        if values.contains(&value) {
+            return;
+        }
+        let max_values = config.distinct_max_values;
+        if max_values != 0 && values.len() >= max_values {
+            self.distinct_untracked.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        values.insert(value);
+    }
+
+    // Adds a matching line to the group of its --group-by value among the groups of its input,
+    // lines without the field are ignored
+    // --group-max-records counts the lines of all inputs, so it is checked against the whole run
+    fn collect_group(
+        &self,
+        groups: &mut HashMap<String, Vec<String>>,
+        line: &str,
+        config: &Config,
+    ) {
+        let Some(key) = serde_json::from_str::<serde_json::Value>(line)
+            .ok()
+            .and_then(|value| Some(json::get_path(&value, &config.group_by)?.to_string()))
+        else {
+            return;
+        };
+        let max_records = config.group_max_records;
+        let reserved = self
+            .grouped
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |grouped| {
+                (max_records == 0 || grouped < max_records).then_some(grouped + 1)
+            });
+        if reserved.is_err() {
+            self.ungrouped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        groups.entry(key).or_default().push(line.trim().to_string());
+    }
+
+    // Adds the groups of a finished input to those of the run, the lock is only taken once per input
+    fn merge_groups(&self, groups: HashMap<String, Vec<String>>) {
+        if groups.is_empty() {
+            return;
+        }
+        let mut run_groups = self.groups.lock().unwrap();
+        for (key, records) in groups {
+            run_groups.entry(key).or_default().extend(records);
+        }
+    }
+}
+
+// Numbers are sorted by their value and before everything else, which is sorted by its JSON text
+fn sort_json_texts(texts: &mut [&str]) {
+    texts.sort_by(|a, b| match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.total_cmp(&b),
+        (Ok(_), Err(_)) => std::cmp::Ordering::Less,
+        (Err(_), Ok(_)) => std::cmp::Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    });
+}
+
+// What to do with a matching line according to --unique-by
+enum Uniqueness {
+    Keep,
+    Drop,
+    // Held back until the end of the input with --unique-keep last
+    Hold,
+}
+
+// Per input state of --unique-by, with --unique-global the keys are shared through the LineFilter instead
+struct UniqueLines<'a> {
+    filter: &'a LineFilter,
+    config: &'a Config,
+    keys: HashSet<String>,
+    // Latest line per key and its line number for --unique-keep last
+    held: HashMap<String, (u64, String)>,
+}
+
+impl<'a> UniqueLines<'a> {
+    fn new(filter: &'a LineFilter, config: &'a Config) -> Self {
+        UniqueLines {
+            filter,
+            config,
+            keys: HashSet::new(),
+            held: HashMap::new(),
+        }
+    }
+
+    fn check(&mut self, line: &str, line_number: u64) -> Uniqueness {
+        if self.config.unique_by.is_empty() {
+            return Uniqueness::Keep;
+        }
+        // Lines without the field can not be deduplicated and are always kept
+        let Some(key) = serde_json::from_str::<serde_json::Value>(line)
+            .ok()
+            .and_then(|value| Some(json::get_path(&value, &self.config.unique_by)?.to_string()))
+        else {
+            return Uniqueness::Keep;
+        };
+        let max_keys = self.config.unique_max_keys;
+
+        if self.config.unique_keep == "last" {
+            if let Some(held) = self.held.get_mut(&key) {
+                *held = (line_number, line.to_string());
+                self.filter.duplicates.fetch_add(1, Ordering::Relaxed);
+            } else if max_keys != 0 && self.held.len() >= max_keys {
+                self.filter.unique_untracked.fetch_add(1, Ordering::Relaxed);
+                return Uniqueness::Keep;
+            } else {
+                self.held.insert(key, (line_number, line.to_string()));
+            }
+            return Uniqueness::Hold;
+        }
+
+        let mut global_keys;
+        let keys = if self.config.unique_global {
+            global_keys = self.filter.unique_keys.lock().unwrap();
+            &mut *global_keys
+        } else {
+            &mut self.keys
+        };
+        if keys.contains(&key) {
+            self.filter.duplicates.fetch_add(1, Ordering::Relaxed);
+            Uniqueness::Drop
+        } else if max_keys != 0 && keys.len() >= max_keys {
+            self.filter.unique_untracked.fetch_add(1, Ordering::Relaxed);
+            Uniqueness::Keep
+        } else {
+            keys.insert(key);
+            Uniqueness::Keep
+        }
+    }
+
+    // The held back lines with their line numbers in their original order
+    fn into_held_lines(self) -> Vec<(u64, String)> {
+        let mut held: Vec<(u64, String)> = self.held.into_values().collect();
+        held.sort_unstable_by_key(|(line_number, _)| *line_number);
+        held
+    }
+}
+
+// Sorts lines by the --sort-by field, lines without it or that are not valid JSON keep their order at the end
+fn sort_lines(lines: &mut Vec<String>, config: &Config) {
+    let mut keyed: Vec<(Option<json::SortKey>, String)> = lines
+        .drain(..)
+        .map(|line| {
+            let key = serde_json::from_str::<serde_json::Value>(&line)
+                .ok()
+                .and_then(|value| {
+                    Some(json::SortKey::new(json::get_path(&value, &config.sort_by)?))
+                });
+            (key, line)
+        })
+        .collect();
+    keyed.sort_by(|(a, _), (b, _)| match (a, b) {
+        (Some(a), Some(b)) if config.sort_desc => b.compare(a),
+        (Some(a), Some(b)) => a.compare(b),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
+    lines.extend(keyed.into_iter().map(|(_, line)| line));
+}
+
+// The first max_bytes of a line without splitting a multi-byte character, 0 means the whole line
+fn line_prefix(line: &str, max_bytes: usize) -> &str {
+    if max_bytes == 0 || line.len() <= max_bytes {
+        return line;
+    }
+    let mut end = max_bytes;
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    &line[..end]
+}
+
+// The last max_bytes of a line without splitting a multi-byte character, 0 means the whole line
+fn line_suffix(line: &str, max_bytes: usize) -> &str {
+    if max_bytes == 0 || line.len() <= max_bytes {
+        return line;
+    }
+    let mut start = line.len() - max_bytes;
+    while !line.is_char_boundary(start) {
+        start += 1;
+    }
+    &line[start..]
+}
+
+// Formats bytes as hex followed by their text, e.g. `7b 22 69 64  |{"id|`
+// Control characters and invalid UTF-8 are shown as dots, multi-byte characters are kept as they are
+fn hex_snippet(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return String::from("(none)");
+    }
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let text: String = String::from_utf8_lossy(bytes)
+        .chars()
+        .map(|c| {
+            if c.is_control() || c == char::REPLACEMENT_CHARACTER {
+                '.'
+            } else {
+                c
+            }
+        })
+        .collect();
+    format!("{}  |{}|", hex.join(" "), text)
+}
+
+// Counts the compressed bytes read from a file that is still being processed for the progress display
+// They are removed again once the file is done and its full size is added to the processed size instead
+// Every input is read through it, so it also throttles reading with --max-read-rate
+struct ProgressReader<'a> {
+    inner: File,
+    file: Arc<FileInProgress>,
+    global_in_progress_size: &'a InProgressSize,
+    read_rate: &'a RateLimit,
+}
+
+impl<'a> ProgressReader<'a> {
+    fn open(
+        path: &Path,
+        global_in_progress_size: &'a InProgressSize,
+        read_rate: &'a RateLimit,
+    ) -> std::io::Result<Self> {
+        let inner = File::open(path)?;
+        let file = Arc::new(FileInProgress {
+            name: path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+            size: inner.metadata()?.len(),
+            started: Instant::now(),
+            consumed: AtomicU64::new(0),
+        });
+        global_in_progress_size
+            .files
+            .lock()
+            .unwrap()
+            .push(Arc::clone(&file));
+        Ok(ProgressReader {
+            inner,
+            file,
+            global_in_progress_size,
+            read_rate,
+        })
+    }
+}
+
+impl Read for ProgressReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read_rate.consume(read);
+        self.file.consumed.fetch_add(read as u64, Ordering::Relaxed);
+        self.global_in_progress_size
+            .total
+            .fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+impl Drop for ProgressReader<'_> {
+    fn drop(&mut self) {
+        self.global_in_progress_size.total.fetch_sub(
+            self.file.consumed.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.global_in_progress_size
+            .files
+            .lock()
+            .unwrap()
+            .retain(|file| !Arc::ptr_eq(file, &self.file));
+    }
+}
+
+// Compressed bytes read of the inputs that are still being processed, in total and per file
+#[derive(Default)]
+struct InProgressSize {
+    total: AtomicU64,
+    files: Mutex<Vec<Arc<FileInProgress>>>,
+}
+
+struct FileInProgress {
+    name: String,
+    size: u64,
+    started: Instant,
+    consumed: AtomicU64,
+}
+
+impl InProgressSize {
+    // The file that will finish last at the rate it has been read at so far, with its remaining time
+    // A single huge file can take much longer than the estimate for the whole run suggests
+    fn slowest(&self) -> Option<(String, Duration)> {
+        self.files
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|file| {
+                let consumed = file.consumed.load(Ordering::Relaxed);
+                let speed = per_second(consumed as f64, file.started.elapsed().as_secs_f64());
+                // Nothing read yet means no estimate
+                if speed < 1.0 {
+                    return None;
+                }
+                let seconds = file.size.saturating_sub(consumed) as f64 / speed;
+                Some((file.name.clone(), Duration::from_secs_f64(seconds)))
+            })
+            .max_by_key(|(_, remaining)| *remaining)
+    }
+}
+
+// Damage that --skip-corrupt-frames read past in a single file
+#[derive(Default)]
+struct SkippedFrames {
+    frames: AtomicUsize,
+    bytes: AtomicU64,
+}
+
+// Decompresses like Decoder, but on a decoding error it searches for the magic number of the next frame and
+// continues from there instead of failing, a frame cut off at the end of the file is counted as corrupt as well
+// A newline is inserted after the output of a corrupt frame so its last incomplete line is not joined with the next one
+struct FrameRecoveryReader<'a, R> {
+    inner: R,
+    decoder: zstd::stream::raw::Decoder<'static>,
+    input: Vec<u8>,
+    pos: usize,
+    eof: bool,
+    // Searching for the next frame after a decoding error
+    resyncing: bool,
+    // The current frame has not been fully decoded yet
+    in_frame: bool,
+    needs_newline: bool,
+    last_byte: u8,
+    skipped: &'a SkippedFrames,
+}
+
+impl<'a, R: Read> FrameRecoveryReader<'a, R> {
+    fn new(inner: R, skipped: &'a SkippedFrames, window_log_max: u32) -> std::io::Result<Self> {
+        let mut decoder = zstd::stream::raw::Decoder::new()?;
+        if window_log_max != 0 {
+            decoder.set_parameter(zstd::stream::raw::DParameter::WindowLogMax(window_log_max))?;
+        }
+        Ok(FrameRecoveryReader {
+            inner,
+            decoder,
+            input: Vec::with_capacity(RECOVERY_READ_SIZE),
+            pos: 0,
+            eof: false,
+            resyncing: false,
+            in_frame: false,
+            needs_newline: false,
+            last_byte: b'\n',
+            skipped,
+        })
+    }
+
+    // Drops the consumed input but keeps the rest, then appends the next chunk of the file
+    fn refill(&mut self) -> std::io::Result<()> {
+        self.input.drain(..self.pos);
+        self.pos = 0;
+        let start = self.input.len();
+        self.input.resize(start + RECOVERY_READ_SIZE, 0);
+        let read = loop {
+            match self.inner.read(&mut self.input[start..]) {
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                result => break result,
+            }
+        };
+        let read = match read {
+            Ok(read) => read,
+            Err(e) => {
+                self.input.truncate(start);
+                return Err(e);
+            }
+        };
+        self.input.truncate(start + read);
+        self.eof = read == 0;
+        Ok(())
+    }
+
+    // Moves to the next zstd magic number, everything in between is counted as skipped
+    fn resync(&mut self) -> std::io::Result<()> {
+        loop {
+            if let Some(offset) = self.input[self.pos..]
+                .windows(ZSTD_MAGIC.len())
+                .position(|window| window == ZSTD_MAGIC)
+            {
+                self.skipped
+                    .bytes
+                    .fetch_add(offset as u64, Ordering::Relaxed);
+                self.pos += offset;
+                self.resyncing = false;
+                return Ok(());
+            }
+            // Keep a possible partial magic number at the end for the next chunk
+            let keep = (self.input.len() - self.pos).min(ZSTD_MAGIC.len() - 1);
+            if self.eof {
+                self.skipped
+                    .bytes
+                    .fetch_add((self.input.len() - self.pos) as u64, Ordering::Relaxed);
+                self.pos = self.input.len();
+                return Ok(());
+            }
+            let discarded = self.input.len() - self.pos - keep;
+            self.skipped
+                .bytes
+                .fetch_add(discarded as u64, Ordering::Relaxed);
+            self.pos += discarded;
+            self.refill()?;
+        }
+    }
+}
+
+impl<R: Read> Read for FrameRecoveryReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.needs_newline {
+                self.needs_newline = false;
+                self.last_byte = b'\n';
+                buf[0] = b'\n';
+                return Ok(1);
+            }
+            if self.resyncing {
+                self.resync()?;
+            }
+            if self.pos == self.input.len() {
+                if self.eof {
+                    if self.in_frame {
+                        // The file ends in the middle of a frame
+                        self.in_frame = false;
+                        self.skipped.frames.fetch_add(1, Ordering::Relaxed);
+                        self.needs_newline = self.last_byte != b'\n';
+                        continue;
+                    }
+                    return Ok(0);
+                }
+                self.refill()?;
+                continue;
+            }
+
+            let mut input = InBuffer::around(&self.input[self.pos..]);
+            let mut output = OutBuffer::around(&mut *buf);
+            match self.decoder.run(&mut input, &mut output) {
+                Ok(hint) => {
+                    self.pos += input.pos();
+                    self.in_frame = hint != 0;
+                    let written = output.pos();
+                    if written > 0 {
+                        self.last_byte = buf[written - 1];
+                        return Ok(written);
+                    }
+                }
+                Err(_) => {
+                    self.decoder.reinit()?;
+                    self.skipped.frames.fetch_add(1, Ordering::Relaxed);
+                    self.in_frame = false;
+                    self.needs_newline = self.last_byte != b'\n';
+                    // Never find the same magic number again
+                    self.pos += 1;
+                    self.skipped.bytes.fetch_add(1, Ordering::Relaxed);
+                    self.resyncing = true;
+                }
+            }
+        }
+    }
+}
+
+// Decompress a file without looking at its content, only counting lines and bytes
+// Returns the decompressed size and number of lines of this file
+fn count_lines(
+    input_file_path: &Path,
+    config: &Config,
+    global_in_progress_size: &Arc<InProgressSize>,
+    read_rate: &RateLimit,
+    global_decompressed_size: &Arc<AtomicUsize>,
+    global_decompressed_lines: &Arc<AtomicUsize>,
+) -> std::io::Result<(usize, usize)> {
+    let file = ProgressReader::open(input_file_path, global_in_progress_size, read_rate)?;
+    let mut decoder = open_decoder(file, config)?;
+    let mut chunk = vec![0u8; 1 << 20];
+
+    let mut file_decompressed_size: usize = 0;
+    let mut file_lines = 0;
+    let mut decompressed_size = 0;
+    let mut line_counter = 0;
+    let mut last_byte = b'\n';
+
+    loop {
+        let read = match decoder.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                // What the decoder has taken from the file so far, the damage is within the last block
+                let consumed = decoder
+                    .get_ref()
+                    .get_ref()
+                    .file
+                    .consumed
+                    .load(Ordering::Relaxed);
+                let compressed_offset = consumed - decoder.get_ref().buffer().len() as u64;
+                global_decompressed_size.fetch_add(decompressed_size, Ordering::Relaxed);
+                global_decompressed_lines.fetch_add(line_counter, Ordering::Relaxed);
+                return Err(std::io::Error::new(
+                    e.kind(),
+                    format!(
+                        "{} after line {} ({} decompressed, around compressed byte {})",
+                        e,
+                        file_lines,
+                        HumanBytes(file_decompressed_size as u64),
+                        compressed_offset
+                    ),
+                ));
+            }
+        };
+        let newlines = chunk[..read].iter().filter(|&&byte| byte == b'\n').count();
+        last_byte = chunk[read - 1];
+
+        file_decompressed_size += read;
+        file_lines += newlines;
+        decompressed_size += read;
+        line_counter += newlines;
+        if decompressed_size > 500000000 {
+            // Update in 500 MB intervals
+            global_decompressed_size.fetch_add(decompressed_size, Ordering::Relaxed);
+            decompressed_size = 0;
+            global_decompressed_lines.fetch_add(line_counter, Ordering::Relaxed);
+            line_counter = 0;
+        }
+    }
+
+    // The last line usually has no trailing newline but is still a line
+    if file_decompressed_size > 0 && last_byte != b'\n' {
+        file_lines += 1;
+        line_counter += 1;
+    }
+    global_decompressed_size.fetch_add(decompressed_size, Ordering::Relaxed);
+    global_decompressed_lines.fetch_add(line_counter, Ordering::Relaxed);
+
+    Ok((file_decompressed_size, file_lines))
+}
+
+// Re-read a finished output file and compare its number of lines with what was written
+fn verify_output(file_path: &Path, expected_lines: usize, config: &Config) -> Result<(), String> {
+    let file_name = file_path.file_name().unwrap_or_default();
+    let file = File::open(file_path).map_err(|e| {
+        format!(
+            "Failed to open output {:?} for verification: {}",
+            file_name, e
+        )
+    })?;
+    let lines = if config.zstd {
+        verify_zstd(file_path, true)?;
+        let decoder = open_output_decoder(file)
+            .map_err(|e| format!("Failed to decode output {:?}: {}", file_name, e))?;
+        count_stream_lines(decoder, config)
+    } else {
+        count_stream_lines(file, config)
+    }
+    .map_err(|e| format!("Failed to read output {:?}: {}", file_name, e))?;
+
+    // The brackets of --json-array are on lines of their own
+    let expected_lines = if config.json_array {
+        expected_lines + 2
+    } else {
+        expected_lines
+    };
+    if lines != expected_lines {
+        return Err(format!(
+            "Output {:?} contains {} lines but {} were written.",
+            file_name, lines, expected_lines
+        ));
+    }
+    Ok(())
+}
+
+// The last byte of --output-delimiter, which ends every record in the output
+fn record_terminator(config: &Config) -> u8 {
+    config
+        .output_delimiter
+        .as_bytes()
+        .last()
+        .copied()
+        .unwrap_or(b'\n')
+}
+
+// Number of record terminators in output data, used to know how many lines verification should find
+fn count_records(data: &[u8], config: &Config) -> usize {
+    let terminator = record_terminator(config);
+    data.iter().filter(|&&byte| byte == terminator).count()
+}
+
+// Count the lines of an output stream, a last line without a trailing delimiter is counted as well
+fn count_stream_lines(mut reader: impl Read, config: &Config) -> std::io::Result<usize> {
+    let terminator = record_terminator(config);
+    let mut chunk = vec![0u8; 1 << 16];
+    let mut lines = 0;
+    let mut last_byte = terminator;
+    loop {
+        let read = match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        lines += count_records(&chunk[..read], config);
+        last_byte = chunk[read - 1];
+    }
+    if last_byte != terminator {
+        lines += 1;
+    }
+    Ok(lines)
+}
+
+// Kept lines of an output that have not been written yet
+// Every line is terminated by the delimiter when it is pushed, so every flush ends with a complete line
+// and readers following the output never see half of one
+// The records of --json-array are separated instead, a comma after the last one would not be valid JSON
+struct LineBuffer {
+    bytes: Vec<u8>,
+    // Whether the next --json-array record follows another one, in the same output file or in the same part of a shared output
+    separate: bool,
+    // The --partition-by-time bucket of every line with the offset its bytes end at
+    buckets: Vec<(usize, Option<String>)>,
+}
+
+impl LineBuffer {
+    fn new(capacity: usize) -> Self {
+        LineBuffer {
+            bytes: Vec::with_capacity(capacity),
+            separate: false,
+            buckets: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, line: &str, config: &Config) {
+        if !config.json_array {
+            self.bytes.extend_from_slice(line.as_bytes());
+            self.bytes
+                .extend_from_slice(config.output_delimiter.as_bytes());
+            return;
+        }
+        if self.separate {
+            self.bytes
+                .extend_from_slice(config.output_delimiter.as_bytes());
+        }
+        self.bytes.extend_from_slice(line.as_bytes());
+        self.separate = true;
+    }
+
+    // Pushes a line that goes to a --partition-by-time bucket
+    fn push_to_bucket(&mut self, line: &str, bucket: Option<String>, config: &Config) {
+        self.push(line, config);
+        self.buckets.push((self.bytes.len(), bucket));
+    }
+
+    fn flush(&mut self, writer: &mut Sink, output_limit: &OutputLimit) -> std::io::Result<()> {
+        writer.write_all(&self.bytes, &self.buckets)?; // Write the buffer content to the output
+        if !output_limit.compressed {
+            output_limit.add(self.bytes.len() as u64);
+        }
+        self.bytes.clear(); // Clear the buffer after writing
+        self.buckets.clear();
+
+        // Shared outputs separate the parts written by different inputs themselves
+        if !matches!(writer, Sink::File(_)) {
+            self.separate = false;
+        }
+        Ok(())
+    }
+}
+
+// Where the matching lines of a single input end up
+enum Sink<'a> {
+    // An output file per input, None if nothing should be written
+    File(Option<OutputWriter>),
+    // A single output file shared by all inputs with --merge-output
+    Merged(&'a Mutex<MergedOutput>),
+    // A file per time bucket shared by all inputs with --partition-by-time
+    Partitioned(&'a Mutex<TimePartitions>),
+}
+
+// Outputs that all inputs write to instead of an output file per input
+#[derive(Clone, Copy)]
+enum SharedOutput<'a> {
+    Merged(&'a Mutex<MergedOutput>),
+    Partitioned(&'a Mutex<TimePartitions>),
+}
+
+impl Sink<'_> {
+    // Data is always made up of complete lines, each terminated by the delimiter
+    // With --json-array the delimiter only separates them, the last one has none
+    // The --partition-by-time buckets say which part of the data goes where
+    fn write_all(
+        &mut self,
+        data: &[u8],
+        buckets: &[(usize, Option<String>)],
+    ) -> std::io::Result<()> {
+        match self {
+            Sink::File(Some(writer)) => writer.write_all(data),
+            Sink::File(None) => Ok(()),
+            Sink::Merged(merged_output) => merged_output.lock().unwrap().write_lines(data),
+            Sink::Partitioned(partitions) => partitions.lock().unwrap().write_lines(data, buckets),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Sink::File(Some(writer)) => writer.flush(),
+            Sink::File(None) => Ok(()),
+            Sink::Merged(merged_output) => merged_output.lock().unwrap().writer.flush(),
+            Sink::Partitioned(partitions) => partitions.lock().unwrap().flush(),
+        }
+    }
+
+    // The merged output is only finished once all inputs are done
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            Sink::File(Some(writer)) => writer.finish(),
+            _ => Ok(()),
+        }
+    }
+}
+
+// The --route outputs of a single input, one file per category in a subdirectory named after it
+// A category file is only created once the first line is routed to it
+struct RouteOutputs<'a> {
+    config: &'a Config,
+    output_limit: &'a Arc<OutputLimit>,
+    // Window of the input for --match-input-params
+    window_log: u32,
+    outputs: Vec<RouteOutput<'a>>,
+}
+
+struct RouteOutput<'a> {
+    path: String,
+    writer: Option<Sink<'a>>,
+    buffer: LineBuffer,
+    lines_written: usize,
+}
+
+impl<'a> RouteOutputs<'a> {
+    fn new(
+        output_file_path: &str,
+        window_log: u32,
+        filter: &LineFilter,
+        config: &'a Config,
+        output_limit: &'a Arc<OutputLimit>,
+    ) -> Self {
+        RouteOutputs {
+            config,
+            output_limit,
+            window_log,
+            outputs: route_output_paths(output_file_path, filter, config)
+                .into_iter()
+                .map(|path| RouteOutput {
+                    path,
+                    writer: None,
+                    buffer: LineBuffer::new(0),
+                    lines_written: 0,
+                })
+                .collect(),
+        }
+    }
+
+    // Writes a line to every given route, with --route-mode all it is duplicated
+    fn write(&mut self, routes: &[usize], line: String) -> std::io::Result<()> {
+        if let Some((&last, others)) = routes.split_last() {
+            for &route in others {
+                self.write_to(route, line.clone())?;
+            }
+            self.write_to(last, line)?;
+        }
+        Ok(())
+    }
+
+    fn write_to(&mut self, route: usize, line: String) -> std::io::Result<()> {
+        let output = &mut self.outputs[route];
+        if output.writer.is_none() {
+            let file = File::create(&output.path)?;
+            let mut writer = OutputWriter::new(file, self.config, self.output_limit)?;
+            writer.set_window_log(self.window_log)?;
+            output.writer = Some(Sink::File(Some(writer)));
+            if self.config.json_array {
+                output.buffer.bytes.extend_from_slice(JSON_ARRAY_START);
+            }
+        }
+        output.lines_written += count_records(line.as_bytes(), self.config) + 1;
+        output.buffer.push(&line, self.config);
+        if output.buffer.bytes.len() >= self.config.buffer {
+            let writer = output.writer.as_mut().unwrap();
+            output.buffer.flush(writer, self.output_limit)?;
+        }
+        Ok(())
+    }
+
+    // Forces the buffered lines out for --flush-interval and --flush-ms
+    fn flush(&mut self) -> std::io::Result<()> {
+        for output in &mut self.outputs {
+            if let Some(writer) = &mut output.writer {
+                output.buffer.flush(writer, self.output_limit)?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Writes the remaining lines and finishes every file that was created
+    fn finish(self) -> std::io::Result<()> {
+        for mut output in self.outputs {
+            let Some(mut writer) = output.writer else {
+                continue;
+            };
+            if self.config.json_array {
+                output.buffer.bytes.extend_from_slice(JSON_ARRAY_END);
+            }
+            output.buffer.flush(&mut writer, self.output_limit)?;
+            writer.finish()?;
+            if self.config.verify_output {
+                if let Err(err) =
+                    verify_output(Path::new(&output.path), output.lines_written, self.config)
+                {
+                    let _ = fs::remove_file(&output.path);
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "{}. The output file was deleted.",
+                            err.trim_end_matches('.')
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Deletes the incomplete files after an error so they are created again on the next run
+    fn remove(self) {
+        for output in self.outputs {
+            if output.writer.is_some() {
+                drop(output.writer);
+                let _ = fs::remove_file(&output.path);
+            }
+        }
+    }
+}
+
+// The output file for --merge-output, written to by all workers
+struct MergedOutput {
+    writer: OutputWriter,
+    // Separates the lines of different inputs, the --output-delimiter
+    delimiter: Vec<u8>,
+    // Whether the file ends with the delimiter, only false for a file continued with --append
+    // whose last line was written without one, e.g. by an older version or another tool
+    at_line_start: bool,
+    newlines_written: usize,
+}
+
+impl MergedOutput {
+    fn new(
+        file: impl Write + Send + 'static,
+        config: &Config,
+        output_limit: &Arc<OutputLimit>,
+    ) -> std::io::Result<Self> {
+        let mut writer = OutputWriter::new(file, config, output_limit)?;
+        if config.json_array {
+            writer.write_all(JSON_ARRAY_START)?;
+        }
+        Ok(MergedOutput {
+            writer,
+            delimiter: config.output_delimiter.as_bytes().to_vec(),
+            at_line_start: true,
+            newlines_written: 0,
+        })
+    }
+
+    // Continue an existing output with --append, compressed output gets a new zstd frame
+    // which decoders read as if it was one continuous stream
+    fn append(
+        path: &str,
+        config: &Config,
+        output_limit: &Arc<OutputLimit>,
+    ) -> std::io::Result<Self> {
+        let existing_size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        // Outputs of this tool end with the delimiter, only uncompressed files can be checked cheaply
+        let at_line_start = if existing_size == 0 || config.zstd {
+            true
+        } else {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::End(-1))?;
+            let mut last_byte = [0u8];
+            file.read_exact(&mut last_byte)?;
+            last_byte[0] == record_terminator(config)
+        };
+        // The existing lines are only needed to verify the complete file afterwards
+        let existing_lines = if config.verify_output && existing_size > 0 {
+            let file = File::open(path)?;
+            if config.zstd {
+                count_stream_lines(open_output_decoder(file)?, config)?
+            } else {
+                count_stream_lines(file, config)?
+            }
+        } else {
+            0
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(MergedOutput {
+            writer: OutputWriter::new(file, config, output_limit)?,
+            delimiter: config.output_delimiter.as_bytes().to_vec(),
+            at_line_start,
+            newlines_written: if at_line_start || existing_lines == 0 {
+                existing_lines
+            } else {
+                existing_lines - 1
+            },
+        })
+    }
+
+    // Every line becomes a row in a sqlite database
+    #[cfg(feature = "sqlite")]
+    fn sqlite(path: &str) -> std::io::Result<Self> {
+        Ok(MergedOutput {
+            writer: OutputWriter::Sqlite(sqlite::SqliteWriter::new(path)?),
+            delimiter: b"\n".to_vec(),
+            at_line_start: true,
+            newlines_written: 0,
+        })
+    }
+
+    // Number of lines in the output, a last line without a trailing delimiter counts as well
+    fn lines_written(&self) -> usize {
+        if self.at_line_start {
+            self.newlines_written
+        } else {
+            self.newlines_written + 1
+        }
+    }
+
+    // Lines from different inputs can arrive in any order, every part ends with the delimiter
+    // so it is already separated from whatever comes next
+    fn write_lines(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        if !self.at_line_start {
+            self.writer.write_all(&self.delimiter)?;
+            self.newlines_written += 1;
+        }
+        self.writer.write_all(data)?;
+        let terminator = self.delimiter.last().copied().unwrap_or(b'\n');
+        self.newlines_written += data.iter().filter(|&&byte| byte == terminator).count();
+        self.at_line_start = data.ends_with(&self.delimiter);
+        Ok(())
+    }
+}
+
+// The outputs of --partition-by-time, the kept lines of all inputs go to a file per time bucket
+// of a timestamp field, e.g. 2024-01-05.jsonl, lines without a usable timestamp to unpartitioned.jsonl
+// Only a limited number of files is kept open, the least recently used one is finished and continued later
+struct TimePartitions {
+    config: Config,
+    output_limit: Arc<OutputLimit>,
+    buckets: TimeBuckets,
+    max_open: usize,
+    // Open files by bucket with the write they were last used for
+    open: HashMap<String, (MergedOutput, u64)>,
+    // Buckets created in this run with the lines they had when last closed
+    // A closed bucket is continued instead of created again
+    created: HashMap<String, usize>,
+    writes: u64,
+    unpartitioned: usize,
+}
+
+// How --partition-by-time names the bucket of a line, every input gets a copy to find the buckets of its lines
+#[derive(Clone)]
+struct TimeBuckets {
+    field: String,
+    // chrono format of the bucket names for the granularity
+    format: &'static str,
+    // Buckets are taken in this fixed offset from UTC, RFC 3339 offsets of the timestamps are converted
+    offset: FixedOffset,
+}
+
+impl TimeBuckets {
+    // Name of the bucket of a line as it was read, None if it has no timestamp that can be parsed
+    // The output options that change the written line do not matter for it
+    fn bucket(&self, line: &str) -> Option<String> {
+        let value = serde_json::from_str::<serde_json::Value>(line).ok()?;
+        let millis = json::timestamp_millis(json::get_path(&value, &self.field)?)?;
+        let time = chrono::DateTime::from_timestamp_millis(millis)?.with_timezone(&self.offset);
+        Some(time.format(self.format).to_string())
+    }
+}
+
+impl TimePartitions {
+    fn new(
+        config: &Config,
+        output_limit: &Arc<OutputLimit>,
+        max_open: usize,
+    ) -> Result<Option<Self>, String> {
+        if config.partition_by_time.is_empty() {
+            return Ok(None);
+        }
+        let (field, granularity) = config.partition_by_time.rsplit_once(':').ok_or_else(|| {
+            format!(
+                "Invalid --partition-by-time {:?}, expected <path>:<granularity>",
+                config.partition_by_time
+            )
+        })?;
+        let format = match granularity {
+            "year" => "%Y",
+            "month" => "%Y-%m",
+            "day" => "%Y-%m-%d",
+            "hour" => "%Y-%m-%dT%H",
+            _ => {
+                return Err(format!(
+                    "Invalid granularity {:?} in --partition-by-time, expected year, month, day or hour",
+                    granularity
+                ))
+            }
+        };
+        let offset = config
+            .partition_utc_offset
+            .parse::<FixedOffset>()
+            .map_err(|_| {
+                format!(
+                    "Invalid --partition-utc-offset {:?}, expected an offset like +02:00",
+                    config.partition_utc_offset
+                )
+            })?;
+        Ok(Some(TimePartitions {
+            config: config.clone(),
+            output_limit: Arc::clone(output_limit),
+            buckets: TimeBuckets {
+                field: field.to_string(),
+                format,
+                offset,
+            },
+            max_open: max_open.max(1),
+            open: HashMap::new(),
+            created: HashMap::new(),
+            writes: 0,
+            unpartitioned: 0,
+        }))
+    }
+
+    fn path(&self, bucket: &str) -> String {
+        let extension = if self.config.file_extension.is_empty() {
+            "jsonl"
+        } else {
+            &self.config.file_extension
+        };
+        format!(
+            "{}{}{}",
+            self.config.output,
+            bucket,
+            output_extension(extension, &self.config)
+        )
+    }
+
+    // Data holds the output lines of LineBuffer, every line ends at its offset in buckets
+    fn write_lines(
+        &mut self,
+        data: &[u8],
+        buckets: &[(usize, Option<String>)],
+    ) -> std::io::Result<()> {
+        let mut start = 0;
+        for (end, bucket) in buckets {
+            let bucket = bucket.clone().unwrap_or_else(|| {
+                self.unpartitioned += 1;
+                String::from("unpartitioned")
+            });
+            self.output(&bucket)?.write_lines(&data[start..*end])?;
+            start = *end;
+        }
+        Ok(())
+    }
+
+    fn output(&mut self, bucket: &str) -> std::io::Result<&mut MergedOutput> {
+        self.writes += 1;
+        if !self.open.contains_key(bucket) {
+            if self.open.len() >= self.max_open {
+                let least_recent = self
+                    .open
+                    .iter()
+                    .min_by_key(|(_, (_, last_write))| *last_write)
+                    .map(|(bucket, _)| bucket.clone());
+                if let Some(least_recent) = least_recent {
+                    self.close(&least_recent)?;
+                }
+            }
+            let path = self.path(bucket);
+            let output = if self.created.contains_key(bucket) {
+                MergedOutput::append(&path, &self.config, &self.output_limit)?
+            } else {
+                // Existing files are never overwritten, like the file of --merge-output
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)
+                    .map_err(|e| {
+                        std::io::Error::new(
+                            e.kind(),
+                            format!("Unable to create the partition {:?}: {}", path, e),
+                        )
+                    })?;
+                self.created.insert(bucket.to_string(), 0);
+                MergedOutput::new(file, &self.config, &self.output_limit)?
+            };
+            self.open.insert(bucket.to_string(), (output, 0));
+        }
+        let (output, last_write) = self.open.get_mut(bucket).unwrap();
+        *last_write = self.writes;
+        Ok(output)
+    }
+
+    fn close(&mut self, bucket: &str) -> std::io::Result<()> {
+        let Some((output, _)) = self.open.remove(bucket) else {
+            return Ok(());
+        };
+        self.created
+            .insert(bucket.to_string(), output.lines_written());
+        output.writer.finish()
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        for (output, _) in self.open.values_mut() {
+            output.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    // Finishes all files that are still open once every input is done
+    // Returns the number of files written, the number of lines without a usable timestamp
+    // and the number of files that failed --verify-output
+    fn finish(mut self) -> std::io::Result<(usize, usize, usize)> {
+        let buckets: Vec<String> = self.open.keys().cloned().collect();
+        for bucket in buckets {
+            self.close(&bucket)?;
+        }
+        let mut broken = 0;
+        if self.config.verify_output {
+            for (bucket, lines_written) in &self.created {
+                if let Err(err) =
+                    verify_output(Path::new(&self.path(bucket)), *lines_written, &self.config)
+                {
+                    eprintln!("{}", err.red());
+                    broken += 1;
+                }
+            }
+        }
+        Ok((self.created.len(), self.unpartitioned, broken))
+    }
+}
+
+// Handles output either compressed or uncompressed
+// Compressed output uses a single encoder per file so the output is one zstd frame
+enum OutputWriter {
+    Plain(BufWriter<CountingFile>),
+    Zstd(Encoder<'static, BufWriter<CountingFile>>),
+    // --include-content-size keeps the output in a temporary file, the frame is only written once its size is known
+    Sized {
+        spool: SpoolFile,
+        file: BufWriter<CountingFile>,
+        compression_level: i32,
+        window_log: u32,
+    },
+    #[cfg(feature = "sqlite")]
+    Sqlite(sqlite::SqliteWriter),
+}
+
+impl OutputWriter {
+    fn new(
+        file: impl Write + Send + 'static,
+        config: &Config,
+        output_limit: &Arc<OutputLimit>,
+    ) -> std::io::Result<Self> {
+        let buf_writer = BufWriter::new(CountingFile {
+            file: Box::new(file),
+            output_limit: Arc::clone(output_limit),
+        });
+        if config.zstd && config.include_content_size {
+            Ok(OutputWriter::Sized {
+                spool: SpoolFile::create()?,
+                file: buf_writer,
+                compression_level: config.compression_level,
+                window_log: 0,
+            })
+        } else if config.zstd {
+            Ok(OutputWriter::Zstd(Encoder::new(
+                buf_writer,
+                config.compression_level,
+            )?))
+        } else {
+            Ok(OutputWriter::Plain(buf_writer))
+        }
+    }
+
+    // Compress with the window of the input for --match-input-params, 0 keeps the zstd default
+    fn set_window_log(&mut self, log: u32) -> std::io::Result<()> {
+        match self {
+            OutputWriter::Zstd(encoder) if log != 0 => encoder.window_log(log),
+            OutputWriter::Sized { window_log, .. } => {
+                *window_log = log;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Write the end of the zstd frame (if any) and flush all remaining data
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            OutputWriter::Plain(mut writer) => writer.flush(),
+            OutputWriter::Zstd(encoder) => encoder.finish()?.flush(),
+            OutputWriter::Sized {
+                mut spool,
+                file,
+                compression_level,
+                window_log,
+            } => {
+                let mut encoder = Encoder::new(file, compression_level)?;
+                if window_log != 0 {
+                    encoder.window_log(window_log)?;
+                }
+                encoder.set_pledged_src_size(Some(spool.size))?;
+                std::io::copy(spool.rewind()?, &mut encoder)?;
+                encoder.finish()?.flush()
+            }
+            #[cfg(feature = "sqlite")]
+            OutputWriter::Sqlite(writer) => writer.finish(),
+        }
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        match self {
+            OutputWriter::Plain(writer) => writer.write(data),
+            OutputWriter::Zstd(encoder) => encoder.write(data),
+            OutputWriter::Sized { spool, .. } => spool.write(data),
+            #[cfg(feature = "sqlite")]
+            OutputWriter::Sqlite(writer) => writer.write(data),
+        }
+    }
+
+    // For zstd this ends the current block so everything written so far can be decoded
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputWriter::Plain(writer) => writer.flush(),
+            OutputWriter::Zstd(encoder) => encoder.flush(),
+            // Nothing can be written before the size is known
+            OutputWriter::Sized { .. } => Ok(()),
+            #[cfg(feature = "sqlite")]
+            OutputWriter::Sqlite(writer) => writer.flush(),
+        }
+    }
+}
+
+// The uncompressed content of a --include-content-size output until it is complete
+// Lives in the temporary directory and is deleted when it is dropped, even when the output fails
+struct SpoolFile {
+    file: BufWriter<File>,
+    path: PathBuf,
+    size: u64,
+}
+
+impl SpoolFile {
+    fn create() -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "zstd-jsonl-filter-{}-{}.spool",
+            process::id(),
+            SPOOL_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(SpoolFile {
+            file: BufWriter::new(file),
+            path,
+            size: 0,
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.file.write_all(data)?;
+        self.size += data.len() as u64;
+        Ok(data.len())
+    }
+
+    // Goes back to the start to read the content once it is complete
+    fn rewind(&mut self) -> std::io::Result<&mut File> {
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(0))?;
+        Ok(file)
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// An output file that reports the bytes that actually reach the disk for --max-output-bytes-compressed
+// Usually a file, stdout when filtering stdin
+struct CountingFile {
+    file: Box<dyn Write + Send>,
+    output_limit: Arc<OutputLimit>,
+}
+
+impl Write for CountingFile {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        // Only the write that reported the full disk reports the free space, other writers may still be waiting
+        let mut reported_full = false;
+        let written = loop {
+            match self.file.write(data) {
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::StorageFull
+                        && self.output_limit.pause_on_disk_full =>
+                {
+                    if !reported_full && !self.output_limit.disk_full.swap(true, Ordering::Relaxed)
+                    {
+                        reported_full = true;
+                        eprintln!(
+                            "{}",
+                            format!(
+                                "The output disk is full, retrying every {}s until there is free space",
+                                DISK_FULL_RETRY_INTERVAL
+                            )
+                            .yellow()
+                        );
+                    }
+                    std::thread::sleep(Duration::from_secs(DISK_FULL_RETRY_INTERVAL));
+                }
+                result => break result?,
+            }
+        };
+        if reported_full {
+            self.output_limit.disk_full.store(false, Ordering::Relaxed);
+            eprintln!(
+                "{}",
+                "The output disk has free space again, continuing".green()
+            );
+        }
+        self.output_limit.write_rate.consume(written);
+        if self.output_limit.compressed {
+            self.output_limit.add(written as u64);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// Counting semaphore for --max-open-files, workers wait until enough file handles are free
+struct FileHandleLimit {
+    // None means no limit
+    available: Option<Mutex<usize>>,
+    max: usize,
+    released: Condvar,
+}
+
+impl FileHandleLimit {
+    fn new(max: usize) -> Self {
+        FileHandleLimit {
+            available: (max > 0).then(|| Mutex::new(max)),
+            max,
+            released: Condvar::new(),
+        }
+    }
+
+    // Blocks until the handles are available, they are given back when the permit is dropped
+    fn acquire(&self, handles: usize) -> FileHandlePermit<'_> {
+        let Some(available) = &self.available else {
+            return FileHandlePermit {
+                limit: self,
+                handles: 0,
+            };
+        };
+        // A limit below what a single file needs would wait forever
+        let handles = handles.min(self.max);
+        let mut available = available.lock().unwrap();
+        while *available < handles {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= handles;
+        FileHandlePermit {
+            limit: self,
+            handles,
+        }
+    }
+}
+
+struct FileHandlePermit<'a> {
+    limit: &'a FileHandleLimit,
+    handles: usize,
+}
+
+impl Drop for FileHandlePermit<'_> {
+    fn drop(&mut self) {
+        if let Some(available) = &self.limit.available {
+            *available.lock().unwrap() += self.handles;
+            self.limit.released.notify_all();
+        }
+    }
+}
+
+// Total bytes written by all workers, compared against --max-output-bytes
+struct OutputLimit {
+    // 0 means no limit
+    max_bytes: u64,
+    // Count the bytes on disk instead of the bytes before compression
+    compressed: bool,
+    bytes_written: AtomicU64,
+    reached: AtomicBool,
+    // Throttles everything written to disk with --max-write-rate
+    write_rate: RateLimit,
+    // Writes wait for free space instead of failing with --on-disk-full pause
+    pause_on_disk_full: bool,
+    // Set while a write waits for free space, so the warning is only printed once
+    disk_full: AtomicBool,
+    // Set by --on-disk-full abort, no further inputs are started
+    aborted: AtomicBool,
+}
+
+impl OutputLimit {
+    fn new(config: &Config) -> Self {
+        OutputLimit {
+            max_bytes: config.max_output_bytes,
+            compressed: config.max_output_bytes_compressed,
+            bytes_written: AtomicU64::new(0),
+            reached: AtomicBool::new(false),
+            write_rate: RateLimit::new(config.max_write_rate),
+            pause_on_disk_full: config.on_disk_full == "pause",
+            disk_full: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
+        }
+    }
+
+    fn add(&self, bytes: u64) {
+        if self.max_bytes == 0 {
+            return;
+        }
+        let total = self.bytes_written.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if total >= self.max_bytes {
+            self.reached.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn is_reached(&self) -> bool {
+        self.reached.load(Ordering::Relaxed)
+    }
+}
+
+// Token bucket for --max-read-rate and --max-write-rate, shared by all workers
+// Up to one second worth of bytes can be used at once, a worker that goes over its budget sleeps until it is paid off
+struct RateLimit {
+    // Bytes per second, 0 means no limit
+    rate: u64,
+    // Available bytes, negative while a worker is waiting, and when they were last refilled
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimit {
+    fn new(rate: u64) -> Self {
+        RateLimit {
+            rate,
+            bucket: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    fn consume(&self, bytes: usize) {
+        if self.rate == 0 || bytes == 0 {
+            return;
+        }
+        let rate = self.rate as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (tokens, refilled) = &mut *bucket;
+            *tokens = (*tokens + refilled.elapsed().as_secs_f64() * rate).min(rate);
+            *refilled = Instant::now();
+            *tokens -= bytes as f64;
+            -*tokens / rate
+        };
+        if wait > 0.0 {
+            std::thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
+
+fn generate_output_filename(input_file_path: &str, config: &Config) -> String {
+    let path = Path::new(input_file_path);
+
+    // Strip the ".jsonl.zst" extension
+    let input_stem = path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string(); // e.g. "13030000000-13040000000.jsonl"
+
+    let file_stem_without_extension = output_stem(input_file_path);
+
+    let original_file_extension = Path::new(&input_stem)
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy();
+
+    // --file-extension replaces the inner extension, --strip-inner-extension drops it
+    // An input without an inner extension like "name.zst" gets none either
+    let output_file_extension = if !config.file_extension.is_empty() {
+        output_extension(&config.file_extension, config)
+    } else if config.strip_inner_extension {
+        output_extension("", config)
+    } else {
+        output_extension(&original_file_extension, config)
+    };
+    format!(
+        "{}{file_stem_without_extension}{}{}",
+        output_dir(input_file_path, config),
+        config.suffix,
+        output_file_extension
+    )
+}
+
+// The extension of an output file including its dot, compressed outputs get .zst added
+// An extension like "jsonl.zst" already names the compression and is not doubled
+fn output_extension(extension: &str, config: &Config) -> String {
+    let extension = extension.trim_start_matches('.');
+    let extension = if extension.is_empty() {
+        String::new()
+    } else {
+        format!(".{}", extension)
+    };
+    if config.zstd && !extension.ends_with(".zst") {
+        extension + ".zst"
+    } else {
+        extension
+    }
+}
+
+// The files of the --route categories or --bucket-by-match-count buckets of an output
+fn route_output_paths(output_file_path: &str, filter: &LineFilter, config: &Config) -> Vec<String> {
+    if config.bucket_by_match_count {
+        return (0..=filter.routes.len())
+            .map(|count| bucket_output_path(output_file_path, count, config))
+            .collect();
+    }
+    // The category directory goes between the output directory and the file name
+    let (dir, file_name) =
+        output_file_path.split_at(output_file_path.rfind('/').map_or(0, |i| i + 1));
+    filter
+        .routes
+        .iter()
+        .map(|(name, _)| format!("{}{}/{}", dir, name, file_name))
+        .collect()
+}
+
+// The file of a --bucket-by-match-count bucket, the number goes before the extension of the regular output
+// e.g. "out/a_filtered.matched2.jsonl.zst" for "out/a_filtered.jsonl.zst"
+fn bucket_output_path(output_file_path: &str, count: usize, config: &Config) -> String {
+    let compressed = if config.zstd {
+        output_file_path.strip_suffix(".zst")
+    } else {
+        None
+    };
+    let path = Path::new(compressed.unwrap_or(output_file_path));
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    format!(
+        "{}.matched{}{}{}",
+        path.with_extension("").to_string_lossy(),
+        count,
+        extension,
+        if compressed.is_some() { ".zst" } else { "" }
+    )
+}
+
+// The base name of an input that all its output files start with, e.g. "13030000000-13040000000"
+// for "13030000000-13040000000.jsonl.zst"
+fn output_stem(input_file_path: &str) -> String {
+    let input_stem = Path::new(input_file_path).file_stem().unwrap_or_default();
+    Path::new(input_stem)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
+}
+
+// Files in the output directories that were written for the current inputs by a run with a different
+// --file-extension or --zstd, so they do not have the name the current run would give them
+// A file only counts if it is named exactly the base name of an input, --suffix and an extension,
+// so the outputs of an input whose base name starts with the same text are never touched
+fn stale_outputs(zstd_files: &[PathBuf], config: &Config) -> Vec<PathBuf> {
+    let mut expected = HashSet::new();
+    let mut stems_by_dir: HashMap<&str, HashSet<String>> = HashMap::new();
+    for input in zstd_files {
+        let input = input.to_string_lossy();
+        let output = generate_output_filename(&input, config);
+        if config.bucket_by_match_count {
+            for count in 0..=config.routes.len() {
+                expected.insert(PathBuf::from(bucket_output_path(&output, count, config)));
+            }
+        }
+        expected.insert(PathBuf::from(output));
+        stems_by_dir
+            .entry(output_dir(&input, config))
+            .or_default()
+            .insert(output_stem(&input));
+    }
+    let mut stale = Vec::new();
+    for (dir, stems) in stems_by_dir {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = Path::new(dir).join(entry.file_name());
+            let name = entry.file_name().to_string_lossy().to_string();
+            let is_file = entry.file_type().is_ok_and(|file_type| file_type.is_file());
+            let named_after_input = stems.iter().any(|stem| {
+                name.strip_prefix(stem.as_str())
+                    .and_then(|rest| rest.strip_prefix(config.suffix.as_str()))
+                    .is_some_and(is_extension)
+            });
+            // Inputs can live in the output directory and --extract-metadata sidecars are not outputs
+            if is_file
+                && named_after_input
+                && !expected.contains(&path)
+                && !zstd_files.contains(&path)
+                && !name.ends_with(".metadata.jsonl")
+            {
+                stale.push(path);
+            }
+        }
+    }
+    stale.sort();
+    stale
+}
+
+// Nothing or only extensions like ".jsonl.zst", anything else belongs to a different file name
+fn is_extension(rest: &str) -> bool {
+    rest.is_empty()
+        || rest.strip_prefix('.').is_some_and(|extensions| {
+            extensions
+                .split('.')
+                .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()))
+        })
+}
+
+// The directory the output of an input is written to, from the first --output-for pattern
+// that matches the input path and --output for all other inputs
+fn output_dir<'a>(input_file_path: &str, config: &'a Config) -> &'a str {
+    config
+        .output_for
+        .iter()
+        .filter_map(|mapping| mapping.split_once('='))
+        .find(|(pattern, _)| {
+            glob::Pattern::new(pattern).is_ok_and(|pattern| pattern.matches(input_file_path))
+        })
+        .map_or(&config.output, |(_, dir)| dir)
+}
+
+// Name of the output file of a single thread with --shard-by-thread
+// The thread number is inserted before the extensions, e.g. merged.jsonl becomes merged.thread0.jsonl
+fn shard_file_name(merge_output: &str, index: usize) -> String {
+    let path = Path::new(merge_output);
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let shard_name = match file_name.split_once('.') {
+        Some((stem, extensions)) => format!("{}.thread{}.{}", stem, index, extensions),
+        None => format!("{}.thread{}", file_name, index),
+    };
+    path.with_file_name(shard_name)
+        .to_string_lossy()
+        .to_string()
+}
+
+// Cheap fingerprint of a file's content for --resume-by-hash: its size plus the first and last bytes
+// Renamed or moved files keep their key, reading the whole file would take far too long
+fn content_key(path: &Path) -> std::io::Result<u64> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut hasher = Xxh3::new();
+    hasher.update(&size.to_le_bytes());
+
+    let mut sample = Vec::with_capacity(RESUME_SAMPLE_SIZE as usize);
+    (&mut file)
+        .take(RESUME_SAMPLE_SIZE)
+        .read_to_end(&mut sample)?;
+    hasher.update(&sample);
+    if size > RESUME_SAMPLE_SIZE {
+        sample.clear();
+        file.seek(SeekFrom::Start(
+            size.saturating_sub(RESUME_SAMPLE_SIZE)
+                .max(RESUME_SAMPLE_SIZE),
+        ))?;
+        file.read_to_end(&mut sample)?;
+        hasher.update(&sample);
+    }
+    Ok(hasher.digest())
+}
+
+// Check if the file name ends with one of the accepted input extensions, e.g. "zst" or "jsonl.zst"
+// This only selects the files, whether they are actually zstd is decided by verify_zstd
+fn has_input_extension(path: &Path, input_extensions: &[String]) -> bool {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    input_extensions
+        .iter()
+        .any(|ext| file_name.ends_with(&format!(".{}", ext.trim_start_matches('.'))))
+}
+
+// The decompressed size recorded in the zstd frame headers, summed over all frames of files written
+// by pzstd or --append, if the encoder wrote it for every frame
+// Only frame and block headers are read, so this is cheap even for huge files
+fn frame_content_size(file_path: &Path) -> Option<u64> {
+    frames::content_size(file_path).ok()?
+}
+
+// The window of the first zstd frame in log2 bytes for --match-input-params, rounded up
+// Single segment frames have no window descriptor, their window is the decompressed size
+fn frame_window_log(file_path: &Path) -> Option<u32> {
+    let header = read_frame_header(file_path)?;
+    if header.len() < 6 || header[..4] != ZSTD_MAGIC {
+        return None;
+    }
+    let descriptor = header[4];
+    let single_segment = descriptor & 0x20 != 0;
+    let window_log = if single_segment {
+        let content_size = zstd::zstd_safe::get_frame_content_size(&header).ok()??;
+        u64::BITS - content_size.saturating_sub(1).leading_zeros()
+    } else {
+        let exponent = (header[5] >> 3) as u32;
+        let mantissa = header[5] & 0x07;
+        10 + exponent + u32::from(mantissa > 0)
+    };
+    Some(window_log.clamp(WINDOW_LOG_MIN, WINDOW_LOG_MAX))
+}
+
+// The first bytes of the first regular frame of a file, enough for any zstd frame header
+// Skippable frames in front of it are passed over, they have no frame header
+fn read_frame_header(file_path: &Path) -> Option<Vec<u8>> {
+    let mut file = File::open(file_path).ok()?;
+    loop {
+        let mut header = Vec::with_capacity(ZSTD_FRAME_HEADER_MAX);
+        (&mut file)
+            .take(ZSTD_FRAME_HEADER_MAX as u64)
+            .read_to_end(&mut header)
+            .ok()?;
+        match frames::skippable_size(&header) {
+            Some(size) => {
+                file.seek(SeekFrom::Current(size as i64 - header.len() as i64))
+                    .ok()?;
+            }
+            None => return Some(header),
+        }
+    }
+}
+
+// Writes the content of the skippable frames of an input to a sidecar file for --extract-metadata
+// Every frame becomes a JSON line, its content as text if it is UTF-8 and as hex otherwise
+fn extract_metadata(input_file_path: &Path, config: &Config, pb: &ProgressBar) {
+    let file_name = input_file_path.file_name().unwrap_or_default();
+    let skippable_frames = match frames::skippable_frames(input_file_path) {
+        Ok(skippable_frames) => skippable_frames,
+        Err(e) => {
+            pb.suspend(|| {
+                eprintln!(
+                    "{}",
+                    format!(
+                        "The frames of {:?} could not be read for --extract-metadata: {}",
+                        file_name, e
+                    )
+                    .yellow()
+                )
+            });
+            return;
+        }
+    };
+    if skippable_frames.is_empty() {
+        return;
+    }
+    let input = input_file_path.to_string_lossy();
+    let metadata_path = format!(
+        "{}{}.metadata.jsonl",
+        output_dir(&input, config),
+        output_stem(&input)
+    );
+    let mut lines = String::new();
+    for frame in &skippable_frames {
+        let mut line = serde_json::json!({
+            "offset": frame.offset,
+            "variant": frame.variant,
+            "size": frame.data.len(),
+        });
+        match std::str::from_utf8(&frame.data) {
+            Ok(text) => line["text"] = serde_json::Value::from(text),
+            Err(_) => {
+                line["hex"] = serde_json::Value::from(
+                    frame
+                        .data
+                        .iter()
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect::<String>(),
+                )
+            }
+        }
+        lines.push_str(&line.to_string());
+        lines.push('\n');
+    }
+    if let Err(e) = fs::write(&metadata_path, lines) {
+        pb.suspend(|| eprintln!("Failed to write {:?}: {}", metadata_path, e));
+        return;
+    }
+    pb.suspend(|| {
+        print_if_not_quiet(
+            config.quiet,
+            &format!(
+                "Extracted {} metadata frame(s) from {:?}",
+                skippable_frames.len(),
+                file_name
+            ),
+        )
+    });
+}
+
+// Verify that the file is a valid zstd file
+// Inputs are only decoded if they start with the zstd magic number, unless check_magic is disabled with --no-magic-check
+fn verify_zstd(file_path: &Path, check_magic: bool) -> Result<(), String> {
+    let mut file = File::open(file_path).map_err(|e| format!("Failed to open file: {}", e))?;
+
+    // Read the first few bytes to detect Zstd magic number
+    let mut magic_bytes = [0u8; 4];
+    file.read_exact(&mut magic_bytes).map_err(|_| {
+        format!(
+            "Skipped not valid zstd {:?}",
+            file_path.file_name().unwrap_or_default()
+        )
+    })?;
+
+    // Check if the magic bytes match Zstd's magic number
+    // A file can start with a skippable frame holding metadata instead of a regular frame
+    if magic_bytes == ZSTD_MAGIC || frames::is_skippable(&magic_bytes) || !check_magic {
+        // It's a Zstd archive; attempt to decompress it
+        let _ = Decoder::new(file).map_err(|_| {
+            format!(
+                "Failed to decode zstd for {:?}",
+                file_path.file_name().unwrap_or_default()
+            )
+        })?;
+    } else {
+        return Err(format!(
+            "Skipped not valid zstd {:?}",
+            file_path.file_name().unwrap_or_default()
+        ));
+    }
+    Ok(())
+}
+
+// A bar for a single file with --file-progress, measured in decompressed bytes
+// The total comes from the zstd frame header, files without it only get a spinner
+fn add_file_progress_bar(multi_progress: &MultiProgress, file_path: &Path) -> ProgressBar {
+    let name = file_path.file_name().unwrap_or_default().to_string_lossy();
+    let file_pb = match frame_content_size(file_path) {
+        Some(content_size) => ProgressBar::new(content_size).with_style(
+            ProgressStyle::with_template(
+                "  {prefix:30!} [{bar:30.green/white}] {bytes}/{total_bytes} {msg}",
+            )
+            .unwrap()
+            .progress_chars("#>-"),
+        ),
+        None => ProgressBar::new_spinner().with_style(
+            ProgressStyle::with_template("  {prefix:30!} {spinner:.green} {bytes} {msg}").unwrap(),
+        ),
+    };
+    file_pb.set_prefix(name.to_string());
+    multi_progress.add(file_pb)
+}
+
+// Files are handed to the threads in schedule order, starting the largest files first
+// avoids a single huge file running alone at the end while the other cores idle
+fn sort_by_schedule(files: &mut [PathBuf], schedule: &str) {
+    match schedule {
+        "size-desc" => files.sort_by_cached_key(|path| {
+            std::cmp::Reverse(fs::metadata(path).map(|m| m.len()).unwrap_or(0))
+        }),
+        "size-asc" => {
+            files.sort_by_cached_key(|path| fs::metadata(path).map(|m| m.len()).unwrap_or(0))
+        }
+        _ => files.sort(),
+    }
+}
+
+// Splits the files into at most count batches of about the same total size for --batch
+// Going from the largest file to the smallest, every file is added to the batch with the smallest total so far
+fn size_balanced_batches(files: &[PathBuf], count: usize) -> Vec<(Vec<PathBuf>, u64)> {
+    let mut sized: Vec<(u64, &PathBuf)> = files
+        .iter()
+        .map(|path| (fs::metadata(path).map(|m| m.len()).unwrap_or(0), path))
+        .collect();
+    sized.sort_by_key(|(size, _)| std::cmp::Reverse(*size));
+    let mut batches: Vec<(Vec<PathBuf>, u64)> = vec![(Vec::new(), 0); count.min(files.len())];
+    for (size, path) in sized {
+        if let Some(batch) = batches.iter_mut().min_by_key(|(_, total)| *total) {
+            batch.0.push(path.clone());
+            batch.1 += size;
+        }
+    }
+    batches
+}
+
+// Updates the progress bar until the process exits, run on a thread of its own so it never takes a rayon thread
+#[allow(clippy::too_many_arguments)]
+fn start_progress_updater(
+    start_time: Instant,
+    global_to_be_processed_size: &Arc<AtomicU64>,
+    pb: ProgressBar,
+    config: &Config,
+    global_decompressed_size: &Arc<AtomicUsize>,
+    global_decompressed_lines: &Arc<AtomicUsize>,
+    global_filtered_lines: &Arc<AtomicUsize>,
+    global_processed_size: &Arc<AtomicU64>,
+    global_in_progress_size: &Arc<InProgressSize>,
+) {
+    let mut sys = System::new_all();
+    let summary_interval = parse_interval(&config.summary_interval).unwrap_or_default();
+    let mut last_summary = Instant::now();
+    loop {
+        let elapsed = start_time.elapsed().as_secs_f64();
+        let global_decompressed_size = global_decompressed_size.load(Ordering::Relaxed);
+        let global_filtered_lines = global_filtered_lines.load(Ordering::Relaxed);
+        let global_decompressed_lines = global_decompressed_lines.load(Ordering::Relaxed);
+        let global_processed_size = global_processed_size.load(Ordering::Relaxed);
+        let slowest_file = global_in_progress_size.slowest();
+        let global_in_progress_size = global_in_progress_size.total.load(Ordering::Relaxed);
+        let global_to_be_processed_size = global_to_be_processed_size.load(Ordering::Relaxed);
+
+        let global_to_be_processed_size_string =
+            format!("{}", HumanBytes(global_to_be_processed_size));
+        let global_to_be_processed_size_string_len =
+            global_to_be_processed_size_string.chars().count();
+        let line_ratio = percentage(
+            global_filtered_lines as f64,
+            global_decompressed_lines as f64,
+        );
+        let line_ratio_string = format!("{:.4}%", line_ratio);
+
+        sys.refresh_all();
+        std::thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+        // Minimal containers and some CI runners do not expose the process, only its stats are left out then
+        let process = sysinfo::get_current_pid()
+            .ok()
+            .and_then(|pid| sys.process(pid));
+        let unavailable = || "—".to_string();
+
+        // Fetch CPU, memory, and I/O stats
+        let cpu_usage_string = match process {
+            Some(process) => {
+                let cpu_usage = process.cpu_usage() / sys.cpus().len().max(1) as f32;
+                let mut cpu_usage_string = cpu_usage.to_string();
+                if cpu_usage < 10.0 {
+                    cpu_usage_string.insert(0, ' ');
+                };
+                format!("{:.5}%", cpu_usage_string)
+            }
+            None => unavailable(),
+        };
+        let cpu_usage_string_len = cpu_usage_string.chars().count();
+
+        let memory_usage = process.map_or_else(unavailable, |process| {
+            format!("{}", HumanBytes(process.memory()))
+        });
+        let memory_usage_len = memory_usage.chars().count();
+
+        let disk_usage = process.map(|process| process.disk_usage());
+        let disk_usage_reads_string = disk_usage.map_or_else(unavailable, |disk_usage| {
+            format!("{}/s", HumanBytes(disk_usage.read_bytes))
+        });
+        let disk_total_reads_string_len = disk_usage_reads_string.chars().count();
+        let disk_usage_writes_string = disk_usage.map_or_else(unavailable, |disk_usage| {
+            format!("{}/s", HumanBytes(disk_usage.written_bytes))
+        });
+        let disk_usage_writes_string_len = disk_usage_writes_string.chars().count();
+
+        // Finished files plus what has been read of the files that are still in progress
+        // A file is briefly counted twice when it finishes and skipped files shrink the total, so keep it in range
+        let processed_size_estimate =
+            (global_processed_size + global_in_progress_size).min(global_to_be_processed_size);
+
+        let avg_speed = per_second(global_decompressed_size as f64, elapsed);
+        let line_speed = format!(
+            "{:.0} lines/s",
+            per_second(global_decompressed_lines as f64, elapsed)
+        );
+        let line_speed_len = line_speed.chars().count();
+        let remaining_compressed_data =
+            global_to_be_processed_size.saturating_sub(processed_size_estimate);
+
+        // Based on the average rate the compressed input has been read at, nothing read yet means no estimate
+        let compressed_speed = per_second(processed_size_estimate as f64, elapsed);
+        let remaining_time = if compressed_speed >= 1.0 {
+            let seconds = remaining_compressed_data as f64 / compressed_speed;
+            format!("{}", HumanDuration(Duration::from_secs_f64(seconds)))
+        } else {
+            "—".to_string()
+        };
+        // Shown next to the estimate for the whole run, which a single huge file can outlast
+        let slowest_file_string = match &slowest_file {
+            Some((name, remaining)) => format!(", {} done in {}", name, HumanDuration(*remaining)),
+            None => String::new(),
+        };
+
+        let remaining_percentage_string = {
+            if global_to_be_processed_size == 0 {
+                "0%".to_string()
+            } else {
+                let percentage = percentage(
+                    processed_size_estimate as f64,
+                    global_to_be_processed_size as f64,
+                )
+                .clamp(0.0, 100.0);
+                format!("{:.2}%", percentage)
+            }
+        };
+        let remaining_percentage_string_len = remaining_percentage_string.chars().count();
+        let processed_size_estimate_string = format!("{}", HumanBytes(processed_size_estimate));
+        let processed_size_estimate_string_len = processed_size_estimate_string.chars().count();
+
+        let terminal_size = match terminal_size() {
+            Some((Width(w), _)) => w as usize,
+            None => 40,
+        };
+        // Sections that do not fit in the rest of the line start on a new one, a zero width wraps every section
+        fn print_pb_divider(bar_width: usize, mut position: usize, size: usize) -> &'static str {
+            if bar_width == 0 {
+                return "\n";
+            }
+            position %= bar_width;
+            if position + size > bar_width {
+                "\n"
+            } else {
+                " | "
+            }
+        }
+
+        let too_long = cpu_usage_string_len
+            + memory_usage_len
+            + line_speed_len
+            + processed_size_estimate_string_len
+            + global_to_be_processed_size_string_len
+            + remaining_percentage_string_len
+            + 43;
+
+        if config.progress_style == "compact" {
+            pb.set_message(format!(
+                "| {} | {}/s | {} remaining",
+                remaining_percentage_string,
+                HumanBytes(avg_speed as u64),
+                remaining_time
+            ));
+        } else {
+            pb.set_message(format!(
+                "({} remaining{})\nCPU: {}{}Memory: {}{}Speed: {}{}Progress: {}/{} ({}){}I/O Reads: {} | I/O Writes: {}\nDecompressed: {} ({})\nKept/Total Lines: {}/{} ({})",
+                remaining_time,
+                slowest_file_string,
+                cpu_usage_string.bright_blue(),
+
+                print_pb_divider(terminal_size, cpu_usage_string_len + 5, memory_usage_len + 11),
+                memory_usage.bright_blue(),
+
+                print_pb_divider(terminal_size, cpu_usage_string_len + memory_usage_len + 16, line_speed_len + 10),
+                line_speed.bright_blue(),
+
+                print_pb_divider(terminal_size, cpu_usage_string_len + memory_usage_len + line_speed_len + 26, processed_size_estimate_string_len + global_to_be_processed_size_string_len + remaining_percentage_string_len + 17),
+                processed_size_estimate_string,
+                global_to_be_processed_size_string,
+                remaining_percentage_string.bright_blue(),
+
+                print_pb_divider(terminal_size, too_long, disk_total_reads_string_len + disk_usage_writes_string_len + 29),
+                disk_usage_reads_string.bright_blue(),
+                disk_usage_writes_string.bright_blue(),
+
+                HumanBytes(global_decompressed_size as u64),
+                format!("{}/s", HumanBytes(avg_speed as u64)).bright_blue(),
+                HumanCount(global_filtered_lines as u64),
+                HumanCount(global_decompressed_lines as u64),
+                line_ratio_string.bright_blue()
+            ));
+        }
+
+        // Persist a plain snapshot for checking on the run without the terminal, or print it when asked by SIGUSR1
+        let snapshot_requested = SNAPSHOT_REQUESTED.swap(false, Ordering::Relaxed);
+        // A heartbeat for logs of runs without a terminal, where the bar is not drawn
+        let summary_due = !summary_interval.is_zero()
+            && !config.quiet
+            && last_summary.elapsed() >= summary_interval;
+        if !config.progress_log.is_empty() || snapshot_requested || summary_due {
+            let mut snapshot = format!(
+                "{} elapsed={:.0}s progress={}/{} ({}) decompressed={} ({}/s) lines={}/{} ({}) remaining={}",
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                elapsed,
+                processed_size_estimate_string,
+                global_to_be_processed_size_string,
+                remaining_percentage_string,
+                HumanBytes(global_decompressed_size as u64),
+                HumanBytes(avg_speed as u64),
+                global_filtered_lines,
+                global_decompressed_lines,
+                line_ratio_string,
+                remaining_time
+            );
+            if let Some((name, remaining)) = &slowest_file {
+                snapshot += &format!(" slowest={} ({:.0}s)", name, remaining.as_secs_f64());
+            }
+            if snapshot_requested {
+                pb.suspend(|| {
+                    eprintln!(
+                        "{} cpu={} memory={} reads={} writes={}",
+                        snapshot,
+                        cpu_usage_string.trim(),
+                        memory_usage,
+                        disk_usage_reads_string,
+                        disk_usage_writes_string
+                    )
+                });
+            }
+            if summary_due {
+                last_summary = Instant::now();
+                pb.suspend(|| println!("{}", snapshot));
+            }
+            if !config.progress_log.is_empty() {
+                if let Err(e) = append_progress_log(&config.progress_log, &snapshot) {
+                    pb.suspend(|| eprintln!("Failed to write progress log: {}", e));
+                }
+            }
+        }
+
+        // Exit the updater if the progress bar is finished
+        if pb.is_finished() {
+            break;
+        }
+
+        // Update every PB_UPDATE_INTERVAL, a SIGUSR1 cuts the wait short
+        let next_update = Instant::now() + Duration::from_millis(PB_UPDATE_INTERVAL);
+        while Instant::now() < next_update && !SNAPSHOT_REQUESTED.load(Ordering::Relaxed) {
+            std::thread::sleep(Duration::from_millis(SNAPSHOT_POLL_INTERVAL));
+        }
+    }
+}
+
+// Without a handler SIGUSR1 terminates the process, so it is only installed with --progress-on-signal
+#[cfg(unix)]
+fn install_snapshot_signal() {
+    extern "C" fn request_snapshot(_: libc::c_int) {
+        // Only an atomic store, which is safe to do in a signal handler
+        SNAPSHOT_REQUESTED.store(true, Ordering::Relaxed);
+    }
+    let handler: extern "C" fn(libc::c_int) = request_snapshot;
+    unsafe {
+        libc::signal(libc::SIGUSR1, handler as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+fn install_snapshot_signal() {}
+
+// Append a line to the progress log, the previous log is kept as <path>.1 once it gets too large
+fn append_progress_log(path: &str, snapshot: &str) -> std::io::Result<()> {
+    if let Ok(metadata) = fs::metadata(path) {
+        if metadata.len() > PROGRESS_LOG_MAX_SIZE {
+            fs::rename(path, format!("{}.1", path))?;
+        }
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", snapshot)
+}
+
+// A --summary-interval like 30s, 5m or 1h, plain numbers are seconds
+fn parse_interval(interval: &str) -> Option<Duration> {
+    let (number, unit) = match interval.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => interval.split_at(index),
+        None => (interval, "s"),
+    };
+    let number: u64 = number.parse().ok()?;
+    let seconds = match unit {
+        "s" => number,
+        "m" => number * 60,
+        "h" => number * 3600,
+        _ => return None,
+    };
+    Some(Duration::from_secs(seconds))
+}
+
+// Share of a part in percent, computed in f64 so huge counts can not overflow, 0 for an empty total
+fn percentage(part: f64, total: f64) -> f64 {
+    if total > 0.0 {
+        part / total * 100.0
+    } else {
+        0.0
+    }
+}
+
+// Rate of an amount over a duration, 0 if no time has passed yet
+fn per_second(amount: f64, seconds: f64) -> f64 {
+    if seconds > 0.0 {
+        amount / seconds
+    } else {
+        0.0
+    }
+}
+
+// Machine-readable record of a run, written to summary.json in the output directory
+#[derive(Serialize)]
+struct RunSummary<'a> {
+    config: &'a Config,
+    files_found: usize,
+    invalid_inputs: &'a [String],
+    files_processed: usize,
+    files_skipped: usize,
+    files_failed: usize,
+    total_lines: usize,
+    matched_lines: usize,
+    // Same counts as the --type and --since/--until entries of dropped_lines, kept for existing readers
+    // They are part of dropped_lines, not lines dropped in addition to it
+    type_mismatched_lines: usize,
+    time_excluded_lines: usize,
+    // Lines dropped by each enabled filter in the order they are checked
+    dropped_lines: serde_json::Map<String, serde_json::Value>,
+    duplicate_lines: usize,
+    ratio_outliers: Vec<RatioOutlier>,
+    compressed_size: u64,
+    decompressed_size: usize,
+    duration_seconds: f64,
+}
+
+/// Command line argument structure
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Rust Configuration Demo", long_about = None)]
+struct Cli {
+    #[arg(long = "input")]
+    input: Vec<String>,
+    #[arg(long = "retry-from")]
+    retry_from: Option<String>,
+    #[arg(long = "skip-invalid-inputs")]
+    skip_invalid_inputs: bool,
+    #[arg(long = "follow-symlinks")]
+    follow_symlinks: bool,
+    #[arg(long = "include-output-in-scan")]
+    include_output_in_scan: bool,
+    #[arg(long = "max-depth")]
+    max_depth: Option<usize>,
+    #[arg(long = "skip-corrupt-frames")]
+    skip_corrupt_frames: bool,
+    #[arg(long = "extract-metadata")]
+    extract_metadata: bool,
+    #[arg(long = "no-magic-check")]
+    no_magic_check: bool,
+    #[arg(long = "window-log-max")]
+    window_log_max: Option<u32>,
+    #[arg(long = "min-decompressed-size")]
+    min_decompressed_size: Option<u64>,
+    #[arg(long = "max-decompressed-size")]
+    max_decompressed_size: Option<u64>,
+    #[arg(long = "newer-than")]
+    newer_than: Option<String>,
+    #[arg(long = "input-extension")]
+    input_extensions: Vec<String>,
+    #[arg(long = "output")]
+    output: Option<String>,
+    #[arg(long = "output-for")]
+    output_for: Vec<String>,
+    #[arg(long = "zstd")]
+    zstd: bool,
+    #[arg(long = "compression-level", allow_negative_numbers = true)]
+    compression_level: Option<i32>,
+    #[arg(long = "recompress-level", allow_negative_numbers = true)]
+    recompress_level: Option<i32>,
+    #[arg(long = "match-input-params")]
+    match_input_params: bool,
+    #[arg(long = "no-recompress")]
+    no_recompress: bool,
+    #[arg(long = "include-content-size")]
+    include_content_size: bool,
+    #[arg(long = "merge-output")]
+    merge_output: Option<String>,
+    #[arg(long = "sqlite")]
+    sqlite: Option<String>,
+    #[arg(long = "append")]
+    append: bool,
+    #[arg(long = "ordered")]
+    ordered: bool,
+    #[arg(long = "global-line-numbers")]
+    global_line_numbers: bool,
+    #[arg(long = "schedule")]
+    schedule: Option<String>,
+    #[arg(long = "limit-files")]
+    limit_files: Option<usize>,
+    #[arg(long = "batch")]
+    batch: Option<usize>,
+    #[arg(long = "shard-by-thread")]
+    shard_by_thread: bool,
+    #[arg(long = "suffix")]
+    suffix: Option<String>,
+    #[arg(long = "tag")]
+    tags: Vec<String>,
+    #[arg(long = "file-extension")]
+    file_extension: Option<String>,
+    #[arg(long = "strip-inner-extension")]
+    strip_inner_extension: bool,
+    #[arg(long = "clean-stale-outputs")]
+    clean_stale_outputs: bool,
+    #[arg(long = "pattern")]
+    pattern: Option<String>,
+    #[arg(long = "scan-prefix")]
+    scan_prefix: Option<usize>,
+    #[arg(long = "extract")]
+    extract: bool,
+    #[arg(long = "max-line-matches")]
+    max_line_matches: Option<usize>,
+    #[arg(long = "has-key")]
+    has_key: Option<String>,
+    #[arg(long = "has-key-depth")]
+    has_key_depth: Option<usize>,
+    #[arg(long = "type")]
+    types: Vec<String>,
+    #[arg(long = "fuzzy")]
+    fuzzy: Vec<String>,
+    #[arg(long = "max-distance")]
+    max_distance: Option<usize>,
+    #[arg(long = "concat-fields")]
+    concat_fields: Option<String>,
+    #[arg(long = "time-field")]
+    time_field: Option<String>,
+    #[arg(long = "since")]
+    since: Option<String>,
+    #[arg(long = "until")]
+    until: Option<String>,
+    #[arg(long = "unique-by")]
+    unique_by: Option<String>,
+    #[arg(long = "unique-keep")]
+    unique_keep: Option<String>,
+    #[arg(long = "unique-global")]
+    unique_global: bool,
+    #[arg(long = "unique-max-keys")]
+    unique_max_keys: Option<usize>,
+    #[arg(long = "distinct")]
+    distinct: Option<String>,
+    #[arg(long = "distinct-max-values")]
+    distinct_max_values: Option<usize>,
+    #[arg(long = "group-by")]
+    group_by: Option<String>,
+    #[arg(long = "group-max-records")]
+    group_max_records: Option<usize>,
+    #[arg(long = "tail-lines")]
+    tail_lines: Option<usize>,
+    #[arg(long = "sort-by")]
+    sort_by: Option<String>,
+    #[arg(long = "sort-desc")]
+    sort_desc: bool,
+    #[arg(long = "sort-max-lines")]
+    sort_max_lines: Option<usize>,
+    #[arg(long = "record-buffer-bytes")]
+    record_buffer_bytes: Option<usize>,
+    #[arg(long = "route")]
+    routes: Vec<String>,
+    #[arg(long = "route-mode")]
+    route_mode: Option<String>,
+    #[arg(long = "bucket-by-match-count")]
+    bucket_by_match_count: bool,
+    #[arg(long = "partition-by-time")]
+    partition_by_time: Option<String>,
+    #[arg(long = "partition-utc-offset")]
+    partition_utc_offset: Option<String>,
+    #[arg(long = "strict-jsonl")]
+    strict_jsonl: bool,
+    #[arg(long = "strict-jsonl-policy")]
+    strict_jsonl_policy: Option<String>,
+    #[arg(long = "max-line-bytes-hard")]
+    max_line_bytes_hard: Option<usize>,
+    #[arg(long = "max-line-bytes-policy")]
+    max_line_bytes_policy: Option<String>,
+    #[arg(long = "explain")]
+    explain: bool,
+    #[arg(long = "explain-lines")]
+    explain_lines: Option<usize>,
+    #[arg(long = "warn-if-ratio-below")]
+    warn_if_ratio_below: Option<f64>,
+    #[arg(long = "warn-if-ratio-above")]
+    warn_if_ratio_above: Option<f64>,
+    #[arg(long = "filter-expr")]
+    filter_expr: Option<String>,
+    #[arg(long = "allow-field")]
+    allow_field: Option<String>,
+    #[arg(long = "allow-file")]
+    allow_file: Option<String>,
+    #[arg(long = "bloom-file")]
+    bloom_file: Option<String>,
+    #[arg(long = "build-bloom")]
+    build_bloom: Option<String>,
+    #[arg(long = "verify-manifest")]
+    verify_manifest: Option<String>,
+    #[arg(long = "bloom-fpr")]
+    bloom_fpr: Option<f64>,
+    #[arg(long = "contains")]
+    contains: Vec<String>,
+    #[arg(long = "contains-file")]
+    contains_file: Option<String>,
+    #[arg(long = "ignore-case")]
+    ignore_case: bool,
+    #[arg(long = "threads")]
+    threads: Option<usize>,
+    #[arg(long = "max-open-files")]
+    max_open_files: Option<usize>,
+    #[arg(long = "buffer")]
+    buffer: Option<usize>,
+    #[arg(long = "read-buffer-size")]
+    read_buffer_size: Option<usize>,
+    #[arg(long = "max-read-rate")]
+    max_read_rate: Option<u64>,
+    #[arg(long = "max-write-rate")]
+    max_write_rate: Option<u64>,
+    #[arg(long = "flush-interval")]
+    flush_interval: Option<usize>,
+    #[arg(long = "flush-ms")]
+    flush_ms: Option<u64>,
+    #[arg(long = "on-complete")]
+    on_complete: Option<String>,
+    #[arg(long = "on-complete-strict")]
+    on_complete_strict: bool,
+    #[arg(long = "output-delimiter")]
+    output_delimiter: Option<String>,
+    #[arg(long = "pretty")]
+    pretty: bool,
+    #[arg(long = "canonicalize-json")]
+    canonicalize_json: bool,
+    #[arg(long = "json-array")]
+    json_array: bool,
+    #[arg(long = "line-numbers")]
+    line_numbers: bool,
+    #[arg(long = "with-filename")]
+    with_filename: bool,
+    #[arg(long = "prefix-separator")]
+    prefix_separator: Option<String>,
+    #[arg(long = "add-source-field")]
+    add_source_field: Option<String>,
+    #[arg(long = "source-value")]
+    source_value: Option<String>,
+    #[arg(long = "verify-output")]
+    verify_output: bool,
+    #[arg(long = "resume-by-hash")]
+    resume_by_hash: bool,
+    #[arg(long = "max-output-bytes")]
+    max_output_bytes: Option<u64>,
+    #[arg(long = "max-output-bytes-compressed")]
+    max_output_bytes_compressed: bool,
+    #[arg(long = "on-disk-full")]
+    on_disk_full: Option<String>,
+    #[arg(long = "no-write")]
+    no_write: bool,
+    #[arg(long = "count-only")]
+    count_only: bool,
+    #[arg(long = "deep-verify")]
+    deep_verify: bool,
+    #[arg(long = "decompress-only")]
+    decompress_only: bool,
+    #[arg(long = "quiet")]
+    quiet: bool,
+    #[arg(long = "color")]
+    color: Option<String>,
+    #[arg(long = "file-progress")]
+    file_progress: bool,
+    #[arg(long = "progress-log")]
+    progress_log: Option<String>,
+    #[arg(long = "progress-on-signal")]
+    progress_on_signal: bool,
+    #[arg(long = "summary-interval")]
+    summary_interval: Option<String>,
+    #[arg(long = "progress-style")]
+    progress_style: Option<String>,
+    #[arg(long = "no-summary-file")]
+    no_summary_file: bool,
+    #[arg(long = "config", default_value = "config.toml")]
+    config: String,
+    #[arg(long = "profile")]
+    profile: Option<String>,
+    #[arg(long = "generate-completions", hide = true)]
+    generate_completions: Option<clap_complete::Shell>,
+    #[arg(long = "selftest", hide = true)]
+    selftest: bool,
+}
+
+// Internal and config.toml structure
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Config {
+    #[serde(deserialize_with = "one_or_many")]
+    input: Vec<String>,
+    #[serde(default)]
+    skip_invalid_inputs: bool,
+    #[serde(default)]
+    follow_symlinks: bool,
+    #[serde(default)]
+    include_output_in_scan: bool,
+    #[serde(default)]
+    max_depth: Option<usize>,
+    #[serde(default)]
+    skip_corrupt_frames: bool,
+    #[serde(default)]
+    extract_metadata: bool,
+    #[serde(default)]
+    no_magic_check: bool,
+    #[serde(default)]
+    window_log_max: u32,
+    #[serde(default)]
+    min_decompressed_size: u64,
+    #[serde(default)]
+    max_decompressed_size: u64,
+    #[serde(default)]
+    newer_than: String,
+    #[serde(default)]
+    input_extensions: Vec<String>,
+    output: String,
+    #[serde(default)]
+    output_for: Vec<String>,
+    zstd: bool,
+    compression_level: i32,
+    #[serde(default)]
+    recompress_level: i32,
+    #[serde(default)]
+    match_input_params: bool,
+    #[serde(default)]
+    no_recompress: bool,
+    #[serde(default)]
+    include_content_size: bool,
+    #[serde(default)]
+    merge_output: String,
+    #[serde(default)]
+    sqlite: String,
+    #[serde(default)]
+    append: bool,
+    #[serde(default)]
+    ordered: bool,
+    #[serde(default)]
+    global_line_numbers: bool,
+    #[serde(default)]
+    schedule: String,
+    #[serde(default)]
+    limit_files: usize,
+    #[serde(default)]
+    batch: usize,
+    #[serde(default)]
+    shard_by_thread: bool,
+    suffix: String,
+    #[serde(
+        default,
+        serialize_with = "tags_as_table",
+        deserialize_with = "tags_from_table"
+    )]
+    tags: Vec<(String, String)>,
+    file_extension: String,
+    #[serde(default)]
+    strip_inner_extension: bool,
+    #[serde(default)]
+    clean_stale_outputs: bool,
+    pattern: String,
+    #[serde(default)]
+    scan_prefix: usize,
+    #[serde(default)]
+    extract: bool,
+    #[serde(default)]
+    max_line_matches: usize,
+    #[serde(default)]
+    has_key: String,
+    #[serde(default)]
+    has_key_depth: usize,
+    #[serde(default)]
+    types: Vec<String>,
+    #[serde(default)]
+    fuzzy: Vec<String>,
+    #[serde(default = "default_max_distance")]
+    max_distance: usize,
+    #[serde(default)]
+    concat_fields: String,
+    #[serde(default)]
+    time_field: String,
+    #[serde(default)]
+    since: String,
+    #[serde(default)]
+    until: String,
+    #[serde(default)]
+    unique_by: String,
+    #[serde(default)]
+    unique_keep: String,
+    #[serde(default)]
+    unique_global: bool,
+    #[serde(default)]
+    unique_max_keys: usize,
+    #[serde(default)]
+    distinct: String,
+    #[serde(default = "default_distinct_max_values")]
+    distinct_max_values: usize,
+    #[serde(default)]
+    group_by: String,
+    #[serde(default = "default_group_max_records")]
+    group_max_records: usize,
+    #[serde(default)]
+    tail_lines: usize,
+    #[serde(default)]
+    sort_by: String,
+    #[serde(default)]
+    sort_desc: bool,
+    #[serde(default = "default_sort_max_lines")]
+    sort_max_lines: usize,
+    #[serde(default)]
+    record_buffer_bytes: usize,
+    #[serde(default)]
+    routes: Vec<String>,
+    #[serde(default)]
+    route_mode: String,
+    #[serde(default)]
+    bucket_by_match_count: bool,
+    #[serde(default)]
+    partition_by_time: String,
+    #[serde(default)]
+    partition_utc_offset: String,
+    #[serde(default)]
+    strict_jsonl: bool,
+    #[serde(default)]
+    strict_jsonl_policy: String,
+    #[serde(default)]
+    max_line_bytes_hard: usize,
+    #[serde(default)]
+    max_line_bytes_policy: String,
+    #[serde(default)]
+    explain: bool,
+    #[serde(default = "default_explain_lines")]
+    explain_lines: usize,
+    #[serde(default)]
+    warn_if_ratio_below: f64,
+    #[serde(default = "default_warn_if_ratio_above")]
+    warn_if_ratio_above: f64,
+    #[serde(default)]
+    filter_expr: String,
+    #[serde(default)]
+    allow_field: String,
+    #[serde(default)]
+    allow_file: String,
+    #[serde(default)]
+    bloom_file: String,
+    #[serde(default)]
+    contains: Vec<String>,
+    #[serde(default)]
+    contains_file: String,
+    #[serde(default)]
+    ignore_case: bool,
+    threads: usize,
+    #[serde(default)]
+    max_open_files: usize,
+    buffer: usize,
+    #[serde(default)]
+    read_buffer_size: usize,
+    #[serde(default)]
+    max_read_rate: u64,
+    #[serde(default)]
+    max_write_rate: u64,
+    #[serde(default)]
+    flush_interval: usize,
+    #[serde(default)]
+    flush_ms: u64,
+    #[serde(default)]
+    on_complete: String,
+    #[serde(default)]
+    on_complete_strict: bool,
+    #[serde(default)]
+    output_delimiter: String,
+    #[serde(default)]
+    pretty: bool,
+    #[serde(default)]
+    canonicalize_json: bool,
+    #[serde(default)]
+    json_array: bool,
+    #[serde(default)]
+    line_numbers: bool,
+    #[serde(default)]
+    with_filename: bool,
+    #[serde(default)]
+    prefix_separator: String,
+    #[serde(default)]
+    add_source_field: String,
+    #[serde(default)]
+    source_value: String,
+    #[serde(default)]
+    verify_output: bool,
+    #[serde(default)]
+    resume_by_hash: bool,
+    #[serde(default)]
+    max_output_bytes: u64,
+    #[serde(default)]
+    max_output_bytes_compressed: bool,
+    #[serde(default = "default_on_disk_full")]
+    on_disk_full: String,
+    no_write: bool,
+    #[serde(default)]
+    count_only: bool,
+    #[serde(default)]
+    deep_verify: bool,
+    #[serde(default)]
+    decompress_only: bool,
+    quiet: bool,
+    #[serde(default)]
+    color: String,
+    #[serde(default)]
+    file_progress: bool,
+    #[serde(default)]
+    progress_log: String,
+    #[serde(default)]
+    progress_on_signal: bool,
+    #[serde(default)]
+    summary_interval: String,
+    #[serde(default)]
+    progress_style: String,
+    #[serde(default)]
+    no_summary_file: bool,
+}
+
+fn default_max_distance() -> usize {
+    2
+}
+
+fn default_distinct_max_values() -> usize {
+    1_000_000
+}
+
+fn default_group_max_records() -> usize {
+    1_000_000
+}
+
+fn default_sort_max_lines() -> usize {
+    1_000_000
+}
+
+fn default_explain_lines() -> usize {
+    100
+}
+
+fn default_warn_if_ratio_above() -> f64 {
+    100.0
+}
+
+fn default_on_disk_full() -> String {
+    String::from("skip")
+}
+
+// Turns the escapes \n, \r, \t, \0 and \\ of --output-delimiter into the characters they stand for
+fn unescape_delimiter(delimiter: &str) -> String {
+    let mut unescaped = String::with_capacity(delimiter.len());
+    let mut chars = delimiter.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('t') => unescaped.push('\t'),
+            Some('0') => unescaped.push('\0'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+// `input` in config.toml can be a single path or a list of paths
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(path) => vec![path],
+        OneOrMany::Many(paths) => paths,
+    })
+}
+
+// `tags` in config.toml and summary.json is a table like `tags = { run = "nightly", dataset = "v3" }`
+fn tags_as_table<S: Serializer>(
+    tags: &[(String, String)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(tags.iter().map(|(key, value)| (key, value)))
+}
+
+fn tags_from_table<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<(String, String)>, D::Error> {
+    let table = toml::Table::deserialize(deserializer)?;
+    table
+        .into_iter()
+        .map(|(key, value)| match value {
+            toml::Value::String(value) => Ok((key, value)),
+            other => Ok((key, other.to_string())),
+        })
+        .collect()
+}
+
+// Replaces the {key} placeholders of --suffix with the value of the --tag with that key
+fn expand_tags(template: &str, tags: &[(String, String)]) -> Result<String, String> {
+    let placeholder = Regex::new(r"\{([A-Za-z0-9_-]+)\}").unwrap();
+    let mut missing = None;
+    let expanded = placeholder.replace_all(template, |captures: &regex::Captures| {
+        let key = &captures[1];
+        match tags.iter().find(|(tag, _)| tag == key) {
+            Some((_, value)) => value.clone(),
+            None => {
+                missing.get_or_insert_with(|| key.to_string());
+                captures[0].to_string()
+            }
+        }
+    });
+    match missing {
+        Some(key) => Err(format!(
+            "{{{}}} is used but no --tag {}=<value> was given",
+            key, key
+        )),
+        None => Ok(expanded.into_owned()),
+    }
+}
+
+fn validate_regex(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| format!("Invalid regex: {}", e))
+}
+
+/// Check --quiet before printing
+fn print_if_not_quiet(quiet: bool, message: &str) {
+    if !quiet {
+        println!("{}", message);
+    }
+}
+
+// Keys of the selected [profiles.<name>] table override the top level keys of config.toml
+// Without --profile a profile named "default" is used if there is one
+// A config that does not parse is ignored like before, only an unknown profile is an error
+fn parse_config(content: &str, profile: Option<&str>) -> Result<Option<Config>, String> {
+    let Ok(mut table) = content.parse::<toml::Table>() else {
+        return Ok(None);
+    };
+    let profiles = match table.remove("profiles") {
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => return Err("profiles in the config file must be a table".to_string()),
+        None => toml::Table::new(),
+    };
+    let selected = match profile {
+        Some(name) => match profiles.get(name) {
+            Some(selected) => Some(selected),
+            None => {
+                let mut names: Vec<&str> = profiles.keys().map(|k| k.as_str()).collect();
+                names.sort_unstable();
+                return Err(format!(
+                    "Profile {} not found in the config file, available profiles: {}",
+                    name,
+                    if names.is_empty() {
+                        "none".to_string()
+                    } else {
+                        names.join(", ")
+                    }
+                ));
+            }
+        },
+        None => profiles.get("default"),
+    };
+    if let Some(selected) = selected {
+        let Some(selected) = selected.as_table() else {
+            return Err("Every profile in the config file must be a table".to_string());
+        };
+        for (key, value) in selected {
+            table.insert(key.clone(), value.clone());
+        }
+    }
+    Ok(toml::Value::Table(table).try_into().ok())
+}
+
+fn set_config(cli: Cli) -> Config {
+    // Fallback values if no config file was found
+    let fallback_input = String::from("./"); // directory where to search for zstd files
+    let fallback_skip_invalid_inputs = false; // warn about and skip input paths that do not exist instead of exiting
+    let fallback_follow_symlinks = false; // include symlinks to regular files in input directories
+    let fallback_include_output_in_scan = false; // let recursive input patterns pick up files in the output directories
+    let fallback_max_depth: Option<usize> = None; // how many directories the ** of an input pattern descends, 0 means only the top level, unset means no limit
+    let fallback_skip_corrupt_frames = false; // continue with the next zstd frame after a decoding error
+    let fallback_extract_metadata = false; // write the content of skippable zstd frames to a sidecar file per input
+    let fallback_no_magic_check = false; // decode inputs that do not start with the zstd magic number
+    let fallback_window_log_max = 0; // largest window in log2 bytes the decoder accepts, 0 means the zstd default of 27
+    let fallback_newer_than = String::from(""); // skip files modified before this time or the last run, empty means disabled
+    let fallback_min_decompressed_size = 0; // skip files whose frame header records a smaller decompressed size
+    let fallback_max_decompressed_size = 0; // skip files whose frame header records a larger decompressed size, 0 means no limit
+    let fallback_input_extensions = vec![String::from("zst")]; // file extensions to look for in the input directory
+    let fallback_output = String::from("./"); // directory where to write files to
+    let fallback_output_for: Vec<String> = Vec::new(); // <glob>=<dir> to write the outputs of matching inputs to another directory
+    let fallback_zstd = false; // by default extract everything
+    let fallback_compression_level = 0; // zstd compression level between 1-22 or negative for faster levels, 0 means the default of 3
+    let fallback_recompress_level = 0; // compression level of --decompress-only --zstd runs, 0 means --compression-level
+    let fallback_match_input_params = false; // compress every output with the window read from the frame header of its input
+    let fallback_no_recompress = false; // copy the compressed input as it is when every line would be kept unchanged
+    let fallback_include_content_size = false; // record the decompressed size in the header of compressed outputs
+    let fallback_merge_output = String::from(""); // write all matching lines into this single file, empty means one output file per input
+    let fallback_sqlite = String::from(""); // write all matching lines into this sqlite database, empty means disabled
+    let fallback_append = false; // append to an existing merged output instead of refusing to overwrite it
+    let fallback_ordered = false; // process files one after another for a reproducible output order
+    let fallback_global_line_numbers = false; // number the lines of all inputs as one stream in name order
+    let fallback_schedule = String::from("size-desc"); // order in which files are handed to the threads: size-desc, size-asc or name
+    let fallback_limit_files = 0; // only process the first files in schedule order, 0 means all files
+    let fallback_batch = 0; // number of size-balanced batches processed one after another, 0 means a single batch
+    let fallback_shard_by_thread = false; // split the merged output into one file per thread
+    let fallback_suffix = String::from("_filtered"); // suffix for your output file
+    let fallback_file_extension = String::from(""); // file extension for your output file
+    let fallback_strip_inner_extension = false; // drop the inner extension like .jsonl from output file names
+    let fallback_clean_stale_outputs = false; // delete outputs of the inputs that were written with a different naming
+    let fallback_pattern = String::from(r#"^"#); // match everything
+    let fallback_scan_prefix = 0; // only apply the pattern to the first n bytes of a line, 0 means the whole line
+    let fallback_extract = false; // write the matches of the pattern instead of the whole line
+    let fallback_max_line_matches = 0; // extract at most n matches per line, 0 means no limit
+    let fallback_has_key = String::from(""); // only keep JSON lines containing this key, empty means disabled
+    let fallback_has_key_depth = 0; // how deep to search for has_key, 0 means no limit
+    let fallback_types = Vec::new(); // required JSON types per field as <path>=<type>
+    let fallback_fuzzy = Vec::new(); // JSON fields that have to be similar to a value as <path>=<value>
+    let fallback_max_distance = default_max_distance(); // the maximum edit distance for fuzzy
+    let fallback_concat_fields = String::from(""); // <path>,<path>,...=<separator> joined into the _concat field the JSON filters can use, empty means disabled
+    let fallback_time_field = String::from(""); // JSON field with the timestamp for since and until, empty means disabled
+    let fallback_since = String::from(""); // keep lines at or after this time, empty means no lower bound
+    let fallback_until = String::from(""); // keep lines before this time, empty means no upper bound
+    let fallback_unique_by = String::from(""); // only keep one line per distinct value of this JSON field, empty means disabled
+    let fallback_unique_keep = String::from("first"); // keep the first or last line per value
+    let fallback_unique_global = false; // deduplicate across all inputs instead of per input
+    let fallback_unique_max_keys = 0; // stop tracking new values after this many, 0 means no limit
+    let fallback_distinct = String::from(""); // JSON field to collect the distinct values of instead of writing lines
+    let fallback_distinct_max_values = default_distinct_max_values(); // stop collecting new values after this many, 0 means no limit
+    let fallback_group_by = String::from(""); // JSON field to group the matching lines by instead of writing them line by line
+    let fallback_group_max_records = default_group_max_records(); // most lines held in groups, 0 means no limit
+    let fallback_tail_lines = 0; // only keep the last n matching lines of every input, 0 means all
+    let fallback_sort_by = String::from(""); // JSON field to sort the output of every input by
+    let fallback_sort_desc = false; // sort in descending order
+    let fallback_sort_max_lines = default_sort_max_lines(); // most matching lines per input that are sorted in memory, 0 means no limit
+    let fallback_record_buffer_bytes = 0; // most bytes of matching lines held per input for tail_lines and sort_by, 0 means no limit
+    let fallback_routes: Vec<String> = Vec::new(); // write matching lines into a subdirectory per <name>=<regex> category they match
+    let fallback_route_mode = String::from("first"); // write a line to the first matching route only or to every matching route
+    let fallback_bucket_by_match_count = false; // write kept lines into a file per number of matching routes instead of a directory per route
+    let fallback_partition_by_time = String::from(""); // <path>:<granularity> of a timestamp to write kept lines into a file per year, month, day or hour, empty means disabled
+    let fallback_partition_utc_offset = String::from("+00:00"); // offset from UTC the time partitions are taken in
+    let fallback_strict_jsonl = false; // require every line to be valid JSON, independent of the filters
+    let fallback_strict_jsonl_policy = String::from("abort"); // what happens to an input with an invalid line, skip the line or fail the input
+    let fallback_max_line_bytes_hard = 0; // longest line in bytes that is read into memory, 0 means no limit
+    let fallback_max_line_bytes_policy = String::from("abort"); // what happens to an input with a longer line, skip the line or fail the input
+    let fallback_explain = false; // print why lines are kept or dropped to stderr
+    let fallback_explain_lines = default_explain_lines(); // lines across all inputs that --explain prints
+    let fallback_warn_if_ratio_below = 0.0; // warn about inputs with a smaller share of kept lines in percent, 0 means disabled
+    let fallback_warn_if_ratio_above = default_warn_if_ratio_above(); // warn about inputs with a larger share of kept lines in percent, 100 means disabled
+    let fallback_filter_expr = String::from(""); // rhai expression evaluated per JSON line, empty means disabled
+    let fallback_allow_field = String::from(""); // field whose value must be in the allow-list, empty means disabled
+    let fallback_allow_file = String::from(""); // file with one allowed value per line, held in memory exactly
+    let fallback_bloom_file = String::from(""); // bloom filter of allowed values built with --build-bloom
+    let fallback_contains = Vec::new(); // only keep lines containing one of these literal substrings
+    let fallback_contains_file = String::from(""); // file with one substring per line for contains
+    let fallback_ignore_case = false; // match pattern and contains case insensitive
+    let fallback_threads = 0; // max number of threads rayon spawn, 0 means no limit
+    let fallback_max_open_files = 0; // the maximum number of input and output files open at once, 0 means no limit
+    let fallback_buffer = 4096; // the buffer size after which data is written to disk, here: 4KiB
+    let fallback_read_buffer_size = 0; // bytes buffered when reading compressed input and decompressed lines, 0 means the defaults
+    let fallback_max_read_rate = 0; // most compressed bytes read per second by all threads together, 0 means no limit
+    let fallback_max_write_rate = 0; // most bytes written to disk per second by all threads together, 0 means no limit
+    let fallback_flush_interval = 0; // flush the output every n matching lines, 0 means only when the buffer is full
+    let fallback_flush_ms = 0; // flush the output once a matching line has waited this many ms, 0 means disabled
+    let fallback_on_complete = String::from(""); // command to run for every finished output file
+    let fallback_on_complete_strict = false; // count a file as failed if its --on-complete command fails
+    let fallback_output_delimiter = String::from("\\n"); // written after every output line, escapes like \0 or \r\n are allowed
+    let fallback_pretty = false; // write kept JSON lines indented
+    let fallback_canonicalize_json = false; // sort the keys of kept JSON lines and remove insignificant whitespace
+    let fallback_json_array = false; // write every output as a single JSON array instead of JSON lines
+    let fallback_line_numbers = false; // prefix every kept line with its line number in the input
+    let fallback_with_filename = false; // prefix every kept line with the input it comes from
+    let fallback_prefix_separator = String::from(":"); // written after the file name and line number prefixes, escapes like \t are allowed
+    let fallback_add_source_field = String::from(""); // name of a field added to every kept JSON object with its source, empty means disabled
+    let fallback_source_value = String::from(""); // value for add_source_field, empty means the input file
+    let fallback_verify_output = false; // re-read every output file after writing it
+    let fallback_resume_by_hash = false; // skip files whose content is listed in resume.manifest of the output directory
+    let fallback_max_output_bytes = 0; // stop once this many bytes have been written, 0 means no limit
+    let fallback_max_output_bytes_compressed = false; // count max_output_bytes after compression instead of before
+    let fallback_on_disk_full = default_on_disk_full(); // what happens once the output disk is full: skip the input, pause until there is space or abort the run
+    let fallback_no_write = false; // do not write to output
+    let fallback_count_only = false; // only count lines and bytes without filtering
+    let fallback_deep_verify = false; // decode every input completely to check its integrity, like count_only
+    let fallback_decompress_only = false; // write every line without filtering
+    let fallback_quiet = false;
+    let fallback_color = String::from("auto"); // colored messages: auto, always or never
+    let fallback_tags = Vec::new(); // key=value metadata of the run
+    let fallback_no_summary_file = false; // do not write summary.json to the output directory
+    let fallback_file_progress = false; // show a progress bar for every file being filtered
+    let fallback_progress_log = String::from(""); // file to append progress snapshots to, empty means disabled
+    let fallback_progress_on_signal = false; // print a progress snapshot to stderr on SIGUSR1
+    let fallback_summary_interval = String::from(""); // print a plain progress summary this often, e.g. 5m, empty means disabled
+    let fallback_progress_style = String::from("full"); // full progress with system usage or a compact single line
+
+    // Print a completion script and exit before anything else happens
+    if let Some(shell) = cli.generate_completions {
+        write_completions(shell, &mut std::io::stdout());
+        process::exit(0);
+    }
+
+    // Check the build end to end on a generated input, without any config or fixtures
+    if cli.selftest {
+        process::exit(if selftest::run() { 0 } else { 1 });
+    }
+
+    // Build a bloom file from a list of values instead of filtering
+    if let Some(list) = &cli.build_bloom {
+        let Some(bloom_file) = &cli.bloom_file else {
+            eprintln!("Error: --build-bloom needs --bloom-file to write the bloom filter to.");
+            process::exit(1);
+        };
+        let false_positive_rate = cli.bloom_fpr.unwrap_or(DEFAULT_BLOOM_FPR);
+        if !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+            eprintln!("Error: --bloom-fpr has to be between 0 and 1, e.g. 0.01 for 1%.");
+            process::exit(1);
+        }
+        match bloom::build(Path::new(list), Path::new(bloom_file), false_positive_rate) {
+            Ok((values, filter)) => {
+                println!(
+                    "Wrote {} values to {} ({}, {} hashes, {}% false positives)",
+                    HumanCount(values),
+                    bloom_file,
+                    HumanBytes(filter.size_in_bytes()),
+                    filter.hash_count(),
+                    false_positive_rate * 100.0
+                );
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // Check the outputs recorded by --resume-by-hash instead of filtering
+    if let Some(manifest) = &cli.verify_manifest {
+        match manifest::verify(Path::new(manifest)) {
+            Ok(verified) => process::exit(if verified { 0 } else { 1 }),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // Attempt to read the config file
+    let config: Option<Config> = if Path::new(&cli.config).exists() {
+        match fs::read_to_string(&cli.config) {
+            Ok(content) => match parse_config(&content, cli.profile.as_deref()) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+            },
+            Err(e) => {
+                eprintln!("Failed to read config file: {}", e);
+                process::exit(1);
+            }
+        }
+    } else if let Some(profile) = &cli.profile {
+        eprintln!(
+            "Error: --profile {} was given but the config file {} does not exist",
+            profile, cli.config
+        );
+        process::exit(1);
+    } else {
+        None
+    };
+
+    // Input paths, --retry-from takes them from the failed.list of an earlier run instead
+    let input = if let Some(list) = &cli.retry_from {
+        if !cli.input.is_empty() {
+            eprintln!("Error: --retry-from reads the inputs from {} and can not be combined with --input.", list);
+            process::exit(1);
+        }
+        let paths: Vec<String> = match fs::read_to_string(list) {
+            Ok(content) => content
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect(),
+            Err(e) => {
+                eprintln!("Error: Failed to read {:?}: {}", list, e);
+                process::exit(1);
+            }
+        };
+        if paths.is_empty() {
+            println!("{} lists no files, nothing to retry.", list);
+            process::exit(0);
+        }
+        paths
+    } else if !cli.input.is_empty() {
+        cli.input
+    } else {
+        config
+            .as_ref()
+            .map(|c| c.input.clone())
+            .unwrap_or(vec![fallback_input])
+    };
+    let skip_invalid_inputs = cli.skip_invalid_inputs
+        || config
+            .as_ref()
+            .map(|c| c.skip_invalid_inputs)
+            .unwrap_or(fallback_skip_invalid_inputs);
+    let follow_symlinks = cli.follow_symlinks
+        || config
+            .as_ref()
+            .map(|c| c.follow_symlinks)
+            .unwrap_or(fallback_follow_symlinks);
+    let include_output_in_scan = cli.include_output_in_scan
+        || config
+            .as_ref()
+            .map(|c| c.include_output_in_scan)
+            .unwrap_or(fallback_include_output_in_scan);
+    let max_depth = cli
+        .max_depth
+        .or_else(|| config.as_ref()?.max_depth)
+        .or(fallback_max_depth);
+
+    // Recover the readable frames of damaged files
+    let skip_corrupt_frames = cli.skip_corrupt_frames
+        || config
+            .as_ref()
+            .map(|c| c.skip_corrupt_frames)
+            .unwrap_or(fallback_skip_corrupt_frames);
+
+    // Content of skippable frames, which the decoder passes over
+    let extract_metadata = cli.extract_metadata
+        || config
+            .as_ref()
+            .map(|c| c.extract_metadata)
+            .unwrap_or(fallback_extract_metadata);
+
+    // Decode inputs without the zstd magic number
+    let no_magic_check = cli.no_magic_check
+        || config
+            .as_ref()
+            .map(|c| c.no_magic_check)
+            .unwrap_or(fallback_no_magic_check);
+
+    // Largest decoding window, needed for files compressed with --long
+    let window_log_max = cli
+        .window_log_max
+        .or_else(|| Some(config.as_ref()?.window_log_max))
+        .unwrap_or(fallback_window_log_max);
+    if window_log_max != 0 && !(WINDOW_LOG_MIN..=WINDOW_LOG_MAX).contains(&window_log_max) {
+        eprintln!(
+            "Error: --window-log-max must be between {} and {}, got {}.",
+            WINDOW_LOG_MIN, WINDOW_LOG_MAX, window_log_max
+        );
+        process::exit(1);
+    }
+
+    // Decompressed size range from the frame header
+    let min_decompressed_size = cli
+        .min_decompressed_size
+        .or_else(|| Some(config.as_ref()?.min_decompressed_size))
+        .unwrap_or(fallback_min_decompressed_size);
+    let max_decompressed_size = cli
+        .max_decompressed_size
+        .or_else(|| Some(config.as_ref()?.max_decompressed_size))
+        .unwrap_or(fallback_max_decompressed_size);
+
+    // Only files modified after a point in time
+    let newer_than = cli
+        .newer_than
+        .or_else(|| Some(config.as_ref()?.newer_than.clone()))
+        .unwrap_or(fallback_newer_than);
+    if !newer_than.is_empty()
+        && newer_than != "last-run"
+        && json::parse_timestamp(&newer_than).is_none()
+    {
+        eprintln!(
+            "Error: Invalid --newer-than {:?}, expected last-run, epoch seconds or milliseconds, RFC 3339 or YYYY-MM-DD",
+            newer_than
+        );
+        process::exit(1);
+    }
+
+    // Accepted input file extensions
+    let input_extensions = if !cli.input_extensions.is_empty() {
+        cli.input_extensions
+    } else {
+        config
+            .as_ref()
+            .map(|c| c.input_extensions.clone())
+            .filter(|extensions| !extensions.is_empty())
+            .unwrap_or(fallback_input_extensions)
+    };
+
+    // Output path
+    let mut output = cli
+        .output
+        .or_else(|| Some(config.as_ref()?.output.clone()))
+        .unwrap_or(fallback_output);
+
+    // Enforce ending with a slash
+    if !output.ends_with("/") {
+        output += "/";
+    }
+
+    // Use zstd compression in output
+    let zstd = cli.zstd || config.as_ref().map(|c| c.zstd).unwrap_or(fallback_zstd);
+
+    // Zstd compression level
+    let mut compression_level = cli
+        .compression_level
+        .or_else(|| Some(config.as_ref()?.compression_level))
+        .unwrap_or(fallback_compression_level);
+
+    // Decompressed size in the zstd frame header
+    let include_content_size = cli.include_content_size
+        || config
+            .as_ref()
+            .map(|c| c.include_content_size)
+            .unwrap_or(fallback_include_content_size);
+
+    // Single output file for all inputs
+    let merge_output = cli
+        .merge_output
+        .or_else(|| Some(config.as_ref()?.merge_output.clone()))
+        .unwrap_or(fallback_merge_output);
+
+    // Single sqlite database for all inputs
+    let sqlite = cli
+        .sqlite
+        .or_else(|| Some(config.as_ref()?.sqlite.clone()))
+        .unwrap_or(fallback_sqlite);
+
+    // Output directories per input pattern, the first matching pattern wins
+    let mut output_for = if !cli.output_for.is_empty() {
+        cli.output_for
+    } else {
+        config
+            .as_ref()
+            .map(|c| c.output_for.clone())
+            .unwrap_or(fallback_output_for)
+    };
+    for mapping in output_for.iter_mut() {
+        let Some((pattern, dir)) = mapping.split_once('=') else {
+            eprintln!(
+                "Error: Invalid --output-for {:?}, expected <glob>=<dir>.",
+                mapping
+            );
+            process::exit(1);
+        };
+        if let Err(e) = glob::Pattern::new(pattern) {
+            eprintln!(
+                "Error: The --output-for pattern {:?} is not valid: {}",
+                pattern, e
+            );
+            process::exit(1);
+        }
+        if dir.is_empty() {
+            eprintln!("Error: --output-for {:?} has no directory.", mapping);
+            process::exit(1);
+        }
+        // Same as --output, so file names can be appended directly
+        if !dir.ends_with('/') {
+            mapping.push('/');
+        }
+    }
+    if !output_for.is_empty() && (!merge_output.is_empty() || !sqlite.is_empty()) {
+        eprintln!("Error: --output-for picks the directory of every output file and can not be combined with --merge-output or --sqlite.");
+        process::exit(1);
+    }
+
+    // Continue existing merged outputs
+    let append = cli.append || config.as_ref().map(|c| c.append).unwrap_or(fallback_append);
+    if append && merge_output.is_empty() {
+        eprintln!("Error: --append requires --merge-output.");
+        process::exit(1);
+    }
+
+    // Deterministic output order
+    // Line numbers that continue across inputs, which needs the inputs to be read one after another
+    let global_line_numbers = cli.global_line_numbers
+        || config
+            .as_ref()
+            .map(|c| c.global_line_numbers)
+            .unwrap_or(fallback_global_line_numbers);
+    let ordered = cli.ordered
+        || config
+            .as_ref()
+            .map(|c| c.ordered)
+            .unwrap_or(fallback_ordered)
+        || global_line_numbers;
+
+    // Processing order of the files
+    let schedule = cli
+        .schedule
+        .or_else(|| {
+            config
+                .as_ref()
+                .map(|c| c.schedule.clone())
+                .filter(|schedule| !schedule.is_empty())
+        })
+        .unwrap_or(fallback_schedule);
+    if !["size-desc", "size-asc", "name"].contains(&schedule.as_str()) {
+        eprintln!(
+            "Error: Unknown schedule {:?}, expected size-desc, size-asc or name.",
+            schedule
+        );
+        process::exit(1);
+    }
+
+    // Only a part of the files for quick tests
+    let limit_files = cli
+        .limit_files
+        .or_else(|| Some(config.as_ref()?.limit_files))
+        .unwrap_or(fallback_limit_files);
+
+    // Size-balanced batches
+    let batch = cli
+        .batch
+        .or_else(|| Some(config.as_ref()?.batch))
+        .unwrap_or(fallback_batch);
+    if batch > 0 && ordered {
+        eprintln!("Error: --batch can not be combined with --ordered or --global-line-numbers, which already process one file at a time.");
+        process::exit(1);
+    }
+
+    // One merged output file per thread
+    let shard_by_thread = cli.shard_by_thread
+        || config
+            .as_ref()
+            .map(|c| c.shard_by_thread)
+            .unwrap_or(fallback_shard_by_thread);
+    if shard_by_thread && merge_output.is_empty() {
+        eprintln!("Error: --shard-by-thread requires --merge-output to name the output files.");
+        process::exit(1);
+    }
+
+    // Output file suffix
+    // Metadata of the run, recorded in summary.json and available as {key} in --suffix
+    let tags = if !cli.tags.is_empty() {
+        let mut tags: Vec<(String, String)> = Vec::new();
+        for tag in &cli.tags {
+            let Some((key, value)) = tag.split_once('=').filter(|(key, _)| !key.is_empty()) else {
+                eprintln!("Error: Invalid --tag {:?}, expected <key>=<value>.", tag);
+                process::exit(1);
+            };
+            // A repeated key replaces the earlier value
+            tags.retain(|(existing, _)| existing != key);
+            tags.push((key.to_string(), value.to_string()));
+        }
+        tags
+    } else {
+        config
+            .as_ref()
+            .map(|c| c.tags.clone())
+            .unwrap_or(fallback_tags)
+    };
+
+    let suffix = cli
+        .suffix
+        .or_else(|| Some(config.as_ref()?.suffix.clone()))
+        .unwrap_or(fallback_suffix);
+    let suffix = match expand_tags(&suffix, &tags) {
+        Ok(suffix) if suffix.contains(['/', '\\']) => {
+            eprintln!(
+                "Error: --suffix {:?} contains a path separator after inserting the tags.",
+                suffix
+            );
+            process::exit(1);
+        }
+        Ok(suffix) => suffix,
+        Err(e) => {
+            eprintln!("Error: --suffix {}.", e);
+            process::exit(1);
+        }
+    };
+
+    // Output file extension
+    let file_extension = cli
+        .file_extension
+        .or_else(|| Some(config.as_ref()?.file_extension.clone()))
+        .unwrap_or(fallback_file_extension);
+    let strip_inner_extension = cli.strip_inner_extension
+        || config
+            .as_ref()
+            .map(|c| c.strip_inner_extension)
+            .unwrap_or(fallback_strip_inner_extension);
+    let clean_stale_outputs = cli.clean_stale_outputs
+        || config
+            .as_ref()
+            .map(|c| c.clean_stale_outputs)
+            .unwrap_or(fallback_clean_stale_outputs);
+
+    // Regex pattern.
+    let pattern = cli
+        .pattern
+        .or_else(|| Some(config.as_ref()?.pattern.clone()))
+        .unwrap_or(fallback_pattern);
+
+    // Prefix of a line the pattern is applied to
+    let scan_prefix = cli
+        .scan_prefix
+        .or_else(|| Some(config.as_ref()?.scan_prefix))
+        .unwrap_or(fallback_scan_prefix);
+
+    // Matches instead of lines
+    let extract = cli.extract
+        || config
+            .as_ref()
+            .map(|c| c.extract)
+            .unwrap_or(fallback_extract);
+    let max_line_matches = cli
+        .max_line_matches
+        .or_else(|| Some(config.as_ref()?.max_line_matches))
+        .unwrap_or(fallback_max_line_matches);
+    if extract && pattern == "^" {
+        eprintln!("Error: --extract needs a --pattern to extract.");
+        process::exit(1);
+    }
+
+    // Required JSON key
+    let has_key = cli
+        .has_key
+        .or_else(|| Some(config.as_ref()?.has_key.clone()))
+        .unwrap_or(fallback_has_key);
+
+    // Search depth for the required JSON key
+    let has_key_depth = cli
+        .has_key_depth
+        .or_else(|| Some(config.as_ref()?.has_key_depth))
+        .unwrap_or(fallback_has_key_depth);
+
+    // Required JSON field types
+    let types = if !cli.types.is_empty() {
+        cli.types
+    } else {
+        config
+            .as_ref()
+            .map(|c| c.types.clone())
+            .unwrap_or(fallback_types)
+    };
+
+    // Approximate matches
+    let fuzzy = if !cli.fuzzy.is_empty() {
+        cli.fuzzy
+    } else {
+        config
+            .as_ref()
+            .map(|c| c.fuzzy.clone())
+            .unwrap_or(fallback_fuzzy)
+    };
+    let max_distance = cli
+        .max_distance
+        .or_else(|| Some(config.as_ref()?.max_distance))
+        .unwrap_or(fallback_max_distance);
+
+    // Several fields joined into one the JSON filters can match
+    let concat_fields = cli
+        .concat_fields
+        .or_else(|| Some(config.as_ref()?.concat_fields.clone()))
+        .unwrap_or(fallback_concat_fields);
+
+    // Time window
+    let time_field = cli
+        .time_field
+        .or_else(|| Some(config.as_ref()?.time_field.clone()))
+        .unwrap_or(fallback_time_field);
+    let since = cli
+        .since
+        .or_else(|| Some(config.as_ref()?.since.clone()))
+        .unwrap_or(fallback_since);
+    let until = cli
+        .until
+        .or_else(|| Some(config.as_ref()?.until.clone()))
+        .unwrap_or(fallback_until);
+    if time_field.is_empty() && (!since.is_empty() || !until.is_empty()) {
+        eprintln!("Error: --since and --until require --time-field.");
+        process::exit(1);
+    }
+
+    // Deduplication by a JSON field
+    let unique_by = cli
+        .unique_by
+        .or_else(|| Some(config.as_ref()?.unique_by.clone()))
+        .unwrap_or(fallback_unique_by);
+    let unique_keep = cli
+        .unique_keep
+        .or_else(|| {
+            config
+                .as_ref()
+                .map(|c| c.unique_keep.clone())
+                .filter(|unique_keep| !unique_keep.is_empty())
+        })
+        .unwrap_or(fallback_unique_keep);
+    let unique_global = cli.unique_global
+        || config
+            .as_ref()
+            .map(|c| c.unique_global)
+            .unwrap_or(fallback_unique_global);
+    let unique_max_keys = cli
+        .unique_max_keys
+        .or_else(|| Some(config.as_ref()?.unique_max_keys))
+        .unwrap_or(fallback_unique_max_keys);
+
+    // Distinct values of a field
+    let distinct = cli
+        .distinct
+        .or_else(|| Some(config.as_ref()?.distinct.clone()))
+        .unwrap_or(fallback_distinct);
+    let distinct_max_values = cli
+        .distinct_max_values
+        .or_else(|| Some(config.as_ref()?.distinct_max_values))
+        .unwrap_or(fallback_distinct_max_values);
+
+    // Group matching lines into one JSON object per value
+    let group_by = cli
+        .group_by
+        .or_else(|| Some(config.as_ref()?.group_by.clone()))
+        .unwrap_or(fallback_group_by);
+    let group_max_records = cli
+        .group_max_records
+        .or_else(|| Some(config.as_ref()?.group_max_records))
+        .unwrap_or(fallback_group_max_records);
+
+    // Last matching lines per input
+    let tail_lines = cli
+        .tail_lines
+        .or_else(|| Some(config.as_ref()?.tail_lines))
+        .unwrap_or(fallback_tail_lines);
+
+    // Sorted output per input
+    let sort_by = cli
+        .sort_by
+        .or_else(|| Some(config.as_ref()?.sort_by.clone()))
+        .unwrap_or(fallback_sort_by);
+    let sort_desc = cli.sort_desc
+        || config
+            .as_ref()
+            .map(|c| c.sort_desc)
+            .unwrap_or(fallback_sort_desc);
+    let sort_max_lines = cli
+        .sort_max_lines
+        .or_else(|| Some(config.as_ref()?.sort_max_lines))
+        .unwrap_or(fallback_sort_max_lines);
+
+    // Memory of the lines held per input, independent of the write buffer
+    let record_buffer_bytes = cli
+        .record_buffer_bytes
+        .or_else(|| Some(config.as_ref()?.record_buffer_bytes))
+        .unwrap_or(fallback_record_buffer_bytes);
+    if unique_keep != "first" && unique_keep != "last" {
+        eprintln!(
+            "Error: Unknown --unique-keep {:?}, expected first or last.",
+            unique_keep
+        );
+        process::exit(1);
+    }
+    if unique_keep == "last" && unique_global {
+        eprintln!("Error: --unique-keep last only works per input and can not be combined with --unique-global.");
+        process::exit(1);
+    }
+
+    // Scripted record filter
+    let filter_expr = cli
+        .filter_expr
+        .or_else(|| Some(config.as_ref()?.filter_expr.clone()))
+        .unwrap_or(fallback_filter_expr);
+
+    // Allow-list of field values, exact or as a bloom filter
+    let allow_field = cli
+        .allow_field
+        .or_else(|| Some(config.as_ref()?.allow_field.clone()))
+        .unwrap_or(fallback_allow_field);
+    let allow_file = cli
+        .allow_file
+        .or_else(|| Some(config.as_ref()?.allow_file.clone()))
+        .unwrap_or(fallback_allow_file);
+    let bloom_file = cli
+        .bloom_file
+        .or_else(|| Some(config.as_ref()?.bloom_file.clone()))
+        .unwrap_or(fallback_bloom_file);
+    if !allow_file.is_empty() && !bloom_file.is_empty() {
+        eprintln!("Error: --allow-file and --bloom-file are two kinds of allow-list, use only one of them.");
+        process::exit(1);
+    }
+    if allow_field.is_empty() != (allow_file.is_empty() && bloom_file.is_empty()) {
+        eprintln!("Error: --allow-field needs an allow-list from --allow-file or --bloom-file and the other way round.");
+        process::exit(1);
+    }
+
+    // Literal substrings
+    let contains = if !cli.contains.is_empty() {
+        cli.contains
+    } else {
+        config
+            .as_ref()
+            .map(|c| c.contains.clone())
+            .unwrap_or(fallback_contains)
+    };
+
+    // File with literal substrings
+    let contains_file = cli
+        .contains_file
+        .or_else(|| Some(config.as_ref()?.contains_file.clone()))
+        .unwrap_or(fallback_contains_file);
+
+    // Case insensitive matching
+    let ignore_case = cli.ignore_case
+        || config
+            .as_ref()
+            .map(|c| c.ignore_case)
+            .unwrap_or(fallback_ignore_case);
+
+    // Max threads.
+    let threads = cli
+        .threads
+        .or_else(|| Some(config.as_ref()?.threads))
+        .unwrap_or(fallback_threads);
+
+    // Open file handles
+    let max_open_files = cli
+        .max_open_files
+        .or_else(|| Some(config.as_ref()?.max_open_files))
+        .unwrap_or(fallback_max_open_files);
+
+    // Max buffer size
+    let buffer = cli
+        .buffer
+        .or_else(|| Some(config.as_ref()?.buffer))
+        .unwrap_or(fallback_buffer);
+
+    // Read buffer size
+    let read_buffer_size = cli
+        .read_buffer_size
+        .or_else(|| Some(config.as_ref()?.read_buffer_size))
+        .unwrap_or(fallback_read_buffer_size);
+
+    // Throughput limits
+    let max_read_rate = cli
+        .max_read_rate
+        .or_else(|| Some(config.as_ref()?.max_read_rate))
+        .unwrap_or(fallback_max_read_rate);
+    let max_write_rate = cli
+        .max_write_rate
+        .or_else(|| Some(config.as_ref()?.max_write_rate))
+        .unwrap_or(fallback_max_write_rate);
+
+    // Flush interval in matching lines
+    let flush_interval = cli
+        .flush_interval
+        .or_else(|| Some(config.as_ref()?.flush_interval))
+        .unwrap_or(fallback_flush_interval);
+
+    // Flush interval in milliseconds
+    let flush_ms = cli
+        .flush_ms
+        .or_else(|| Some(config.as_ref()?.flush_ms))
+        .unwrap_or(fallback_flush_ms);
+    // A sized frame can only be written once the output is complete
+    if include_content_size && zstd && (flush_interval != 0 || flush_ms != 0) {
+        eprintln!(
+            "{}",
+            "Warning: --flush-interval and --flush-ms are ignored with --include-content-size, every output is only written once it is complete."
+                .yellow()
+        );
+    }
+
+    // Command per finished output file
+    let on_complete = cli
+        .on_complete
+        .or_else(|| Some(config.as_ref()?.on_complete.clone()))
+        .unwrap_or(fallback_on_complete);
+    let on_complete_strict = cli.on_complete_strict
+        || config
+            .as_ref()
+            .map(|c| c.on_complete_strict)
+            .unwrap_or(fallback_on_complete_strict);
+
+    // Record delimiter of the output
+    let output_delimiter = unescape_delimiter(
+        &cli.output_delimiter
+            .or_else(|| Some(config.as_ref()?.output_delimiter.clone()))
+            .filter(|delimiter| !delimiter.is_empty())
+            .unwrap_or(fallback_output_delimiter),
+    );
+
+    // Pretty print JSON output
+    let pretty = cli.pretty || config.as_ref().map(|c| c.pretty).unwrap_or(fallback_pretty);
+
+    // Rewrite kept JSON lines with sorted keys
+    let canonicalize_json = cli.canonicalize_json
+        || config
+            .as_ref()
+            .map(|c| c.canonicalize_json)
+            .unwrap_or(fallback_canonicalize_json);
+
+    // Every line of the database is one row, so it replaces the other kinds of merged output
+    if !sqlite.is_empty() && cfg!(not(feature = "sqlite")) {
+        eprintln!("Error: --sqlite is not available, build with `--features sqlite` to enable it.");
+        process::exit(1);
+    }
+
+    // One JSON array per output, the records are separated by a comma and a newline
+    let json_array = cli.json_array
+        || config
+            .as_ref()
+            .map(|c| c.json_array)
+            .unwrap_or(fallback_json_array);
+    let output_delimiter = if json_array {
+        if output_delimiter != "\n" || !sqlite.is_empty() || append {
+            eprintln!("Error: --json-array can not be combined with --output-delimiter, --sqlite or --append.");
+            process::exit(1);
+        }
+        String::from(",\n")
+    } else {
+        output_delimiter
+    };
+
+    // grep-like prefixes for previewing matches, e.g. part0.jsonl.zst:12:{"id":1}
+    let line_numbers = cli.line_numbers
+        || config
+            .as_ref()
+            .map(|c| c.line_numbers)
+            .unwrap_or(fallback_line_numbers);
+    let with_filename = cli.with_filename
+        || config
+            .as_ref()
+            .map(|c| c.with_filename)
+            .unwrap_or(fallback_with_filename);
+    let prefix_separator = unescape_delimiter(
+        &cli.prefix_separator
+            .or_else(|| Some(config.as_ref()?.prefix_separator.clone()))
+            .filter(|separator| !separator.is_empty())
+            .unwrap_or(fallback_prefix_separator),
+    );
+    if (line_numbers || with_filename) && (json_array || !sqlite.is_empty()) {
+        eprintln!("Error: --line-numbers and --with-filename can not be combined with --json-array or --sqlite.");
+        process::exit(1);
+    }
+    // Lines that are only written after the whole input has been read do not know their line number anymore
+    if line_numbers && (tail_lines > 0 || !sort_by.is_empty() || unique_keep == "last") {
+        eprintln!("Error: --line-numbers can not be combined with --tail-lines, --sort-by or --unique-keep last.");
+        process::exit(1);
+    }
+
+    // Provenance field
+    let add_source_field = cli
+        .add_source_field
+        .or_else(|| Some(config.as_ref()?.add_source_field.clone()))
+        .unwrap_or(fallback_add_source_field);
+    let source_value = cli
+        .source_value
+        .or_else(|| Some(config.as_ref()?.source_value.clone()))
+        .unwrap_or(fallback_source_value);
+
+    // Verify written output
+    let verify_output = cli.verify_output
+        || config
+            .as_ref()
+            .map(|c| c.verify_output)
+            .unwrap_or(fallback_verify_output);
+
+    // Resume by content instead of output file names
+    let resume_by_hash = cli.resume_by_hash
+        || config
+            .as_ref()
+            .map(|c| c.resume_by_hash)
+            .unwrap_or(fallback_resume_by_hash);
+    // Inputs left out before they are read would shift the numbers of the following ones
+    if global_line_numbers
+        && (resume_by_hash || min_decompressed_size > 0 || max_decompressed_size > 0)
+    {
+        eprintln!("Error: --global-line-numbers can not be combined with --resume-by-hash, --min-decompressed-size or --max-decompressed-size.");
+        process::exit(1);
+    }
+
+    // Cap on the total output
+    let max_output_bytes = cli
+        .max_output_bytes
+        .or_else(|| Some(config.as_ref()?.max_output_bytes))
+        .unwrap_or(fallback_max_output_bytes);
+    let max_output_bytes_compressed = cli.max_output_bytes_compressed
+        || config
+            .as_ref()
+            .map(|c| c.max_output_bytes_compressed)
+            .unwrap_or(fallback_max_output_bytes_compressed);
+
+    // Handling of a full output disk
+    let on_disk_full = cli
+        .on_disk_full
+        .or_else(|| Some(config.as_ref()?.on_disk_full.clone()))
+        .unwrap_or(fallback_on_disk_full);
+    if !["skip", "pause", "abort"].contains(&on_disk_full.as_str()) {
+        eprintln!(
+            "Error: Unknown --on-disk-full {:?}, expected skip, pause or abort.",
+            on_disk_full
+        );
+        process::exit(1);
+    }
+
+    // Do not write to output, --distinct and --group-by write their own file instead of the matching lines
+    let no_write = cli.no_write
+        || config
+            .as_ref()
+            .map(|c| c.no_write)
+            .unwrap_or(fallback_no_write)
+        || !distinct.is_empty()
+        || !group_by.is_empty();
+
+    // Decode every input completely as an integrity check, this is --count-only with a verdict at the end
+    let deep_verify = cli.deep_verify
+        || config
+            .as_ref()
+            .map(|c| c.deep_verify)
+            .unwrap_or(fallback_deep_verify);
+
+    // Only count lines and bytes
+    let count_only = cli.count_only
+        || config
+            .as_ref()
+            .map(|c| c.count_only)
+            .unwrap_or(fallback_count_only)
+        || deep_verify;
+    if count_only && (!distinct.is_empty() || !group_by.is_empty()) {
+        eprintln!("Error: --distinct and --group-by can not be combined with --count-only.");
+        process::exit(1);
+    }
+
+    // Pass every line through, e.g. to recompress with --zstd
+    let decompress_only = cli.decompress_only
+        || config
+            .as_ref()
+            .map(|c| c.decompress_only)
+            .unwrap_or(fallback_decompress_only);
+    if decompress_only
+        && (pattern != "^"
+            || !contains.is_empty()
+            || !contains_file.is_empty()
+            || !has_key.is_empty()
+            || !types.is_empty()
+            || !fuzzy.is_empty()
+            || !time_field.is_empty()
+            || !filter_expr.is_empty()
+            || !allow_field.is_empty()
+            || !unique_by.is_empty()
+            || !distinct.is_empty()
+            || !group_by.is_empty()
+            || count_only)
+    {
+        eprintln!("Error: --decompress-only writes every line and can not be combined with filters, --distinct, --group-by or --count-only.");
+        process::exit(1);
+    }
+
+    // Categories of matching lines, each written to its own subdirectory of the output
+    let routes = if !cli.routes.is_empty() {
+        cli.routes
+    } else {
+        config
+            .as_ref()
+            .map(|c| c.routes.clone())
+            .unwrap_or(fallback_routes)
+    };
+    let route_mode = cli
+        .route_mode
+        .or_else(|| {
+            config
+                .as_ref()
+                .map(|c| c.route_mode.clone())
+                .filter(|route_mode| !route_mode.is_empty())
+        })
+        .unwrap_or(fallback_route_mode);
+    if route_mode != "first" && route_mode != "all" {
+        eprintln!(
+            "Error: Unknown --route-mode {:?}, expected first or all.",
+            route_mode
+        );
+        process::exit(1);
+    }
+    let bucket_by_match_count = cli.bucket_by_match_count
+        || config
+            .as_ref()
+            .map(|c| c.bucket_by_match_count)
+            .unwrap_or(fallback_bucket_by_match_count);
+    if bucket_by_match_count && routes.is_empty() {
+        eprintln!("Error: --bucket-by-match-count counts the --route patterns a line matches and needs at least one --route.");
+        process::exit(1);
+    }
+    if !routes.is_empty()
+        && (no_write || count_only || !merge_output.is_empty() || !sqlite.is_empty())
+    {
+        eprintln!("Error: --route writes a file per input and category and can not be combined with --merge-output, --sqlite, --no-write, --count-only, --distinct or --group-by.");
+        process::exit(1);
+    }
+
+    // A file per time bucket of a timestamp field instead of a file per input
+    let partition_by_time = cli
+        .partition_by_time
+        .or_else(|| Some(config.as_ref()?.partition_by_time.clone()))
+        .unwrap_or(fallback_partition_by_time);
+    let partition_utc_offset = cli
+        .partition_utc_offset
+        .or_else(|| {
+            config
+                .as_ref()
+                .map(|c| c.partition_utc_offset.clone())
+                .filter(|offset| !offset.is_empty())
+        })
+        .unwrap_or(fallback_partition_utc_offset);
+    if !partition_by_time.is_empty()
+        && (no_write
+            || count_only
+            || !merge_output.is_empty()
+            || !sqlite.is_empty()
+            || !routes.is_empty()
+            || json_array
+            || pretty
+            || append)
+    {
+        eprintln!("Error: --partition-by-time writes a file per time bucket and can not be combined with --merge-output, --sqlite, --route, --json-array, --pretty, --append, --no-write, --count-only, --distinct or --group-by.");
+        process::exit(1);
+    }
+
+    // Every line has to be valid JSON
+    let strict_jsonl = cli.strict_jsonl
+        || config
+            .as_ref()
+            .map(|c| c.strict_jsonl)
+            .unwrap_or(fallback_strict_jsonl);
+    let strict_jsonl_policy = cli
+        .strict_jsonl_policy
+        .or_else(|| {
+            config
+                .as_ref()
+                .map(|c| c.strict_jsonl_policy.clone())
+                .filter(|policy| !policy.is_empty())
+        })
+        .unwrap_or(fallback_strict_jsonl_policy);
+    if strict_jsonl_policy != "skip" && strict_jsonl_policy != "abort" {
+        eprintln!(
+            "Error: Unknown --strict-jsonl-policy {:?}, expected skip or abort.",
+            strict_jsonl_policy
+        );
+        process::exit(1);
+    }
+
+    // Cap on the length of a single line, so a corrupt or hostile input can not exhaust memory
+    let max_line_bytes_hard = cli
+        .max_line_bytes_hard
+        .or_else(|| Some(config.as_ref()?.max_line_bytes_hard))
+        .unwrap_or(fallback_max_line_bytes_hard);
+    let max_line_bytes_policy = cli
+        .max_line_bytes_policy
+        .or_else(|| {
+            config
+                .as_ref()
+                .map(|c| c.max_line_bytes_policy.clone())
+                .filter(|policy| !policy.is_empty())
+        })
+        .unwrap_or(fallback_max_line_bytes_policy);
+    if max_line_bytes_policy != "skip" && max_line_bytes_policy != "abort" {
+        eprintln!(
+            "Error: Unknown --max-line-bytes-policy {:?}, expected skip or abort.",
+            max_line_bytes_policy
+        );
+        process::exit(1);
+    }
+    if strict_jsonl && (count_only || decompress_only) {
+        eprintln!("Error: --strict-jsonl checks lines while filtering and can not be combined with --count-only or --decompress-only.");
+        process::exit(1);
+    }
+    if extract_metadata && (no_write || count_only) {
+        eprintln!("Error: --extract-metadata writes a file per input and can not be combined with --no-write or --count-only.");
+        process::exit(1);
+    }
+
+    // Print the decision of every filter for the first lines
+    let explain = cli.explain
+        || config
+            .as_ref()
+            .map(|c| c.explain)
+            .unwrap_or(fallback_explain);
+    let explain_lines = cli
+        .explain_lines
+        .or_else(|| Some(config.as_ref()?.explain_lines))
+        .unwrap_or(fallback_explain_lines);
+    if explain && (count_only || decompress_only) {
+        eprintln!("Error: --explain shows the filter decisions and can not be combined with --count-only or --decompress-only, which do not filter.");
+        process::exit(1);
+    }
+
+    // Expected share of kept lines per input in percent
+    let warn_if_ratio_below = cli
+        .warn_if_ratio_below
+        .or_else(|| Some(config.as_ref()?.warn_if_ratio_below))
+        .unwrap_or(fallback_warn_if_ratio_below);
+    let warn_if_ratio_above = cli
+        .warn_if_ratio_above
+        .or_else(|| Some(config.as_ref()?.warn_if_ratio_above))
+        .unwrap_or(fallback_warn_if_ratio_above);
+    if !(0.0..=100.0).contains(&warn_if_ratio_below)
+        || !(0.0..=100.0).contains(&warn_if_ratio_above)
+        || warn_if_ratio_below > warn_if_ratio_above
+    {
+        eprintln!("Error: --warn-if-ratio-below and --warn-if-ratio-above are percentages between 0 and 100 and the lower bound can not be above the upper one.");
+        process::exit(1);
+    }
+
+    // Mute most announcements
+    let quiet = cli.quiet || config.as_ref().map(|c| c.quiet).unwrap_or(fallback_quiet);
+
+    // Colored output, applied right away so the warnings below follow it as well
+    let color = cli
+        .color
+        .or_else(|| {
+            config
+                .as_ref()
+                .map(|c| c.color.clone())
+                .filter(|color| !color.is_empty())
+        })
+        .unwrap_or(fallback_color);
+    match color.as_str() {
+        "always" => colored::control::set_override(true),
+        "never" => colored::control::set_override(false),
+        "auto" => colored::control::set_override(
+            std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                && std::io::stdout().is_terminal()
+                && std::io::stderr().is_terminal(),
+        ),
+        _ => {
+            eprintln!(
+                "Error: Unknown --color {:?}, expected auto, always or never.",
+                color
+            );
+            process::exit(1);
+        }
+    }
+
+    // Progress bar per file
+    let file_progress = cli.file_progress
+        || config
+            .as_ref()
+            .map(|c| c.file_progress)
+            .unwrap_or(fallback_file_progress);
+
+    // Progress log file
+    let progress_log = cli
+        .progress_log
+        .or_else(|| Some(config.as_ref()?.progress_log.clone()))
+        .unwrap_or(fallback_progress_log);
+
+    // Print a snapshot on SIGUSR1
+    let progress_on_signal = cli.progress_on_signal
+        || config
+            .as_ref()
+            .map(|c| c.progress_on_signal)
+            .unwrap_or(fallback_progress_on_signal);
+    if progress_on_signal && !cfg!(unix) {
+        eprintln!(
+            "{}",
+            "Warning: --progress-on-signal is only supported on Unix and does nothing here."
+                .yellow()
+        );
+    }
+
+    // Periodic plain summaries
+    let summary_interval = cli
+        .summary_interval
+        .or_else(|| Some(config.as_ref()?.summary_interval.clone()))
+        .unwrap_or(fallback_summary_interval);
+    if !summary_interval.is_empty() && parse_interval(&summary_interval).is_none() {
+        eprintln!(
+            "Error: Invalid --summary-interval {:?}, expected seconds or a number with s, m or h like 5m.",
+            summary_interval
+        );
+        process::exit(1);
+    }
+
+    // Layout of the progress display
+    let progress_style = cli
+        .progress_style
+        .or_else(|| {
+            config
+                .as_ref()
+                .map(|c| c.progress_style.clone())
+                .filter(|progress_style| !progress_style.is_empty())
+        })
+        .unwrap_or(fallback_progress_style);
+    if progress_style != "full" && progress_style != "compact" {
+        eprintln!(
+            "Error: Unknown --progress-style {:?}, expected full or compact.",
+            progress_style
+        );
+        process::exit(1);
+    }
+
+    // Skip the run summary
+    let no_summary_file = cli.no_summary_file
+        || config
+            .as_ref()
+            .map(|c| c.no_summary_file)
+            .unwrap_or(fallback_no_summary_file);
+
+    // Validate the regex pattern, it is never used when only counting
+    if !count_only {
+        if let Err(e) = validate_regex(&pattern) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+
+    // Re-leveling a whole archive uses its own level, so the regular --compression-level can stay in the config
+    let recompress_level = cli
+        .recompress_level
+        .or_else(|| Some(config.as_ref()?.recompress_level))
+        .unwrap_or(fallback_recompress_level);
+    if recompress_level != 0 {
+        if decompress_only && zstd {
+            compression_level = recompress_level;
+        } else {
+            eprintln!(
+                "{}",
+                "Warning: --recompress-level is ignored without --decompress-only and --zstd."
+                    .yellow()
+            );
+        }
+    }
+
+    // Reuse the parameters of every input for its output
+    let match_input_params = cli.match_input_params
+        || config
+            .as_ref()
+            .map(|c| c.match_input_params)
+            .unwrap_or(fallback_match_input_params);
+    if match_input_params && zstd && merge_output.is_empty() && sqlite.is_empty() {
+        // Frame headers only record the window, the level that produced a frame is lost
+        eprintln!(
+            "{}",
+            "Warning: The compression level of the inputs can not be read from their frame headers, --match-input-params only reuses their window."
+                .yellow()
+        );
+    }
+    if !zstd && compression_level != 0 {
+        eprintln!(
+            "{}",
+            "Warning: --compression-level has no effect without --zstd.".yellow()
+        );
+    }
+
+    // Verify valid zstd compression level range, negative levels are faster and compress less
+    let compression_level_range = zstd::compression_level_range();
+    if !compression_level_range.contains(&compression_level) {
+        eprintln!(
+            "{}",
+            format!(
+                "Warning: compression level {} is outside of the supported range {} to {}, using the zstd default instead.",
+                compression_level,
+                compression_level_range.start(),
+                compression_level_range.end()
+            )
+            .yellow()
+        );
+        compression_level = 0;
+    }
+
+    // Copy inputs without decoding them, only when the recompressed output would hold exactly the same lines
+    let mut no_recompress = cli.no_recompress
+        || config
+            .as_ref()
+            .map(|c| c.no_recompress)
+            .unwrap_or(fallback_no_recompress);
+    if no_recompress
+        && (!zstd
+            || no_write
+            || pattern != "^"
+            || ignore_case
+            || !contains.is_empty()
+            || !contains_file.is_empty()
+            || !has_key.is_empty()
+            || !types.is_empty()
+            || !fuzzy.is_empty()
+            || !time_field.is_empty()
+            || !filter_expr.is_empty()
+            || !allow_field.is_empty()
+            || !unique_by.is_empty()
+            || count_only
+            || extract
+            || tail_lines > 0
+            || !sort_by.is_empty()
+            || !routes.is_empty()
+            || !partition_by_time.is_empty()
+            || strict_jsonl
+            || max_line_bytes_hard > 0
+            || explain
+            || pretty
+            || canonicalize_json
+            || json_array
+            || line_numbers
+            || with_filename
+            || !add_source_field.is_empty()
+            || output_delimiter != "\n"
+            || global_line_numbers
+            || verify_output
+            || !merge_output.is_empty()
+            || !sqlite.is_empty()
+            || skip_corrupt_frames
+            || max_output_bytes > 0
+            || recompress_level != 0
+            || match_input_params
+            || include_content_size)
+    {
+        eprintln!(
+            "{}",
+            "Warning: --no-recompress is ignored, it only copies inputs with --zstd and no option that filters, changes, merges, verifies or recompresses lines."
+                .yellow()
+        );
+        no_recompress = false;
+    }
+
+    // stdin is a single stream written to stdout, nothing that needs output files or several inputs works with it
+    if input.iter().any(|input| input == "-") {
+        if input.len() > 1 {
+            eprintln!("Error: --input - reads stdin and can not be combined with other inputs.");
+            process::exit(1);
+        }
+        if !merge_output.is_empty()
+            || !sqlite.is_empty()
+            || !routes.is_empty()
+            || !partition_by_time.is_empty()
+            || !output_for.is_empty()
+            || !distinct.is_empty()
+            || !group_by.is_empty()
+            || !on_complete.is_empty()
+            || count_only
+            || deep_verify
+            || verify_output
+            || extract_metadata
+            || resume_by_hash
+            || append
+        {
+            eprintln!("Error: --input - writes the kept lines to stdout and can not be combined with --merge-output, --sqlite, --route, --partition-by-time, --output-for, --distinct, --group-by, --on-complete, --count-only, --deep-verify, --verify-output, --extract-metadata, --resume-by-hash or --append.");
+            process::exit(1);
+        }
+    }
+
+    let config = Config {
+        input,
+        skip_invalid_inputs,
+        follow_symlinks,
+        include_output_in_scan,
+        max_depth,
+        skip_corrupt_frames,
+        extract_metadata,
+        no_magic_check,
+        window_log_max,
+        min_decompressed_size,
+        max_decompressed_size,
+        newer_than,
+        input_extensions,
+        output,
+        output_for,
+        zstd,
+        compression_level,
+        recompress_level,
+        match_input_params,
+        no_recompress,
+        include_content_size,
+        merge_output,
+        sqlite,
+        append,
+        ordered,
+        global_line_numbers,
+        schedule,
+        limit_files,
+        batch,
+        shard_by_thread,
+        suffix,
+        tags,
+        file_extension,
+        strip_inner_extension,
+        clean_stale_outputs,
+        pattern,
+        scan_prefix,
+        extract,
+        max_line_matches,
+        has_key,
+        has_key_depth,
+        types,
+        fuzzy,
+        max_distance,
+        concat_fields,
+        time_field,
+        since,
+        until,
+        unique_by,
+        unique_keep,
+        unique_global,
+        unique_max_keys,
+        distinct,
+        distinct_max_values,
+        group_by,
+        group_max_records,
+        tail_lines,
+        sort_by,
+        sort_desc,
+        sort_max_lines,
+        record_buffer_bytes,
+        routes,
+        route_mode,
+        bucket_by_match_count,
+        partition_by_time,
+        partition_utc_offset,
+        strict_jsonl,
+        strict_jsonl_policy,
+        max_line_bytes_hard,
+        max_line_bytes_policy,
+        explain,
+        explain_lines,
+        warn_if_ratio_below,
+        warn_if_ratio_above,
+        filter_expr,
+        allow_field,
+        allow_file,
+        bloom_file,
+        contains,
+        contains_file,
+        ignore_case,
+        threads,
+        max_open_files,
+        buffer,
+        read_buffer_size,
+        max_read_rate,
+        max_write_rate,
+        flush_interval,
+        flush_ms,
+        on_complete,
+        on_complete_strict,
+        output_delimiter,
+        pretty,
+        canonicalize_json,
+        json_array,
+        line_numbers,
+        with_filename,
+        prefix_separator,
+        add_source_field,
+        source_value,
+        verify_output,
+        resume_by_hash,
+        max_output_bytes,
+        max_output_bytes_compressed,
+        on_disk_full,
+        no_write,
+        count_only,
+        deep_verify,
+        decompress_only,
+        quiet,
+        color,
+        file_progress,
+        progress_log,
+        progress_on_signal,
+        summary_interval,
+        progress_style,
+        no_summary_file,
+    };
+    if let Err(e) = check_output_formats(&config) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+    config
+}
 
-        let global_to_be_processed_size_string =
-            format!("{}", HumanBytes(global_to_be_processed_size));
-        let global_to_be_processed_size_string_len =
-            global_to_be_processed_size_string.chars().count();
-        let line_ratio = {
-            if global_decompressed_lines == 0 {
-                0 as f64
-            } else {
-                ((global_filtered_lines) * 100) as f64 / global_decompressed_lines as f64
-            }
-        };
-        let line_ratio_string = format!("{:.4}%", line_ratio);
+// The completion script of a shell for every option of the command line
+fn write_completions(shell: clap_complete::Shell, out: &mut impl Write) {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, out);
+}
 
-        sys.refresh_all();
-        std::thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
-        let pid = sysinfo::get_current_pid().unwrap();
-        let process = sys.process(pid).unwrap();
+// Rejects combinations of output format and compression options that can not work together
+fn check_output_formats(config: &Config) -> Result<(), String> {
+    if config.extract
+        && (config.pretty || config.canonicalize_json || !config.add_source_field.is_empty())
+    {
+        return Err(String::from("--extract writes plain matches and can not be combined with --pretty, --canonicalize-json or --add-source-field."));
+    }
+    if !config.sqlite.is_empty() {
+        if config.zstd {
+            return Err(String::from(
+                "--sqlite writes a database and can not be combined with --zstd.",
+            ));
+        }
+        if !config.merge_output.is_empty() || config.pretty || config.output_delimiter != "\n" {
+            return Err(String::from(
+                "--sqlite can not be combined with --merge-output, --pretty or --output-delimiter.",
+            ));
+        }
+    }
+    if config.include_content_size && !config.zstd {
+        return Err(String::from(
+            "--include-content-size only applies to compressed output, use it with --zstd.",
+        ));
+    }
+    if !config.zstd && config.file_extension.ends_with("zst") {
+        return Err(format!(
+            "--file-extension {:?} names uncompressed output as zstd, add --zstd to compress it.",
+            config.file_extension
+        ));
+    }
+    if config.match_input_params {
+        if !config.zstd {
+            return Err(String::from(
+                "--match-input-params only applies to compressed output, use it with --zstd.",
+            ));
+        }
+        if !config.merge_output.is_empty() || !config.sqlite.is_empty() {
+            return Err(String::from("--match-input-params sets the parameters per output file and can not be combined with --merge-output or --sqlite."));
+        }
+    }
+    Ok(())
+}
 
-        // Fetch CPU, memory, and I/O stats
-        let cpu_usage = process.cpu_usage() / sys.cpus().len() as f32;
-        let mut cpu_usage_string = cpu_usage.to_string();
-        if cpu_usage < 10 as f32 {
-            cpu_usage_string.insert_str(0, " ");
-        };
-        cpu_usage_string = format!("{:.5}%", cpu_usage_string);
-        let cpu_usage_string_len = cpu_usage_string.chars().count();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let memory_usage = format!("{}", HumanBytes(process.memory()));
-        let memory_usage_len = memory_usage.chars().count();
+    // A config as the command line would give it, without reading a config file
+    fn test_config(args: &[&str]) -> Config {
+        let mut arguments = vec!["zstd-jsonl-filter", "--config", "none"];
+        arguments.extend_from_slice(args);
+        set_config(Cli::parse_from(arguments))
+    }
 
-        let disk_usage = process.disk_usage();
-        let disk_usage_reads = disk_usage.read_bytes;
-        let disk_usage_writes = disk_usage.written_bytes;
-        let disk_usage_reads_string = format!("{}/s", HumanBytes(disk_usage_reads));
-        let disk_total_reads_string_len = disk_usage_reads_string.chars().count();
-        let disk_usage_writes_string = format!("{}/s", HumanBytes(disk_usage_writes));
-        let disk_usage_writes_string_len = disk_usage_writes_string.chars().count();
+    #[test]
+    fn concat_fields_pattern_matches_across_fields() {
+        let config = test_config(&[
+            "--concat-fields",
+            "first_name,last_name= ",
+            "--pattern",
+            "^Jane D",
+        ]);
+        let filter = LineFilter::new(&config).unwrap();
+        assert!(filter.is_match(r#"{"first_name":"Jane","last_name":"Doe"}"#, &config));
+        assert!(!filter.is_match(r#"{"first_name":"Jane","last_name":"Smith"}"#, &config));
+        // The line itself does not match the pattern, only the joined value does
+        assert!(!filter.is_match("Jane Doe", &config));
 
-        //  update or estimate the size, somewhat more accurate than // processed_size_estimate = disk_total_reads;
-        if last_accurate_proc_size == global_processed_size {
-            processed_size_estimate += disk_usage.read_bytes;
-        } else {
-            last_accurate_proc_size = global_processed_size;
-            processed_size_estimate = global_processed_size;
-        }
+        let config = test_config(&["--concat-fields", "a,b=-", "--contains", "x-y"]);
+        let filter = LineFilter::new(&config).unwrap();
+        assert!(filter.is_match(r#"{"a":"x","b":"y"}"#, &config));
+        assert!(!filter.is_match(r#"{"a":"x","c":"x-y"}"#, &config));
+    }
 
-        let avg_speed = global_decompressed_size as f64 / elapsed;
-        let line_speed = format!("{:.0} lines/s", global_decompressed_lines as f64 / elapsed);
-        let line_speed_len = line_speed.chars().count();
-        let remaining_compressed_data = global_to_be_processed_size - processed_size_estimate;
+    // A file in the temporary directory that is unique to this test run
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("zstd-jsonl-filter-test-{}-{}", process::id(), name))
+    }
 
-        let remaining_time = HumanDuration(Duration::new(
-            (remaining_compressed_data as f64 / (disk_usage_reads as f64)) as u64,
+    // Filters lines through an in-memory zstd stream into a temporary output file and returns its decompressed content
+    fn filter_to_output(name: &str, lines: &[String], args: &[&str]) -> Vec<u8> {
+        let config = test_config(args);
+        let filter = LineFilter::new(&config).unwrap();
+        let output_limit = Arc::new(OutputLimit::new(&config));
+        let compressed = zstd::stream::encode_all(lines.join("\n").as_bytes(), 3).unwrap();
+        let reader = BufReader::new(Decoder::new(&compressed[..]).unwrap());
+        let path = temp_path(name);
+        filter_lines(
+            reader,
+            name,
+            &path.to_string_lossy(),
+            None,
             0,
-        ));
+            &config,
+            &filter,
+            &output_limit,
+            &ProgressBar::hidden(),
+            None,
+            &Arc::new(AtomicUsize::new(0)),
+            &Arc::new(AtomicUsize::new(0)),
+            &Arc::new(AtomicUsize::new(0)),
+        )
+        .unwrap();
+        let output = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        if config.zstd {
+            zstd::stream::decode_all(&output[..]).unwrap()
+        } else {
+            output
+        }
+    }
 
-        let remaining_percentage_string = {
-            if global_decompressed_lines == 0 {
-                format!("0%")
-            } else {
+    #[test]
+    fn tiny_buffers_keep_every_line_whole() {
+        let lines: Vec<String> = (0..200)
+            .map(|id| {
                 format!(
-                    "{:.2}%",
-                    (processed_size_estimate * 100) as f64 / global_to_be_processed_size as f64
+                    r#"{{"id":{},"level":"{}"}}"#,
+                    id,
+                    if id % 3 == 0 { "error" } else { "info" }
                 )
-            }
-        };
-        let remaining_percentage_string_len = remaining_percentage_string.chars().count();
-        let processed_size_estimate_string = format!("{}", HumanBytes(processed_size_estimate));
-        let processed_size_estimate_string_len = processed_size_estimate_string.chars().count();
-
-        let terminal_size = match terminal_size() {
-            Some((Width(w), _)) => w as usize,
-            None => 40,
-        };
-        fn print_pb_divider(bar_width: usize, mut position: usize, size: usize) -> &'static str {
-            position = position % bar_width;
-            if position + size > bar_width {
-                "\n"
-            } else {
-                " | "
+            })
+            .collect();
+        let expected: Vec<u8> = lines
+            .iter()
+            .filter(|line| line.contains("error"))
+            .flat_map(|line| format!("{}\n", line).into_bytes())
+            .collect();
+        let below_line = (lines[0].len() - 1).to_string();
+        for buffer in ["1", "2", below_line.as_str()] {
+            for zstd in [false, true] {
+                let mut args = vec!["--pattern", "error", "--buffer", buffer];
+                if zstd {
+                    args.push("--zstd");
+                }
+                let name = format!("tiny-buffer-{}-{}", buffer, zstd);
+                let output = filter_to_output(&name, &lines, &args);
+                assert_eq!(output, expected, "--buffer {} --zstd {}", buffer, zstd);
             }
         }
+    }
 
-        let too_long = cpu_usage_string_len
-            + memory_usage_len
-            + line_speed_len
-            + processed_size_estimate_string_len
-            + global_to_be_processed_size_string_len
-            + remaining_percentage_string_len
-            + 43;
-
-        pb.set_message(format!(
-            "({} remaining)\nCPU: {}{}Memory: {}{}Speed: {}{}Progress: {}/{} ({}){}I/O Reads: {} | I/O Writes: {}\nDecompressed: {} ({})\nKept/Total Lines: {}/{} ({})",
-            remaining_time,
-            cpu_usage_string.bright_blue(),
-
-            print_pb_divider(terminal_size, cpu_usage_string_len + 5, memory_usage_len + 11),
-            memory_usage.bright_blue(),
-
-            print_pb_divider(terminal_size, cpu_usage_string_len + memory_usage_len + 16, line_speed_len + 10),
-            line_speed.bright_blue(),
+    #[test]
+    fn command_line_is_consistent() {
+        Cli::command().debug_assert();
+    }
 
-            print_pb_divider(terminal_size, cpu_usage_string_len + memory_usage_len + line_speed_len + 26, processed_size_estimate_string_len + global_to_be_processed_size_string_len + remaining_percentage_string_len + 17),
-            processed_size_estimate_string,
-            global_to_be_processed_size_string,
-            remaining_percentage_string.bright_blue(),
+    #[test]
+    fn bash_completions_list_the_options() {
+        let mut script = Vec::new();
+        write_completions(clap_complete::Shell::Bash, &mut script);
+        let script = String::from_utf8(script).unwrap();
+        for option in ["--pattern", "--merge-output", "--unique-by"] {
+            assert!(script.contains(option), "{} is missing", option);
+        }
+    }
 
-            print_pb_divider(terminal_size, too_long, disk_total_reads_string_len + disk_usage_writes_string_len + 29),
-            disk_usage_reads_string.bright_blue(),
-            disk_usage_writes_string.bright_blue(),
+    #[test]
+    fn output_file_names() {
+        let cases: &[(&str, &[&str], &str)] = &[
+            ("in/name.jsonl.zst", &[], "out/name_filtered.jsonl"),
+            (
+                "in/name.jsonl.zst",
+                &["--zstd"],
+                "out/name_filtered.jsonl.zst",
+            ),
+            (
+                "in/name.jsonl.zst",
+                &["--file-extension", "csv"],
+                "out/name_filtered.csv",
+            ),
+            (
+                "in/name.jsonl.zst",
+                &["--file-extension", ".csv"],
+                "out/name_filtered.csv",
+            ),
+            (
+                "in/name.jsonl.zst",
+                &["--file-extension", "csv", "--zstd"],
+                "out/name_filtered.csv.zst",
+            ),
+            (
+                "in/name.jsonl.zst",
+                &["--file-extension", "jsonl.zst", "--zstd"],
+                "out/name_filtered.jsonl.zst",
+            ),
+            (
+                "in/name.jsonl.zst",
+                &["--strip-inner-extension"],
+                "out/name_filtered",
+            ),
+            (
+                "in/name.jsonl.zst",
+                &["--strip-inner-extension", "--zstd"],
+                "out/name_filtered.zst",
+            ),
+            (
+                "in/name.jsonl.zst",
+                &["--strip-inner-extension", "--file-extension", "csv"],
+                "out/name_filtered.csv",
+            ),
+            (
+                "in/name.jsonl.zst",
+                &[
+                    "--strip-inner-extension",
+                    "--file-extension",
+                    "csv",
+                    "--zstd",
+                ],
+                "out/name_filtered.csv.zst",
+            ),
+            // Without an inner extension there is nothing to keep
+            ("in/name.zst", &[], "out/name_filtered"),
+            ("in/name.zst", &["--zstd"], "out/name_filtered.zst"),
+            (
+                "in/name.zst",
+                &["--file-extension", "csv"],
+                "out/name_filtered.csv",
+            ),
+            (
+                "in/name.zst",
+                &["--strip-inner-extension", "--zstd"],
+                "out/name_filtered.zst",
+            ),
+            ("in/name.jsonl.zst", &["--suffix", ""], "out/name.jsonl"),
+        ];
+        for (input, args, expected) in cases {
+            let mut arguments = vec!["--output", "out/"];
+            arguments.extend_from_slice(args);
+            let config = test_config(&arguments);
+            assert_eq!(
+                generate_output_filename(input, &config),
+                *expected,
+                "{} {:?}",
+                input,
+                args
+            );
+        }
+    }
 
-            format!("{}", HumanBytes(global_decompressed_size as u64)),
-            format!("{}/s", HumanBytes(avg_speed as u64)).bright_blue(),
-            format!("{}", HumanCount(global_filtered_lines as u64)),
-            format!("{}", HumanCount(global_decompressed_lines as u64)),
-            line_ratio_string.bright_blue()
-        ));
+    #[test]
+    fn delimiter_escapes() {
+        assert_eq!(unescape_delimiter("\\0"), "\0");
+        assert_eq!(unescape_delimiter("\\r\\n"), "\r\n");
+        assert_eq!(unescape_delimiter("\\t"), "\t");
+        assert_eq!(unescape_delimiter("\\\\"), "\\");
+        // An unknown escape stands for the character itself, a trailing backslash for a backslash
+        assert_eq!(unescape_delimiter("\\x"), "x");
+        assert_eq!(unescape_delimiter(";\\"), ";\\");
+        assert_eq!(unescape_delimiter("--"), "--");
+    }
 
-        // Exit the updater if the progress bar is finished
-        if pb.is_finished() {
-            break;
+    #[test]
+    fn nul_delimited_output_is_verified() {
+        let config = test_config(&["--output-delimiter", "\\0"]);
+        assert_eq!(config.output_delimiter, "\0");
+        let output_limit = Arc::new(OutputLimit::new(&config));
+        // Newlines inside a record are not record terminators
+        let lines = ["a", "multi\nline", "c"];
+        let mut buffer = LineBuffer::new(0);
+        for line in lines {
+            buffer.push(line, &config);
         }
+        assert_eq!(buffer.bytes, b"a\0multi\nline\0c\0");
+        assert_eq!(count_records(&buffer.bytes, &config), lines.len());
 
-        // Update every PB_UPDATE_INTERVAL
-        std::thread::sleep(Duration::from_millis(PB_UPDATE_INTERVAL));
+        let path = temp_path("nul-delimited");
+        let mut sink = Sink::File(Some(
+            OutputWriter::new(File::create(&path).unwrap(), &config, &output_limit).unwrap(),
+        ));
+        buffer.flush(&mut sink, &output_limit).unwrap();
+        sink.finish().unwrap();
+        assert_eq!(verify_output(&path, lines.len(), &config), Ok(()));
+        assert!(verify_output(&path, lines.len() + 1, &config).is_err());
+        fs::remove_file(&path).unwrap();
     }
-}
 
-/// Command line argument structure
-#[derive(Parser, Debug)]
-#[command(author, version, about = "Rust Configuration Demo", long_about = None)]
-struct Cli {
-    #[arg(long = "input")]
-    input: Option<String>,
-    #[arg(long = "output")]
-    output: Option<String>,
-    #[arg(long = "zstd")]
-    zstd: bool,
-    #[arg(long = "compression-level")]
-    compression_level: Option<i32>,
-    #[arg(long = "suffix")]
-    suffix: Option<String>,
-    #[arg(long = "file-extension")]
-    file_extension: Option<String>,
-    #[arg(long = "pattern")]
-    pattern: Option<String>,
-    #[arg(long = "threads")]
-    threads: Option<usize>,
-    #[arg(long = "buffer")]
-    buffer: Option<usize>,
-    #[arg(long = "no-write")]
-    no_write: bool,
-    #[arg(long = "quiet")]
-    quiet: bool,
-    #[arg(long = "config", default_value = "config.toml")]
-    config: String,
-}
+    // Options of a combination, applied to a config
+    type Combination<'a> = (&'a str, &'a dyn Fn(&mut Config));
 
-// Internal and config.toml structure
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Config {
-    input: String,
-    output: String,
-    zstd: bool,
-    compression_level: i32,
-    suffix: String,
-    file_extension: String,
-    pattern: String,
-    threads: usize,
-    buffer: usize,
-    no_write: bool,
-    quiet: bool,
-}
+    #[test]
+    fn output_format_combinations() {
+        let base = test_config(&[]);
+        let with = |change: &dyn Fn(&mut Config)| {
+            let mut config = base.clone();
+            change(&mut config);
+            check_output_formats(&config)
+        };
+        let rejected: &[Combination] = &[
+            ("--extract --pretty", &|c| {
+                (c.extract, c.pretty) = (true, true)
+            }),
+            ("--extract --canonicalize-json", &|c| {
+                (c.extract, c.canonicalize_json) = (true, true)
+            }),
+            ("--extract --add-source-field", &|c| {
+                c.extract = true;
+                c.add_source_field = String::from("source");
+            }),
+            ("--sqlite --zstd", &|c| {
+                (c.sqlite, c.zstd) = (String::from("a.db"), true)
+            }),
+            ("--sqlite --merge-output", &|c| {
+                (c.sqlite, c.merge_output) = (String::from("a.db"), String::from("all.jsonl"))
+            }),
+            ("--sqlite --pretty", &|c| {
+                (c.sqlite, c.pretty) = (String::from("a.db"), true)
+            }),
+            ("--sqlite --output-delimiter", &|c| {
+                (c.sqlite, c.output_delimiter) = (String::from("a.db"), String::from("\0"))
+            }),
+            ("--include-content-size", &|c| c.include_content_size = true),
+            ("--file-extension jsonl.zst", &|c| {
+                c.file_extension = String::from("jsonl.zst")
+            }),
+            ("--match-input-params", &|c| c.match_input_params = true),
+            ("--match-input-params --zstd --merge-output", &|c| {
+                (c.match_input_params, c.zstd) = (true, true);
+                c.merge_output = String::from("all.jsonl.zst");
+            }),
+            ("--match-input-params --zstd --sqlite", &|c| {
+                (c.match_input_params, c.zstd) = (true, true);
+                c.sqlite = String::from("a.db");
+            }),
+        ];
+        for (options, change) in rejected {
+            assert!(with(change).is_err(), "{} should be rejected", options);
+        }
+        let accepted: &[Combination] = &[
+            ("defaults", &|_| {}),
+            ("--extract", &|c| c.extract = true),
+            ("--sqlite", &|c| c.sqlite = String::from("a.db")),
+            ("--include-content-size --zstd", &|c| {
+                (c.include_content_size, c.zstd) = (true, true)
+            }),
+            ("--file-extension jsonl.zst --zstd", &|c| {
+                c.file_extension = String::from("jsonl.zst");
+                c.zstd = true;
+            }),
+            ("--file-extension csv", &|c| {
+                c.file_extension = String::from("csv")
+            }),
+            // Only warnings
+            ("--compression-level without --zstd", &|c| {
+                c.compression_level = 19
+            }),
+            ("--recompress-level without --decompress-only", &|c| {
+                c.recompress_level = 19
+            }),
+            ("--match-input-params --zstd", &|c| {
+                (c.match_input_params, c.zstd) = (true, true)
+            }),
+        ];
+        for (options, change) in accepted {
+            assert_eq!(with(change), Ok(()), "{} should be accepted", options);
+        }
+    }
 
-fn validate_regex(pattern: &str) -> Result<Regex, String> {
-    Regex::new(pattern).map_err(|e| format!("Invalid regex: {}", e))
-}
+    #[test]
+    fn truncation_keeps_multi_byte_characters_whole() {
+        for text in ["日本語🙂", "a🙂b語", "🙂🙂"] {
+            for max_bytes in 0..=text.len() + 1 {
+                let prefix = line_prefix(text, max_bytes);
+                let suffix = line_suffix(text, max_bytes);
+                assert!(text.starts_with(prefix) && text.ends_with(suffix));
+                if max_bytes != 0 {
+                    assert!(prefix.len() <= max_bytes && suffix.len() <= max_bytes);
+                }
+                // A cut through a character drops it instead of keeping part of it
+                assert!(prefix.len() + 4 > max_bytes.min(text.len()));
+                assert!(suffix.len() + 4 > max_bytes.min(text.len()));
+            }
+            // Raw bytes cut at any offset, like the bytes before a decoding error
+            let bytes = text.as_bytes();
+            for end in 0..=bytes.len() {
+                for start in 0..=end {
+                    let snippet = hex_snippet(&bytes[start..end]);
+                    if start < end {
+                        assert_eq!(
+                            snippet.split("  |").next().unwrap().split(' ').count(),
+                            end - start
+                        );
+                    }
+                }
+            }
+        }
+        assert_eq!(line_prefix("日本語🙂", 4), "日");
+        assert_eq!(line_suffix("日本語🙂", 5), "🙂");
+        assert_eq!(hex_snippet("日".as_bytes()), "e6 97 a5  |日|");
+        assert_eq!(hex_snippet(&"日".as_bytes()[..2]), "e6 97  |.|");
+    }
 
-/// Check --quiet before printing
-fn print_if_not_quiet(quiet: bool, message: &str) {
-    if !quiet {
-        println!("{}", message);
+    #[test]
+    fn line_buffer_flushes_complete_lines() {
+        let config = test_config(&["--buffer", "1"]);
+        let output_limit = Arc::new(OutputLimit::new(&config));
+        let path = temp_path("line-buffer");
+        let file = File::create(&path).unwrap();
+        let mut sink = Sink::File(Some(
+            OutputWriter::new(file, &config, &output_limit).unwrap(),
+        ));
+        let mut buffer = LineBuffer::new(config.buffer);
+        let mut expected = String::new();
+        for line in [r#"{"id":1}"#, r#"{"id":22}"#, r#"{"id":333}"#] {
+            buffer.push(line, &config);
+            buffer.flush(&mut sink, &output_limit).unwrap();
+            sink.flush().unwrap();
+            expected.push_str(line);
+            expected.push('\n');
+            // A reader following the file sees every line as soon as it is flushed, including its newline
+            assert_eq!(fs::read_to_string(&path).unwrap(), expected);
+        }
+        sink.finish().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), expected);
+        fs::remove_file(&path).unwrap();
     }
-}
 
-fn set_config() -> Config {
-    // Fallback values if no config file was found
-    let fallback_input = String::from("./"); // directory where to search for zstd files
-    let fallback_output = String::from("./"); // directory where to write files to
-    let fallback_zstd = false; // by default extract everything
-    let fallback_compression_level = 0; // zstd compression level between 1-22, 0 means the default of 3
-    let fallback_suffix = String::from("_filtered"); // suffix for your output file
-    let fallback_file_extension = String::from(""); // file extension for your output file
-    let fallback_pattern = String::from(r#"^"#); // match everything
-    let fallback_threads = 0; // max number of threads rayon spawn, 0 means no limit
-    let fallback_buffer = 4096; // the buffer size after which data is written to disk, here: 4KiB
-    let fallback_no_write = false; // do not write to output
-    let fallback_quiet = false;
+    #[test]
+    fn partitions_use_the_line_as_read() {
+        let config = test_config(&[
+            "--output",
+            "out/",
+            "--partition-by-time",
+            "ts:day",
+            "--zstd",
+            "--file-extension",
+            "jsonl.zst",
+            "--line-numbers",
+        ]);
+        let output_limit = Arc::new(OutputLimit::new(&config));
+        let partitions = TimePartitions::new(&config, &output_limit, 1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(partitions.path("2024-01-05"), "out/2024-01-05.jsonl.zst");
+        // The prefix of --line-numbers is only added to the written line
+        let line = r#"{"ts":"2024-01-05T10:00:00Z"}"#;
+        assert_eq!(
+            partitions.buckets.bucket(line).as_deref(),
+            Some("2024-01-05")
+        );
+        let output_line = prefix_line(line.to_string(), "a.jsonl.zst", 1, &config);
+        assert_eq!(partitions.buckets.bucket(&output_line), None);
+    }
 
-    // Parse command-line arguments.
-    let cli = Cli::parse();
+    #[test]
+    fn line_buffer_separates_json_array_records() {
+        let config = test_config(&["--json-array"]);
+        let mut buffer = LineBuffer::new(0);
+        buffer.push("1", &config);
+        buffer.push("2", &config);
+        assert_eq!(buffer.bytes, b"1,\n2");
+    }
 
-    // Attempt to read the config file
-    let config: Option<Config> = if Path::new(&cli.config).exists() {
-        match fs::read_to_string(&cli.config) {
-            Ok(content) => toml::from_str(&content).ok(),
-            Err(e) => {
-                eprintln!("Failed to read config file: {}", e);
-                process::exit(1);
-            }
+    #[test]
+    fn on_complete_paths_are_quoted() {
+        let command = on_complete_command(
+            "gzip -k {output} && echo {input}",
+            "in/a b;$(rm -rf x)'.jsonl.zst",
+            "out/{input}.jsonl",
+        );
+        if cfg!(windows) {
+            assert_eq!(
+                command,
+                r#"gzip -k "out/{input}.jsonl" && echo "in/a b;$(rm -rf x)'.jsonl.zst""#
+            );
+        } else {
+            assert_eq!(
+                command,
+                r#"gzip -k 'out/{input}.jsonl' && echo 'in/a b;$(rm -rf x)'\''.jsonl.zst'"#
+            );
+            // The shell sees every path as a single word
+            let output = process::Command::new("sh")
+                .args([
+                    "-c",
+                    &on_complete_command("printf '%s|' {input} {output}", "a b;c", "d'e"),
+                ])
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8_lossy(&output.stdout), "a b;c|d'e|");
         }
-    } else {
-        None
-    };
+    }
 
-    // Input path.
-    let input = cli
-        .input
-        .or_else(|| Some(config.as_ref()?.input.clone()))
-        .unwrap_or_else(|| fallback_input);
+    // Runs an input that fails and returns the decompressed bytes, lines and kept lines added to the totals
+    fn aborted_input_counts(name: &str, lines: &[String], args: &[&str]) -> [usize; 3] {
+        let config = test_config(args);
+        let filter = LineFilter::new(&config).unwrap();
+        let output_limit = Arc::new(OutputLimit::new(&config));
+        let compressed = zstd::stream::encode_all(lines.join("\n").as_bytes(), 3).unwrap();
+        let reader = line_reader(open_decoder(&compressed[..], &config).unwrap(), &config);
+        let path = temp_path(name);
+        let totals = [(); 3].map(|_| Arc::new(AtomicUsize::new(0)));
+        let result = filter_lines(
+            reader,
+            name,
+            &path.to_string_lossy(),
+            None,
+            0,
+            &config,
+            &filter,
+            &output_limit,
+            &ProgressBar::hidden(),
+            None,
+            &totals[0],
+            &totals[1],
+            &totals[2],
+        );
+        assert!(result.is_err());
+        assert!(!path.exists(), "the incomplete output is removed");
+        totals.map(|total| total.load(Ordering::Relaxed))
+    }
 
-    // Output path
-    let mut output = cli
-        .output
-        .or_else(|| Some(config.as_ref()?.output.clone()))
-        .unwrap_or_else(|| fallback_output);
+    #[test]
+    fn oversized_line_abort_counts_kept_lines() {
+        let mut lines: Vec<String> = (0..3).map(|id| format!(r#"{{"id":{}}}"#, id)).collect();
+        lines.push(format!(r#"{{"id":3,"text":"{}"}}"#, "x".repeat(200)));
+        let [size, read, kept] = aborted_input_counts(
+            "abort_oversized.jsonl",
+            &lines,
+            &["--max-line-bytes-hard", "100"],
+        );
+        assert_eq!((size, read, kept), (24, 4, 3));
+    }
 
-    // Enforce ending with a slash
-    if !output.ends_with("/") {
-        output += "/";
+    #[test]
+    fn invalid_line_abort_counts_kept_lines() {
+        let lines = [r#"{"id":0}"#, r#"{"id":1}"#, "not json", r#"{"id":3}"#].map(String::from);
+        let [size, read, kept] =
+            aborted_input_counts("abort_invalid.jsonl", &lines, &["--strict-jsonl"]);
+        assert_eq!((size, read, kept), (16, 3, 2));
     }
 
-    // Use zstd compression in output
-    let zstd = cli.zstd
-        || config
-            .as_ref()
-            .and_then(|c| Some(c.zstd))
-            .unwrap_or(fallback_zstd);
+    #[test]
+    fn sort_overflow_abort_counts_kept_lines() {
+        let lines: Vec<String> = (0..4).map(|id| format!(r#"{{"id":{}}}"#, id)).collect();
+        let [size, read, kept] = aborted_input_counts(
+            "abort_sort.jsonl",
+            &lines,
+            &["--sort-by", "id", "--sort-max-lines", "2"],
+        );
+        // Lines waiting to be sorted are not kept yet
+        assert_eq!((size, read, kept), (16, 3, 0));
+    }
 
-    // Zstd compression level
-    let mut compression_level = cli
-        .compression_level
-        .or_else(|| Some(config.as_ref()?.compression_level.clone()))
-        .unwrap_or_else(|| fallback_compression_level);
+    #[test]
+    fn output_limit_abort_counts_kept_lines() {
+        let lines: Vec<String> = (0..4).map(|id| format!(r#"{{"id":{}}}"#, id)).collect();
+        // Every line is flushed on its own, the limit is reached after the second one
+        let [size, read, kept] = aborted_input_counts(
+            "abort_limit.jsonl",
+            &lines,
+            &["--buffer", "1", "--max-output-bytes", "18"],
+        );
+        assert_eq!((size, read, kept), (16, 2, 2));
+    }
 
-    // Output file suffix
-    let suffix = cli
-        .suffix
-        .or_else(|| Some(config.as_ref()?.suffix.clone()))
-        .unwrap_or_else(|| fallback_suffix);
+    #[test]
+    fn stale_outputs_are_named_exactly_after_an_input() {
+        let dir = temp_path("stale-outputs");
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "data_filtered.jsonl",
+            "data_filtered.jsonl.zst",
+            "data_filtered.metadata.jsonl",
+            "data-2_filtered.jsonl",
+            "data_2_filtered.jsonl",
+            "data_filtered-copy.jsonl",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let output = format!("{}/", dir.to_string_lossy());
+        let config = test_config(&["--output", &output, "--zstd"]);
+        // "data" is a prefix of the other inputs, their outputs are not outputs of "data"
+        let inputs = [PathBuf::from("in/data.jsonl.zst")];
+        assert_eq!(
+            stale_outputs(&inputs, &config),
+            [dir.join("data_filtered.jsonl")]
+        );
+        let inputs = [
+            PathBuf::from("in/data.jsonl.zst"),
+            PathBuf::from("in/data-2.jsonl.zst"),
+        ];
+        assert_eq!(
+            stale_outputs(&inputs, &config),
+            [
+                dir.join("data-2_filtered.jsonl"),
+                dir.join("data_filtered.jsonl")
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-    // Output file extension
-    let file_extension = cli
-        .file_extension
-        .or_else(|| Some(config.as_ref()?.file_extension.clone()))
-        .unwrap_or_else(|| fallback_file_extension);
+    #[test]
+    fn truncated_zstd_files_are_incomplete() {
+        let lines: Vec<String> = (0..1000).map(|id| format!(r#"{{"id":{}}}"#, id)).collect();
+        let compressed = zstd::stream::encode_all(lines.join("\n").as_bytes(), 3).unwrap();
+        let path = temp_path("truncated.jsonl.zst");
+        fs::write(&path, &compressed).unwrap();
+        assert!(frames::check_complete(&path).is_ok());
+        for length in [25, compressed.len() - 1] {
+            fs::write(&path, &compressed[..length]).unwrap();
+            assert!(frames::check_complete(&path).is_err(), "{} bytes", length);
+        }
+        fs::remove_file(&path).unwrap();
+    }
 
-    // Regex pattern.
-    let pattern = cli
-        .pattern
-        .or_else(|| Some(config.as_ref()?.pattern.clone()))
-        .unwrap_or_else(|| fallback_pattern);
+    #[test]
+    fn content_size_adds_up_every_frame() {
+        let path = temp_path("frames.jsonl.zst");
+        let mut file = Vec::new();
+        let mut total = 0;
+        for size in [100, 1000, 100_000] {
+            file.extend(zstd::bulk::compress(&vec![b'a'; size], 3).unwrap());
+            total += size as u64;
+        }
+        // Skippable frames hold no content
+        file.extend([0x50, 0x2A, 0x4D, 0x18, 2, 0, 0, 0, b'{', b'}']);
+        fs::write(&path, &file).unwrap();
+        assert_eq!(frame_content_size(&path), Some(total));
 
-    // Max threads.
-    let threads = cli
-        .threads
-        .or_else(|| Some(config.as_ref()?.threads.clone()))
-        .unwrap_or_else(|| fallback_threads);
+        // A streamed frame does not record its size, so the total is unknown
+        let mut encoder = Encoder::new(Vec::new(), 3).unwrap();
+        encoder.write_all(b"b").unwrap();
+        file.extend(encoder.finish().unwrap());
+        fs::write(&path, &file).unwrap();
+        assert_eq!(frame_content_size(&path), None);
+        fs::remove_file(&path).unwrap();
+    }
 
-    // Max buffer size
-    let buffer = cli
-        .buffer
-        .or_else(|| Some(config.as_ref()?.buffer.clone()))
-        .unwrap_or_else(|| fallback_buffer);
+    #[test]
+    fn outputs_with_a_long_window_are_verified() {
+        let config = test_config(&["--zstd"]);
+        let path = temp_path("long-window.jsonl.zst");
+        let mut encoder = Encoder::new(File::create(&path).unwrap(), 3).unwrap();
+        // Like an output of --match-input-params for an input written with --long=28
+        encoder.window_log(28).unwrap();
+        encoder.write_all(b"a\nb\n").unwrap();
+        encoder.finish().unwrap();
+        assert_eq!(verify_output(&path, 2, &config), Ok(()));
+        fs::remove_file(&path).unwrap();
+    }
 
-    // Do not write to output
-    let no_write = cli.no_write
-        || config
-            .as_ref()
-            .and_then(|c| Some(c.no_write))
-            .unwrap_or(fallback_no_write);
+    #[test]
+    fn sized_outputs_record_their_content_size() {
+        let config = test_config(&["--zstd", "--include-content-size"]);
+        let output_limit = Arc::new(OutputLimit::new(&config));
+        let path = temp_path("sized.jsonl.zst");
+        let mut writer =
+            OutputWriter::new(File::create(&path).unwrap(), &config, &output_limit).unwrap();
+        let OutputWriter::Sized { spool, .. } = &writer else {
+            panic!("--include-content-size does not spool the output");
+        };
+        let spool_path = spool.path.clone();
+        let content = "{\"a\":1}\n".repeat(100_000);
+        writer.write_all(content.as_bytes()).unwrap();
+        writer.finish().unwrap();
+        assert!(!spool_path.exists());
+        assert_eq!(frame_content_size(&path), Some(content.len() as u64));
+        assert_eq!(
+            zstd::decode_all(File::open(&path).unwrap()).unwrap(),
+            content.as_bytes()
+        );
+        fs::remove_file(&path).unwrap();
+    }
 
-    // Mute most announcements
-    let quiet = cli.quiet
-        || config
-            .as_ref()
-            .and_then(|c| Some(c.quiet))
-            .unwrap_or(fallback_quiet);
+    #[test]
+    fn bloom_files_are_read_back_and_checked() {
+        let path = temp_path("values.bloom");
+        let mut filter = bloom::BloomFilter::with_rate(100, 0.01);
+        for id in 0..100 {
+            filter.insert(&id.to_string());
+        }
+        filter.write(&path).unwrap();
+        let read = bloom::BloomFilter::read(&path).unwrap();
+        assert_eq!(read.hash_count(), filter.hash_count());
+        assert_eq!(read.size_in_bytes(), filter.size_in_bytes());
+        assert!((0..100).all(|id| read.contains(&id.to_string())));
 
-    // Validate the regex pattern.
-    let _ = match validate_regex(&pattern) {
-        Ok(r) => r,
-        Err(e) => {
-            eprintln!("{}", e);
-            process::exit(1);
+        let file = fs::read(&path).unwrap();
+        // Cut short, or a header claiming far more bits or hashes than the file holds
+        let mut huge_bit_count = file.clone();
+        huge_bit_count[13..21].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut huge_hash_count = file.clone();
+        huge_hash_count[9..13].copy_from_slice(&u32::MAX.to_le_bytes());
+        for broken in [
+            &file[..file.len() - 1],
+            &file[..10],
+            &huge_bit_count,
+            &huge_hash_count,
+        ] {
+            fs::write(&path, broken).unwrap();
+            assert!(bloom::BloomFilter::read(&path).is_err());
         }
-    };
+        fs::remove_file(&path).unwrap();
+    }
 
-    // Verify valid zstd compression level range
-    compression_level = if zstd::compression_level_range().contains(&compression_level) {
-        compression_level
-    } else {
-        0
-    };
+    #[test]
+    fn ignore_case_folds_non_ascii_substrings() {
+        let lines = [
+            r#"{"city":"ÉCOLE"}"#,
+            r#"{"city":"straße"}"#,
+            r#"{"city":"other"}"#,
+        ]
+        .map(String::from);
+        let output = filter_to_output(
+            "ignore-case-unicode",
+            &lines,
+            &[
+                "--contains",
+                "école",
+                "--contains",
+                "STRAßE",
+                "--ignore-case",
+            ],
+        );
+        assert_eq!(output, format!("{}\n{}\n", lines[0], lines[1]).into_bytes());
+    }
 
-    Config {
-        input: input,
-        output: output,
-        zstd: zstd,
-        compression_level: compression_level,
-        suffix: suffix,
-        file_extension: file_extension,
-        pattern: pattern,
-        threads: threads,
-        buffer: buffer,
-        no_write: no_write,
-        quiet: quiet,
-    }
-}
\ No newline at end of file
+    #[test]
+    fn held_unique_lines_keep_their_place_in_the_tail() {
+        let lines = [
+            r#"{"id":1,"v":"a"}"#,
+            r#"{"id":2,"v":"b"}"#,
+            r#"{"id":1,"v":"c"}"#,
+            r#"{"v":"no id"}"#,
+        ]
+        .map(String::from);
+        let output = filter_to_output(
+            "unique-last-tail",
+            &lines,
+            &[
+                "--unique-by",
+                "id",
+                "--unique-keep",
+                "last",
+                "--tail-lines",
+                "2",
+            ],
+        );
+        assert_eq!(output, format!("{}\n{}\n", lines[2], lines[3]).into_bytes());
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use colored::*;
+use xxhash_rust::xxh3::Xxh3;
+
+// Size and xxh3 hash of a whole output file, recorded in resume.manifest for --verify-manifest
+pub fn checksum(path: &Path) -> std::io::Result<(u64, u64)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Xxh3::new();
+    let mut buffer = vec![0; 1 << 16];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok((size, hasher.digest()))
+}
+
+// The manifest line of a processed input, the output with its size and hash follows if the input has a file of its own
+pub fn entry(key: &str, input: &Path, output: Option<(&str, u64, u64)>) -> String {
+    match output {
+        Some((output, size, hash)) => format!(
+            "{}\t{}\t{}\t{}\t{:016x}\n",
+            key,
+            input.display(),
+            output,
+            size,
+            hash
+        ),
+        None => format!("{}\t{}\n", key, input.display()),
+    }
+}
+
+// Hashes every output listed in a resume.manifest again and prints the ones that are missing or changed
+// An output listed more than once was written again by a later run, so only its last entry counts
+// Returns whether every output matched
+pub fn verify(manifest: &Path) -> Result<bool, String> {
+    let file = File::open(manifest).map_err(|e| format!("Failed to open {:?}: {}", manifest, e))?;
+    let mut outputs: Vec<String> = Vec::new();
+    let mut recorded: HashMap<String, (u64, u64)> = HashMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| format!("Failed to read {:?}: {}", manifest, e))?;
+        // Entries of inputs without an output of their own and of older versions have no checksum
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [_, _, output, size, hash] = fields[..] else {
+            continue;
+        };
+        let (Ok(size), Ok(hash)) = (size.parse::<u64>(), u64::from_str_radix(hash, 16)) else {
+            return Err(format!("Invalid entry in {:?}: {}", manifest, line));
+        };
+        if recorded.insert(output.to_string(), (size, hash)).is_none() {
+            outputs.push(output.to_string());
+        }
+    }
+
+    let mut problems = 0;
+    for output in &outputs {
+        let (size, hash) = recorded[output];
+        let problem = match checksum(Path::new(output)) {
+            Ok((actual_size, _)) if actual_size != size => Some(format!(
+                "{} has {} bytes instead of {}",
+                output, actual_size, size
+            )),
+            Ok((_, actual_hash)) if actual_hash != hash => {
+                Some(format!("{} does not match its checksum", output))
+            }
+            Ok(_) => None,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Some(format!("{} is missing", output))
+            }
+            Err(e) => Some(format!("Failed to read {}: {}", output, e)),
+        };
+        if let Some(problem) = problem {
+            problems += 1;
+            println!("{}", problem.red());
+        }
+    }
+    println!(
+        "Verified {} output(s) listed in {:?}, {} missing or changed",
+        outputs.len(),
+        manifest,
+        problems
+    );
+    Ok(problems == 0)
+}
@@ -0,0 +1,105 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use colored::*;
+
+// Lines of the generated input, every tenth one is an error
+const LINES: usize = 1000;
+const PATTERN: &str = r#""level":"error""#;
+
+// Compresses a generated input into a temporary directory, filters it with this binary the same way a user would
+// and compares the decompressed output with the lines that should have been kept
+// Returns whether every step passed, the temporary directory is removed either way
+pub fn run() -> bool {
+    let dir =
+        std::env::temp_dir().join(format!("zstd-jsonl-filter-selftest-{}", std::process::id()));
+    let result = run_in(&dir);
+    let _ = fs::remove_dir_all(&dir);
+    match result {
+        Ok(()) => {
+            println!("{}", "Selftest passed".green());
+            true
+        }
+        Err(e) => {
+            println!("{}", format!("Selftest failed: {}", e).red());
+            false
+        }
+    }
+}
+
+fn run_in(dir: &Path) -> Result<(), String> {
+    let input_dir = dir.join("in");
+    let output_dir = dir.join("out");
+    fs::create_dir_all(&input_dir)
+        .map_err(|e| format!("Failed to create {:?}: {}", input_dir, e))?;
+
+    let lines: Vec<String> = (0..LINES)
+        .map(|id| {
+            let level = if id % 10 == 0 { "error" } else { "info" };
+            format!(r#"{{"id":{},"level":"{}","msg":"line {}"}}"#, id, level, id)
+        })
+        .collect();
+    let expected: Vec<&str> = lines
+        .iter()
+        .filter(|line| line.contains(PATTERN))
+        .map(String::as_str)
+        .collect();
+
+    let compressed = zstd::stream::encode_all(lines.join("\n").as_bytes(), 3)
+        .map_err(|e| format!("Compressing the input failed: {}", e))?;
+    let input = input_dir.join("selftest.jsonl.zst");
+    fs::write(&input, compressed).map_err(|e| format!("Failed to write {:?}: {}", input, e))?;
+    println!("Compressed {} generated lines", LINES);
+
+    let executable =
+        std::env::current_exe().map_err(|e| format!("Failed to find this binary: {}", e))?;
+    // The directories need the trailing slash that --input and --output expect, the config file does not exist
+    let run = Command::new(executable)
+        .arg("--config")
+        .arg(dir.join("none.toml"))
+        .arg("--input")
+        .arg(format!("{}/", input_dir.display()))
+        .arg("--output")
+        .arg(format!("{}/", output_dir.display()))
+        .args(["--pattern", PATTERN, "--zstd", "--verify-output"])
+        .args(["--threads", "2", "--quiet", "--no-summary-file"])
+        .output()
+        .map_err(|e| format!("Failed to run the filter: {}", e))?;
+    if !run.status.success() {
+        return Err(format!(
+            "The filter run exited with {}: {}",
+            run.status,
+            String::from_utf8_lossy(&run.stderr).trim()
+        ));
+    }
+    println!("Filtered with --pattern {}", PATTERN);
+
+    let output = output_dir.join("selftest_filtered.jsonl.zst");
+    let compressed_output =
+        fs::read(&output).map_err(|e| format!("Failed to read {:?}: {}", output, e))?;
+    let decompressed = zstd::stream::decode_all(&compressed_output[..])
+        .map_err(|e| format!("Decompressing the output failed: {}", e))?;
+    let kept = String::from_utf8(decompressed)
+        .map_err(|_| String::from("The output is not valid UTF-8"))?;
+    let kept: Vec<&str> = kept.lines().collect();
+    if kept.len() != expected.len() {
+        return Err(format!(
+            "Expected {} kept lines, the output has {}",
+            expected.len(),
+            kept.len()
+        ));
+    }
+    if let Some((kept, expected)) = kept
+        .iter()
+        .zip(&expected)
+        .find(|(kept, expected)| kept != expected)
+    {
+        return Err(format!(
+            "Expected {:?} in the output, found {:?}",
+            expected, kept
+        ));
+    }
+    println!("Output has the {} expected lines", expected.len());
+    Ok(())
+}
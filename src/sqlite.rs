@@ -0,0 +1,84 @@
+use std::io::Write;
+
+use rusqlite::Connection;
+
+// Rows are committed in batches, a transaction per line would be far too slow
+const ROWS_PER_TRANSACTION: usize = 10_000;
+
+// Writes every line as a row of the `lines` table with the columns `id` and `json`
+// The output is a byte stream like any other, so rows are split at newlines
+pub struct SqliteWriter {
+    connection: Connection,
+    // Start of a line whose newline has not been written yet
+    partial_line: Vec<u8>,
+    rows_in_transaction: usize,
+}
+
+impl SqliteWriter {
+    pub fn new(path: &str) -> std::io::Result<Self> {
+        let connection = Connection::open(path).map_err(std::io::Error::other)?;
+        connection
+            .execute_batch(
+                "PRAGMA journal_mode = WAL;
+                PRAGMA synchronous = NORMAL;
+                CREATE TABLE IF NOT EXISTS lines (id INTEGER PRIMARY KEY, json TEXT NOT NULL);
+                BEGIN;",
+            )
+            .map_err(std::io::Error::other)?;
+        Ok(SqliteWriter {
+            connection,
+            partial_line: Vec::new(),
+            rows_in_transaction: 0,
+        })
+    }
+
+    fn insert(&mut self, line: &[u8]) -> std::io::Result<()> {
+        self.connection
+            .prepare_cached("INSERT INTO lines (json) VALUES (?1)")
+            .and_then(|mut statement| statement.execute([String::from_utf8_lossy(line)]))
+            .map_err(std::io::Error::other)?;
+        self.rows_in_transaction += 1;
+        if self.rows_in_transaction >= ROWS_PER_TRANSACTION {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // Every line ends with a newline, anything left over is a last line written without one
+    pub fn finish(mut self) -> std::io::Result<()> {
+        if !self.partial_line.is_empty() {
+            let line = std::mem::take(&mut self.partial_line);
+            self.insert(&line)?;
+        }
+        self.connection
+            .execute_batch("COMMIT;")
+            .map_err(std::io::Error::other)
+    }
+}
+
+impl Write for SqliteWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let mut rest = data;
+        while let Some(end) = rest.iter().position(|&byte| byte == b'\n') {
+            if self.partial_line.is_empty() {
+                self.insert(&rest[..end])?;
+            } else {
+                let mut line = std::mem::take(&mut self.partial_line);
+                line.extend_from_slice(&rest[..end]);
+                self.insert(&line)?;
+            }
+            rest = &rest[end + 1..];
+        }
+        self.partial_line.extend_from_slice(rest);
+        Ok(data.len())
+    }
+
+    // Commits all rows so far, they are visible to other connections afterwards
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.connection
+            .execute_batch("COMMIT; BEGIN;")
+            .map_err(std::io::Error::other)?;
+        self.rows_in_transaction = 0;
+        Ok(())
+    }
+}